@@ -0,0 +1,163 @@
+// Tab completion at the prompt: the command word completes against the
+// builtins, and every other word against paths, or against what `complete`
+// registered for that command.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::PathBuf;
+
+use rustyline::completion::{Completer, Pair};
+use rustyline::highlight::Highlighter;
+use rustyline::hint::Hinter;
+use rustyline::validate::Validator;
+use rustyline::{Context, Helper};
+
+use crate::glob_match;
+
+pub(crate) struct ShellHelper {
+    commands: Vec<&'static str>,
+    // By command name, sorted so `complete` lists them in a stable order
+    pub(crate) specs: BTreeMap<String, CompletionSpec>,
+}
+
+// How a command's arguments complete, as set by `complete`
+pub(crate) struct CompletionSpec {
+    // Fixed words offered for every argument (-W)
+    pub(crate) words: Vec<String>,
+    // Which paths are offered too. With neither words nor paths given,
+    // all paths are.
+    pub(crate) paths: Option<PathKind>,
+}
+
+#[derive(Clone)]
+pub(crate) enum PathKind {
+    // -f: any file or directory
+    Any,
+    // -d: directories only
+    Dirs,
+    // -G PATTERN: files whose names match, plus directories to get to them
+    Matching(String),
+}
+
+impl ShellHelper {
+    pub(crate) fn new(mut commands: Vec<&'static str>) -> Self {
+        commands.sort_unstable();
+        commands.dedup();
+        ShellHelper { commands, specs: BTreeMap::new() }
+    }
+
+    fn complete_command(&self, prefix: &str) -> Vec<Pair> {
+        self.commands
+            .iter()
+            .filter(|name| name.starts_with(prefix))
+            .map(|name| Pair { display: name.to_string(), replacement: format!("{} ", name) })
+            .collect()
+    }
+}
+
+impl CompletionSpec {
+    // The `complete` command that would recreate this spec
+    pub(crate) fn describe(&self, command: &str) -> String {
+        let mut line = String::from("complete");
+        if !self.words.is_empty() {
+            line.push_str(&format!(" -W {}", self.words.join(",")));
+        }
+        match &self.paths {
+            Some(PathKind::Any) => line.push_str(" -f"),
+            Some(PathKind::Dirs) => line.push_str(" -d"),
+            Some(PathKind::Matching(pattern)) => line.push_str(&format!(" -G '{}'", pattern)),
+            None => {}
+        }
+        format!("{} {}", line, command)
+    }
+}
+
+// Entries of the directory named by everything up to the word's last `/`
+// (or the current directory) whose names start with what follows it. The
+// replacement keeps the directory part as typed; directories get a trailing
+// `/` so completion can carry on into them. Dotfiles are offered only once
+// the name being completed starts with `.`.
+fn complete_path(word: &str, kind: &PathKind) -> Vec<Pair> {
+    let (dir, prefix) = match word.rfind('/') {
+        Some(i) => word.split_at(i + 1),
+        None => ("", word),
+    };
+    let search = match dir.strip_prefix("~/") {
+        Some(rest) => dirs::home_dir().map(|home| home.join(rest)),
+        None if dir.is_empty() => Some(PathBuf::from(".")),
+        None => Some(PathBuf::from(dir)),
+    };
+    let Some(entries) = search.and_then(|search| fs::read_dir(search).ok()) else {
+        return Vec::new();
+    };
+
+    let mut candidates: Vec<Pair> = entries
+        .flatten()
+        .filter_map(|entry| {
+            let name = entry.file_name().to_string_lossy().into_owned();
+            if !name.starts_with(prefix) || (name.starts_with('.') && !prefix.starts_with('.')) {
+                return None;
+            }
+            let is_dir = fs::metadata(entry.path()).is_ok_and(|meta| meta.is_dir());
+            let wanted = match kind {
+                PathKind::Any => true,
+                PathKind::Dirs => is_dir,
+                PathKind::Matching(pattern) => is_dir || glob_match(pattern, &name),
+            };
+            if !wanted {
+                return None;
+            }
+            let display = if is_dir { format!("{}/", name) } else { name };
+            let replacement = if is_dir { format!("{}{}", dir, display) } else { format!("{}{} ", dir, display) };
+            Some(Pair { display, replacement })
+        })
+        .collect();
+    candidates.sort_by(|a, b| a.display.cmp(&b.display));
+    candidates
+}
+
+impl Completer for ShellHelper {
+    type Candidate = Pair;
+
+    fn complete(&self, line: &str, pos: usize, _: &Context<'_>) -> rustyline::Result<(usize, Vec<Pair>)> {
+        let before = &line[..pos];
+        let start = before.rfind(char::is_whitespace).map_or(0, |i| i + 1);
+        let word = &before[start..];
+        // The first word of a command, including one after `|`, `;` or `&&`
+        let is_command = before[..start].trim_end().is_empty() || before[..start].trim_end().ends_with(['|', ';', '&']);
+        if is_command && !word.contains('/') {
+            return Ok((start, self.complete_command(word)));
+        }
+        // The command this word is an argument of starts the current segment
+        let segment = before[..start].rsplit(['|', ';', '&']).next().unwrap_or("");
+        let spec = segment.split_whitespace().next().and_then(|command| self.specs.get(command));
+        let candidates = match spec {
+            None => complete_path(word, &PathKind::Any),
+            Some(spec) => {
+                let mut candidates: Vec<Pair> = spec
+                    .words
+                    .iter()
+                    .filter(|candidate| candidate.starts_with(word))
+                    .map(|candidate| Pair { display: candidate.clone(), replacement: format!("{} ", candidate) })
+                    .collect();
+                match (&spec.paths, spec.words.is_empty()) {
+                    (Some(kind), _) => candidates.extend(complete_path(word, kind)),
+                    (None, true) => candidates.extend(complete_path(word, &PathKind::Any)),
+                    (None, false) => {}
+                }
+                candidates
+            }
+        };
+        Ok((start, candidates))
+    }
+}
+
+impl Hinter for ShellHelper {
+    type Hint = String;
+}
+
+impl Highlighter for ShellHelper {}
+
+impl Validator for ShellHelper {}
+
+impl Helper for ShellHelper {}
@@ -0,0 +1,125 @@
+// Backslash escapes as `echo -e` and `printf` interpret them, so the two
+// commands can't drift apart.
+
+use std::iter::Peekable;
+use std::str::Chars;
+
+// How a numeric octal escape is written: echo wants a leading zero
+// (`\0101`), printf's format string takes the digits directly (`\101`)
+#[derive(Clone, Copy)]
+pub(crate) enum Octal {
+    Echo,
+    Printf,
+}
+
+// Replaces `\n`, `\t`, `\\`, octal and `\xHH` escapes and the rest of the
+// usual single-letter ones. Unknown escapes, a `\x` with no hex digits and
+// a trailing backslash are kept as written. Also says whether the text held
+// a `\c`, which ends the output there.
+pub(crate) fn expand(text: &str, octal: Octal) -> (String, bool) {
+    let mut out = String::with_capacity(text.len());
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            out.push(c);
+            continue;
+        }
+        match (chars.peek().copied(), octal) {
+            (Some('0'), Octal::Echo) => {
+                chars.next();
+                out.push(take_digits(&mut chars, 8, 3).unwrap_or('\0'));
+                continue;
+            }
+            (Some('0'..='7'), Octal::Printf) => {
+                out.push(take_digits(&mut chars, 8, 3).unwrap_or('\0'));
+                continue;
+            }
+            _ => {}
+        }
+        match chars.next() {
+            Some('a') => out.push('\x07'),
+            Some('b') => out.push('\x08'),
+            Some('c') => return (out, true),
+            Some('e') => out.push('\x1b'),
+            Some('f') => out.push('\x0c'),
+            Some('n') => out.push('\n'),
+            Some('r') => out.push('\r'),
+            Some('t') => out.push('\t'),
+            Some('v') => out.push('\x0b'),
+            Some('\\') => out.push('\\'),
+            Some('x') => match take_digits(&mut chars, 16, 2) {
+                Some(c) => out.push(c),
+                None => out.push_str("\\x"),
+            },
+            Some(other) => {
+                out.push('\\');
+                out.push(other);
+            }
+            None => out.push('\\'),
+        }
+    }
+    (out, false)
+}
+
+// Consumes up to `max` digits in `radix` for a numeric escape like `\0101`
+fn take_digits(chars: &mut Peekable<Chars>, radix: u32, max: usize) -> Option<char> {
+    let mut value = 0;
+    let mut digits = 0;
+    while digits < max {
+        match chars.peek().and_then(|c| c.to_digit(radix)) {
+            Some(d) => value = value * radix + d,
+            None => break,
+        }
+        chars.next();
+        digits += 1;
+    }
+    (digits > 0).then(|| char::from_u32(value)).flatten()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn echo(text: &str) -> (String, bool) {
+        expand(text, Octal::Echo)
+    }
+
+    fn printf(text: &str) -> String {
+        expand(text, Octal::Printf).0
+    }
+
+    #[test]
+    fn single_letter_escapes() {
+        assert_eq!(echo(r"a\nb\tc\\d").0, "a\nb\tc\\d");
+        assert_eq!(echo(r"\a\b\e\f\r\v").0, "\x07\x08\x1b\x0c\r\x0b");
+    }
+
+    #[test]
+    fn octal_escapes_need_a_leading_zero_in_echo_only() {
+        assert_eq!(echo(r"\0101\0").0, "A\0");
+        assert_eq!(echo(r"\101").0, r"\101");
+        assert_eq!(printf(r"\101\0"), "A\0");
+        // At most three digits are read; the rest is ordinary text
+        assert_eq!(echo(r"\01014").0, "A4");
+        assert_eq!(printf(r"\1014"), "A4");
+    }
+
+    #[test]
+    fn hex_escapes_take_up_to_two_digits() {
+        assert_eq!(echo(r"\x41\x7e1").0, "A~1");
+        assert_eq!(echo(r"\x4z").0, "\x04z");
+        assert_eq!(echo(r"\xg").0, r"\xg");
+    }
+
+    #[test]
+    fn unknown_escapes_and_a_trailing_backslash_are_kept() {
+        assert_eq!(echo(r"\q\z").0, r"\q\z");
+        assert_eq!(echo("end\\"), ("end\\".to_string(), false));
+    }
+
+    #[test]
+    fn backslash_c_stops_the_output() {
+        assert_eq!(echo(r"one\ctwo"), ("one".to_string(), true));
+        assert_eq!(echo("plain"), ("plain".to_string(), false));
+    }
+}
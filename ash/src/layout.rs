@@ -0,0 +1,159 @@
+// Terminal-width-aware text layout for the commands that print listings or
+// long lines of text.
+
+use std::env;
+use std::io::{self, IsTerminal};
+
+// What to assume when stdout isn't a terminal and COLUMNS/LINES are unset
+const DEFAULT_WIDTH: usize = 80;
+const DEFAULT_HEIGHT: usize = 24;
+
+// Space left between columns
+const GAP: usize = 2;
+
+// The width of the terminal stdout is attached to; otherwise COLUMNS, then 80
+pub(crate) fn terminal_width() -> usize {
+    terminal_size().map(|(width, _)| width).unwrap_or_else(|| size_from_env("COLUMNS", DEFAULT_WIDTH))
+}
+
+// The height of the terminal stdout is attached to; otherwise LINES, then 24
+pub(crate) fn terminal_height() -> usize {
+    terminal_size().map(|(_, height)| height).unwrap_or_else(|| size_from_env("LINES", DEFAULT_HEIGHT))
+}
+
+fn size_from_env(var: &str, default: usize) -> usize {
+    env::var(var).ok().and_then(|size| size.parse().ok()).filter(|&size| size > 0).unwrap_or(default)
+}
+
+fn terminal_size() -> Option<(usize, usize)> {
+    if io::stdout().is_terminal() {
+        tty_size()
+    } else {
+        None
+    }
+}
+
+#[cfg(unix)]
+fn tty_size() -> Option<(usize, usize)> {
+    let mut size: libc::winsize = unsafe { std::mem::zeroed() };
+    let ok = unsafe { libc::ioctl(libc::STDOUT_FILENO, libc::TIOCGWINSZ, &mut size) } == 0;
+    (ok && size.ws_col > 0 && size.ws_row > 0).then_some((size.ws_col as usize, size.ws_row as usize))
+}
+
+#[cfg(not(unix))]
+fn tty_size() -> Option<(usize, usize)> {
+    None
+}
+
+// Lays items out the way ls does: down each column, then across, using as
+// many columns as fit in `width`. Each column is as wide as its longest item
+// plus a gap, and rows carry no trailing spaces. An item wider than `width`
+// gets a row of its own. Color escapes in an item take up no width.
+pub(crate) fn columns(items: &[String], width: usize) -> Vec<String> {
+    let widths: Vec<usize> = items.iter().map(|item| visible_width(item)).collect();
+    for rows in 1..=items.len() {
+        let cols = items.len().div_ceil(rows);
+        let col_widths: Vec<usize> = widths
+            .chunks(rows)
+            .map(|column| column.iter().copied().max().unwrap_or(0))
+            .collect();
+        let total = col_widths.iter().sum::<usize>() + GAP * (cols - 1);
+        if total <= width || cols == 1 {
+            return (0..rows)
+                .map(|row| {
+                    let mut line = String::new();
+                    for (col, col_width) in col_widths.iter().enumerate() {
+                        let Some(item) = items.get(col * rows + row) else {
+                            break;
+                        };
+                        if !line.is_empty() {
+                            line.push_str(&" ".repeat(GAP));
+                        }
+                        line.push_str(item);
+                        if items.get((col + 1) * rows + row).is_some() {
+                            line.push_str(&" ".repeat(col_width - widths[col * rows + row]));
+                        }
+                    }
+                    line
+                })
+                .collect();
+        }
+    }
+    Vec::new()
+}
+
+// Characters on screen, not counting `ESC [ ... m` color sequences
+fn visible_width(text: &str) -> usize {
+    let mut width = 0;
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        if c == '\x1b' {
+            chars.find(|c| c.is_ascii_alphabetic());
+        } else {
+            width += 1;
+        }
+    }
+    width
+}
+
+// Greedy word wrap at `width` characters. Words longer than a line are left
+// whole on a line of their own rather than broken.
+pub(crate) fn wrap(text: &str, width: usize) -> Vec<String> {
+    let mut lines = Vec::new();
+    let mut line = String::new();
+    for word in text.split_whitespace() {
+        if !line.is_empty() && line.chars().count() + 1 + word.chars().count() > width {
+            lines.push(std::mem::take(&mut line));
+        }
+        if !line.is_empty() {
+            line.push(' ');
+        }
+        line.push_str(word);
+    }
+    if !line.is_empty() || lines.is_empty() {
+        lines.push(line);
+    }
+    lines
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn items(names: &[&str]) -> Vec<String> {
+        names.iter().map(|name| name.to_string()).collect()
+    }
+
+    #[test]
+    fn columns_fill_one_row_when_everything_fits() {
+        assert_eq!(columns(&items(&["a", "bb", "ccc", "dddd"]), 80), ["a  bb  ccc  dddd"]);
+    }
+
+    #[test]
+    fn columns_run_down_then_across_padded_to_the_widest_item() {
+        assert_eq!(columns(&items(&["a", "bb", "ccc", "dddd"]), 10), ["a   ccc", "bb  dddd"]);
+        let five = items(&["a", "b", "c", "d", "e"]);
+        assert_eq!(columns(&five, 7), ["a  c  e", "b  d"]);
+    }
+
+    #[test]
+    fn columns_fall_back_to_one_per_line() {
+        assert_eq!(columns(&items(&["a", "bb", "ccc", "dddd"]), 5), ["a", "bb", "ccc", "dddd"]);
+        assert_eq!(columns(&items(&["a", "much too long"]), 5), ["a", "much too long"]);
+        assert!(columns(&[], 80).is_empty());
+    }
+
+    #[test]
+    fn wrap_breaks_between_words() {
+        assert_eq!(wrap("one two three", 7), ["one two", "three"]);
+        assert_eq!(wrap("a enormousword b", 5), ["a", "enormousword", "b"]);
+        assert_eq!(wrap("", 10), [""]);
+    }
+
+    #[test]
+    fn columns_ignore_color_escapes_when_measuring() {
+        let red = "\x1b[31mab\x1b[0m".to_string();
+        let rows = columns(&[red.clone(), "c".to_string(), "d".to_string()], 6);
+        assert_eq!(rows, [format!("{}  d", red), "c".to_string()]);
+    }
+}
@@ -0,0 +1,7781 @@
+mod complete;
+mod escape;
+mod layout;
+mod picker;
+mod walk;
+
+use std::cell::Cell;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::env;
+use std::fs;
+use std::io::{self, BufRead, IsTerminal, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::process::{self, exit};
+use std::rc::Rc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use chrono::format::{Item, StrftimeItems};
+use chrono::{DateTime, Datelike, Local, NaiveDate, TimeDelta, TimeZone, Utc};
+use rustyline::{Cmd, Config, EditMode, Editor, EventHandler, KeyEvent, Movement, error::ReadlineError};
+use rustyline::config::Configurer;
+use rustyline::history::{FileHistory, History};
+use notify::{EventKind, RecursiveMode, Watcher};
+use regex::{Regex, RegexBuilder};
+use complete::{CompletionSpec, PathKind, ShellHelper};
+use walk::{Walk, WalkEntry};
+use thiserror::Error;
+
+
+#[derive(Error, Debug)]
+pub enum ShellError {
+    #[error("I/O error: {0}")]
+    Io(#[from] io::Error),
+
+    #[error("Invalid argument: {0}")]
+    InvalidArgument(String),
+
+    #[error("Missing Arguments: {0}")]
+    MissingArguments(&'static str),
+
+    #[error("Command not found: {0}")]
+    CommandNotFound(String),
+
+    #[error("File not found: {0}")]
+    FileNotFound(String),
+    
+    #[error("Is a directory: {0}")]
+    IsDirectory(String),
+
+    #[error("Not a directory: {0}")]
+    NotADirectory(String),
+
+    #[error("Permission denied: {0}")]
+    PermissionDenied(String),
+
+    #[error("Unavailable: {0}")]
+    Unavailable(String),
+
+    // The command already printed its own per-operand errors to stderr
+    #[error("{0}: some operands failed")]
+    Reported(&'static str),
+
+    // Reported with exit status 124, matching coreutils timeout
+    #[error("Timed out after {1:?}: {0}")]
+    TimedOut(String, Duration),
+}
+
+impl ShellError {
+    // The status `$?` reports for a command that failed this way, following
+    // POSIX shells: 126 when a command can't be run, 127 when it can't be
+    // found, 2 for usage mistakes and 1 for any other failure
+    fn exit_code(&self) -> i32 {
+        match self {
+            ShellError::CommandNotFound(_) => 127,
+            ShellError::PermissionDenied(_) => 126,
+            ShellError::TimedOut(..) => 124,
+            ShellError::InvalidArgument(_) | ShellError::MissingArguments(_) => 2,
+            ShellError::Io(_)
+            | ShellError::FileNotFound(_)
+            | ShellError::IsDirectory(_)
+            | ShellError::NotADirectory(_)
+            | ShellError::Unavailable(_)
+            | ShellError::Reported(_) => 1,
+        }
+    }
+}
+
+pub type ShellResult<T> = Result<T, ShellError>;
+
+// Set by the Ctrl-C handler so long-running builtins can stop cleanly
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+// Session state that outlives a single command
+struct ShellState {
+    // Defined with `alias name=value`; see `expand_alias`
+    aliases: HashMap<String, String>,
+    // Executables found in ~/.ash/commands, scanned on first use and
+    // refreshed by `rehash`
+    plugins: Option<HashMap<String, PathBuf>>,
+    // Key bindings added with `bind`, in the order they were defined
+    bindings: Vec<(String, Binding)>,
+    edit_mode: EditMode,
+    // Sidecar record of when each history entry was added, oldest first
+    history_times: Vec<(i64, String)>,
+    // Entries added this session, appended to the history file on exit
+    history_new: Vec<String>,
+    // Commands waiting to run on a background timer thread (`at` and `every`)
+    scheduled: Arc<Mutex<Vec<ScheduledJob>>>,
+    next_scheduled_id: usize,
+    // Run before each prompt is displayed, like bash's PROMPT_COMMAND
+    prompt_command: Option<String>,
+    // Run after every successful `cd`, like zsh's chpwd functions
+    chpwd_hooks: Vec<String>,
+    running_chpwd: bool,
+    // .env files loaded by ASH_AUTOENV, innermost directory last
+    autoenv_loaded: Vec<LoadedEnv>,
+    // Whether the user agreed to load each .env file seen this session
+    autoenv_trusted: HashMap<PathBuf, bool>,
+    // Open when ASH_AUDIT_LOG names a file; see `audit_command`
+    audit_log: Option<io::BufWriter<fs::File>>,
+    // `--safe` / `set -o safe`; see SAFE_MODE_BLOCKS
+    safe_mode: bool,
+    // `--verbose` / `set -x`: echo each command to stderr before it runs
+    xtrace: bool,
+    // `set -o pipefail`: a pipeline fails if any stage does, not just the last
+    pipefail: bool,
+    // `--posix` / `set -o posix`: turns off ASH-only behavior (the prompt and
+    // chpwd hooks, autoenv, slow-command alerts, GNU echo flags) for
+    // portability testing
+    posix: bool,
+    error_format: ErrorFormat,
+    // Exit status of the last external program or plugin, kept for `$?`
+    last_status: i32,
+    // Set by `exit`: nothing more runs, and the shell stops with this status
+    // once it has saved history
+    exit_status: Option<i32>,
+    // Where the last successful cd started from, for `cd -`
+    previous_dir: Option<PathBuf>,
+    // Directories saved by `pushd`, most recent last
+    dir_stack: Vec<PathBuf>,
+    // Programs started with a trailing `&`, reaped before each prompt
+    jobs: Vec<Job>,
+    next_job_id: usize,
+}
+
+struct Job {
+    id: usize,
+    command: String,
+    child: process::Child,
+}
+
+const SAFE_MODE_BLOCKS: &str = "rm, mv onto an existing file, and redirections that truncate files";
+
+struct LoadedEnv {
+    dir: PathBuf,
+    // Values the variables had before the .env file overrode them
+    saved: Vec<(String, Option<String>)>,
+}
+
+struct ScheduledJob {
+    id: usize,
+    command: String,
+    due: DateTime<Local>,
+    // Set for `every` timers, which are rescheduled after each run
+    interval: Option<Duration>,
+    cancelled: Arc<AtomicBool>,
+}
+
+impl ShellState {
+    fn new() -> Self {
+        // ASH_EDIT_MODE=vi starts the editor with vi keybindings; emacs otherwise
+        let edit_mode = match env::var("ASH_EDIT_MODE").as_deref() {
+            Ok("vi") => EditMode::Vi,
+            _ => EditMode::Emacs,
+        };
+        ShellState {
+            aliases: HashMap::new(),
+            plugins: None,
+            bindings: Vec::new(),
+            edit_mode,
+            history_times: Vec::new(),
+            history_new: Vec::new(),
+            scheduled: Arc::new(Mutex::new(Vec::new())),
+            next_scheduled_id: 1,
+            prompt_command: env::var("PROMPT_COMMAND").ok().filter(|cmd| !cmd.trim().is_empty()),
+            chpwd_hooks: Vec::new(),
+            running_chpwd: false,
+            autoenv_loaded: Vec::new(),
+            autoenv_trusted: HashMap::new(),
+            audit_log: None,
+            safe_mode: false,
+            xtrace: false,
+            pipefail: false,
+            posix: false,
+            error_format: ErrorFormat::Plain,
+            last_status: 0,
+            exit_status: None,
+            previous_dir: None,
+            dir_stack: Vec::new(),
+            jobs: Vec::new(),
+            next_job_id: 1,
+        }
+    }
+}
+
+type LineEditor = Editor<ShellHelper, FileHistory>;
+
+// A shell session: the line editor plus everything commands can change.
+// `run_interactive` drives one from the prompt. Programs that embed ASH
+// create their own and call `run_command`, which runs lines as the prompt
+// would but captures their output, without touching the history file or
+// startup files.
+pub struct Shell {
+    // None for shells that never read from the prompt, such as the ones
+    // `at` and `every` run their commands in
+    rl: Option<LineEditor>,
+    state: ShellState,
+    commands: Registry,
+    clock: Box<dyn Clock>,
+}
+
+// Where time-dependent commands get the current time, so an embedder can
+// fix it and get the same output every run
+pub trait Clock {
+    fn now(&self) -> DateTime<Local>;
+}
+
+// The real time, used unless `Shell::set_clock` replaces it
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> DateTime<Local> {
+        Local::now()
+    }
+}
+
+// What one `Shell::run_command` call printed, and how it finished
+pub struct CommandOutput {
+    pub stdout: Vec<u8>,
+    // Error reports for commands that failed. Warnings a command prints while
+    // it carries on, and external programs' stderr, still go to the process's
+    // stderr.
+    pub stderr: Vec<u8>,
+    // Status of the last command that ran: 0 on success
+    pub status: i32,
+}
+
+impl Shell {
+    // Falls back to a shell without an editor if one can't be created; only
+    // `history`, `bind`, `complete` and `set -o vi` need it
+    pub fn new() -> Self {
+        let state = ShellState::new();
+        let config = Config::builder().edit_mode(state.edit_mode).build();
+        let rl = line_editor(config).ok();
+        Shell { rl, state, commands: builtin_registry(), clock: Box::new(SystemClock) }
+    }
+
+    fn with_editor(rl: LineEditor, state: ShellState) -> Self {
+        Shell { rl: Some(rl), state, commands: builtin_registry(), clock: Box::new(SystemClock) }
+    }
+
+    fn without_editor() -> Self {
+        Shell { rl: None, state: ShellState::new(), commands: builtin_registry(), clock: Box::new(SystemClock) }
+    }
+
+    pub fn set_clock(&mut self, clock: impl Clock + 'static) {
+        self.clock = Box::new(clock);
+    }
+
+    // Adds a command to this shell, replacing any builtin with the same name
+    pub fn register(&mut self, command: Box<dyn Command>) {
+        self.commands.insert(command.name().to_string(), Rc::from(command));
+    }
+
+    pub fn run_command(&mut self, line: &str) -> CommandOutput {
+        let mut stdout = Sink::Capture(Vec::new());
+        let mut stderr = Vec::new();
+        match parse_command_list(line) {
+            Ok(list) => {
+                run_command_list(&list, self, &mut stdout, &mut stderr);
+            }
+            Err(e) => {
+                self.state.last_status = e.exit_code();
+                let _ = report_error(e, "", self.state.error_format, &mut stderr);
+            }
+        }
+        CommandOutput {
+            stdout: match stdout {
+                Sink::Capture(captured) => captured,
+                _ => Vec::new(),
+            },
+            stderr,
+            // An embedded shell has no process of its own to end, so `exit`
+            // just stops the line and gives its status
+            status: self.state.exit_status.take().unwrap_or(self.state.last_status),
+        }
+    }
+}
+
+impl Default for Shell {
+    fn default() -> Self {
+        Shell::new()
+    }
+}
+
+#[derive(Clone)]
+enum Binding {
+    Action(&'static str),
+    Macro(String),
+}
+
+// Readline action names accepted by `bind`
+const BIND_ACTIONS: &[&str] = &[
+    "accept-line",
+    "clear-screen",
+    "reverse-search-history",
+    "forward-search-history",
+    "history-search-backward",
+    "history-search-forward",
+    "beginning-of-line",
+    "end-of-line",
+];
+
+fn bind_action_cmd(action: &str) -> Option<Cmd> {
+    match action {
+        "accept-line" => Some(Cmd::AcceptLine),
+        "clear-screen" => Some(Cmd::ClearScreen),
+        "reverse-search-history" => Some(Cmd::ReverseSearchHistory),
+        "forward-search-history" => Some(Cmd::ForwardSearchHistory),
+        "history-search-backward" => Some(Cmd::HistorySearchBackward),
+        "history-search-forward" => Some(Cmd::HistorySearchForward),
+        "beginning-of-line" => Some(Cmd::Move(Movement::BeginningOfLine)),
+        "end-of-line" => Some(Cmd::Move(Movement::EndOfLine)),
+        _ => None,
+    }
+}
+
+// Command-line flags accepted by the `ash` binary
+#[derive(Default)]
+struct StartupOptions {
+    safe: bool,
+    verbose: bool,
+    timing: bool,
+    login: bool,
+    posix: bool,
+    error_format: ErrorFormat,
+    // `-c COMMAND`, or a script file named after the options: run it
+    // instead of the prompt
+    command: Option<String>,
+    script: Option<PathBuf>,
+    version: bool,
+}
+
+impl StartupOptions {
+    fn parse(mut args: impl Iterator<Item = String>) -> Result<Self, String> {
+        let mut options = StartupOptions::default();
+        while let Some(arg) = args.next() {
+            if options.command.is_some() || options.script.is_some() {
+                return Err(format!("unexpected argument: {}", arg));
+            }
+            match arg.as_str() {
+                "--safe" => options.safe = true,
+                "-v" | "--verbose" => options.verbose = true,
+                "--timing" => options.timing = true,
+                "-l" | "--login" => options.login = true,
+                "--posix" => options.posix = true,
+                "--color" => options.error_format = ErrorFormat::Color,
+                "--errors-json" => options.error_format = ErrorFormat::Json,
+                "-c" => options.command = Some(args.next().ok_or("-c needs a command")?),
+                "--version" => options.version = true,
+                _ if arg.starts_with('-') => return Err(format!("unknown option: {}", arg)),
+                _ => options.script = Some(PathBuf::from(arg)),
+            }
+        }
+        Ok(options)
+    }
+}
+
+// Runs a script (or a `-c` command) without the prompt, history or startup
+// files, returning the status for the process to exit with. Lines are read
+// as the prompt would read them, so comments and quoted newlines work the
+// same. The first command that fails stops the script, and its status
+// becomes the shell's, so a failing step fails a CI job.
+fn run_script(script: &str, options: &StartupOptions) -> i32 {
+    let mut shell = Shell::new();
+    shell.state.safe_mode = options.safe;
+    shell.state.xtrace = options.verbose;
+    shell.state.posix = options.posix;
+    shell.state.error_format = options.error_format;
+
+    let mut lines = script.lines();
+    while let Some(line) = lines.next() {
+        let mut line = line.to_string();
+        while is_incomplete(&line) {
+            let Some(more) = lines.next() else {
+                break;
+            };
+            line.push('\n');
+            line.push_str(more);
+        }
+        match parse_command_list(&line) {
+            Ok(list) => {
+                run_command_list(&list, &mut shell, &mut Sink::Terminal, &mut io::stderr());
+            }
+            Err(e) => {
+                shell.state.last_status = e.exit_code();
+                handle_error(e, "", shell.state.error_format);
+            }
+        }
+        report_finished_jobs(&mut shell.state, &mut io::stderr());
+        if let Some(status) = shell.state.exit_status {
+            return status;
+        }
+        if shell.state.last_status != 0 {
+            break;
+        }
+    }
+    shell.state.last_status
+}
+
+// Per-phase startup durations, printed to stderr by `ash --timing`
+struct StartupTimer {
+    last: Instant,
+    phases: Vec<(&'static str, Duration)>,
+}
+
+impl StartupTimer {
+    fn new() -> Self {
+        StartupTimer { last: Instant::now(), phases: Vec::new() }
+    }
+
+    // Attributes the time since the previous phase ended to `name`
+    fn phase(&mut self, name: &'static str) {
+        let now = Instant::now();
+        self.phases.push((name, now - self.last));
+        self.last = now;
+    }
+
+    fn report(&self) {
+        let width = self.phases.iter().map(|(name, _)| name.len()).max().unwrap_or(0);
+        for (name, elapsed) in &self.phases {
+            eprintln!("{:<width$}  {:>9.3} ms", name, elapsed.as_secs_f64() * 1000.0);
+        }
+        let total: Duration = self.phases.iter().map(|(_, elapsed)| *elapsed).sum();
+        eprintln!("{:<width$}  {:>9.3} ms", "total", total.as_secs_f64() * 1000.0);
+    }
+}
+
+// The `ash` binary: the interactive shell, unless it was given a script or
+// `-c` command to run instead
+pub fn run_interactive() {
+    let mut timer = StartupTimer::new();
+    let mut options = StartupOptions::parse(env::args().skip(1)).unwrap_or_else(|e| {
+        eprintln!("ash: {}", e);
+        eprintln!("Usage: ash [--safe] [-v | --verbose] [--timing] [-l | --login] [--posix] [--color | --errors-json] [--version] [-c command | script]");
+        exit(2);
+    });
+    if options.version {
+        println!("ash {}", env!("CARGO_PKG_VERSION"));
+        return;
+    }
+    if let Some(command) = &options.command {
+        exit(run_script(command, &options));
+    }
+    if let Some(path) = &options.script {
+        let script = fs::read_to_string(path).unwrap_or_else(|e| {
+            eprintln!("ash: {}: {}", path.display(), io_error_reason(&e));
+            exit(127);
+        });
+        exit(run_script(&script, &options));
+    }
+    // login(1) starts login shells with a leading '-' in argv[0], e.g. "-ash"
+    if env::args().next().is_some_and(|arg0| arg0.starts_with('-')) {
+        options.login = true;
+    }
+
+    println!("ASH Shell - Aditya's Shell in Rust");
+
+    let history_path = dirs::home_dir()
+        .unwrap_or_else(|| PathBuf::from("."))
+        .join(".ash_history");
+
+    let mut state = ShellState::new();
+    state.safe_mode = options.safe;
+    state.xtrace = options.verbose;
+    state.posix = options.posix;
+    state.error_format = options.error_format;
+    if state.safe_mode {
+        println!("Safe mode: blocking {}", SAFE_MODE_BLOCKS);
+    }
+    let history_size = history_load_limit();
+    let config = Config::builder()
+        .edit_mode(state.edit_mode)
+        .max_history_size(history_size)
+        .and_then(|config| config.history_ignore_dups(true))
+        .map(|config| config.build());
+    let mut rl = match config.and_then(line_editor) {
+        Ok(rl) => rl,
+        Err(e) => {
+            eprintln!("ash: cannot start the line editor: {}", e);
+            exit(1);
+        }
+    };
+    rl.bind_sequence(KeyEvent::ctrl('T'), EventHandler::Conditional(Box::new(picker::FilePicker)));
+    timer.phase("shell setup");
+    let mut loaded = read_history(&history_path, history_size);
+    if matches!(&loaded, Err(e) if e.kind() == io::ErrorKind::InvalidData) {
+        match recover_history(&history_path) {
+            Ok((kept, dropped)) => {
+                eprintln!(
+                    "History file was damaged: recovered {} entries, dropped {} unreadable lines",
+                    kept, dropped
+                );
+                eprintln!("The original was saved to {}.bak", history_path.display());
+                loaded = read_history(&history_path, history_size);
+            }
+            Err(e) => eprintln!("Failed to recover history: {}", e),
+        }
+    }
+    match loaded {
+        Ok(entries) => {
+            for entry in entries {
+                let _ = rl.history_mut().add_owned(entry);
+            }
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => eprintln!("No previous history found"),
+        Err(e) => eprintln!("Failed to load history: {}", e),
+    }
+    timer.phase("load history");
+    let times_path = history_times_path(&history_path);
+    state.history_times = load_history_times(&times_path);
+    timer.phase("load history times");
+
+    if let Some(path) = env::var_os("ASH_AUDIT_LOG").filter(|path| !path.is_empty()) {
+        match fs::OpenOptions::new().create(true).append(true).open(&path) {
+            Ok(file) => state.audit_log = Some(io::BufWriter::new(file)),
+            Err(e) => eprintln!("Failed to open audit log {}: {}", PathBuf::from(path).display(), e),
+        }
+    }
+    timer.phase("open audit log");
+
+    let mut shell = Shell::with_editor(rl, state);
+
+    // Every interactive shell reads ~/.ashrc, so aliases and exports set
+    // there survive restarts; a login shell reads ~/.ash_profile first
+    if let Some(home) = dirs::home_dir() {
+        if options.login {
+            source_file(&home.join(".ash_profile"), &mut shell);
+        }
+        source_file(&home.join(".ashrc"), &mut shell);
+    }
+    timer.phase("startup files");
+
+    let handler = ctrlc::set_handler(move || {
+        INTERRUPTED.store(true, Ordering::SeqCst);
+        println!("\nType 'exit' to quit or use history to view commands");
+    });
+    // Without it Ctrl-C still works at the prompt; it just can't stop builtins
+    if let Err(e) = handler {
+        eprintln!("Failed to set the Ctrl-C handler: {}", e);
+    }
+    timer.phase("signal handlers");
+    if options.timing {
+        timer.report();
+    }
+    
+    loop {
+        report_finished_jobs(&mut shell.state, &mut io::stderr());
+        if let Some(hook) = shell.state.prompt_command.clone().filter(|_| !shell.state.posix) {
+            run_hook(&hook, &mut shell);
+        }
+        // `exit` in the last command, a hook or ~/.ashrc
+        if shell.state.exit_status.is_some() {
+            break;
+        }
+
+        let Some(rl) = shell.rl.as_mut() else {
+            break;
+        };
+        match print_prompt(rl) {
+            Ok(input) => {
+                if input.is_empty() {
+                    continue;
+                }
+
+                // The expanded line is what runs and what history keeps
+                let input = match expand_history(&input, rl.history()).filter(|_| !shell.state.posix) {
+                    Some(Ok(expanded)) => {
+                        println!("{}", expanded);
+                        expanded
+                    }
+                    Some(Err(event)) => {
+                        eprintln!("{}: event not found", event);
+                        shell.state.last_status = 1;
+                        continue;
+                    }
+                    None => input,
+                };
+                
+                // Add to history
+                if rl.add_history_entry(&input).unwrap_or(false) {
+                    record_history_time(&times_path, &input, shell.clock.now(), &mut shell.state);
+                    shell.state.history_new.push(input.clone());
+                }
+                
+                let started = Instant::now();
+                match parse_command_list(&input) {
+                    Ok(list) => {
+                        run_command_list(&list, &mut shell, &mut Sink::Terminal, &mut io::stderr());
+                    }
+                    Err(e) => {
+                        shell.state.last_status = e.exit_code();
+                        handle_error(e, "", shell.state.error_format);
+                    }
+                }
+                if !shell.state.posix {
+                    notify_if_slow(&input, started.elapsed());
+                }
+            }
+            Err(ReadlineError::Interrupted) => {
+                println!("^C");
+                continue;
+            }
+            Err(ReadlineError::Eof) => {
+                println!("exit");
+                break;
+            }
+            Err(err) => {
+                eprintln!("Readline error: {}", err);
+                break;
+            }
+        }
+    }
+
+    while let Some(loaded) = shell.state.autoenv_loaded.pop() {
+        unload_env(loaded);
+    }
+
+    let pending = shell.state.scheduled.lock().map(|jobs| jobs.len()).unwrap_or(0);
+    if pending > 0 {
+        eprintln!("Cancelling {} scheduled job(s) and timer(s)", pending);
+    }
+
+    append_history(&history_path, &shell.state.history_new, history_size)
+        .unwrap_or_else(|e| eprintln!("Failed to save history: {}", e));
+    trim_history_times(&times_path, history_size)
+        .unwrap_or_else(|e| eprintln!("Failed to trim history times: {}", e));
+    if let Some(status) = shell.state.exit_status {
+        exit(status);
+    }
+}
+
+// Asks the shell to stop rather than exiting here, so the main loop can
+// still save history and unload autoenv files on the way out. A bad status
+// is reported and the shell carries on.
+fn exit_shell(args: &[&str], state: &mut ShellState) -> ShellResult<()> {
+    let status = match args {
+        [] => 0,
+        [status] => status
+            .parse()
+            .map_err(|_| ShellError::InvalidArgument(format!("numeric status required: {}", status)))?,
+        _ => return Err(ShellError::InvalidArgument("too many arguments".to_string())),
+    };
+    state.exit_status = Some(status);
+    Ok(())
+}
+
+// With ASH_NOTIFY_THRESHOLD set (e.g. "10" or "2m"), a foreground command that
+// runs at least that long rings the terminal bell when it finishes, or sends a
+// desktop notification when ASH_NOTIFY_STYLE=desktop. Off by default.
+fn notify_if_slow(line: &str, elapsed: Duration) {
+    let Some(threshold) = env::var("ASH_NOTIFY_THRESHOLD").ok().and_then(|value| parse_duration(&value)) else {
+        return;
+    };
+    if elapsed < threshold {
+        return;
+    }
+
+    if env::var("ASH_NOTIFY_STYLE").as_deref() == Ok("desktop") {
+        let body = format!("'{}' finished after {}s", line, elapsed.as_secs());
+        if send_notification("ash", &body).is_ok() {
+            return;
+        }
+    }
+    eprint!("\x07");
+    let _ = io::stderr().flush();
+}
+
+// Appends one JSON object per line to the ASH_AUDIT_LOG file:
+// {"timestamp": RFC 3339 time, "cwd": directory the command ran in,
+//  "command": name, "args": [arguments], "status": exit status (0 on
+//  success), "error": message (only present on failure)}
+// Each record is written in one buffered write and flushed straight away so
+// the trail survives a crash.
+fn audit_command(
+    state: &mut ShellState,
+    now: DateTime<Local>,
+    cwd: &Path,
+    command: &str,
+    args: &[&str],
+    result: &ShellResult<()>,
+) {
+    let Some(log) = &mut state.audit_log else {
+        return;
+    };
+
+    let mut record = serde_json::json!({
+        "timestamp": now.to_rfc3339(),
+        "cwd": cwd.to_string_lossy(),
+        "command": command,
+        "args": args,
+        "status": state.last_status,
+    });
+    if let Err(e) = result {
+        record["error"] = serde_json::Value::from(e.to_string());
+    }
+
+    let written = writeln!(log, "{}", record).and_then(|_| log.flush());
+    if let Err(e) = written {
+        eprintln!("Failed to write audit log: {}", e);
+    }
+}
+
+// Hooks are full command lines, so `date; ls | wc -l` works. They report
+// their own errors but never interrupt the caller, and `$?` afterwards is
+// still the status of the caller's last command.
+fn run_hook(line: &str, shell: &mut Shell) {
+    let status = shell.state.last_status;
+    match parse_command_list(line) {
+        Ok(list) => {
+            run_command_list(&list, shell, &mut Sink::Terminal, &mut io::stderr());
+        }
+        Err(e) => handle_error(e, "", shell.state.error_format),
+    }
+    shell.state.last_status = status;
+}
+
+// Runs each line of a startup file as a command. `KEY=VALUE` (or
+// `export KEY=VALUE`) lines set environment variables; blank lines and `#`
+// comments are skipped. A missing file is not an error.
+fn source_file(path: &Path, shell: &mut Shell) {
+    let Ok(content) = fs::read_to_string(path) else {
+        return;
+    };
+    for line in content.lines().map(|line| strip_comment(line).trim()) {
+        if line.is_empty() {
+            continue;
+        }
+        match parse_env_file(line).pop() {
+            Some((key, value)) => env::set_var(key, value),
+            None => run_hook(line, shell),
+        }
+    }
+}
+
+// How error reports are written: `--color` and `--errors-json` at startup
+#[derive(Clone, Copy, Default)]
+enum ErrorFormat {
+    #[default]
+    Plain,
+    Color,
+    // One JSON object per error, for tools driving the shell
+    Json,
+}
+
+fn handle_error(error: ShellError, command: &str, format: ErrorFormat) {
+    let _ = report_error(error, command, format, &mut io::stderr());
+}
+
+fn report_error(error: ShellError, command: &str, format: ErrorFormat, errors: &mut dyn Write) -> io::Result<()> {
+    if matches!(error, ShellError::Reported(_)) {
+        return Ok(());
+    }
+    let (message, hint) = describe_error(&error, command);
+    // Errors that aren't tied to one command (such as a malformed pipeline)
+    // have no usage line to show
+    let usage = match error {
+        ShellError::InvalidArgument(_) | ShellError::MissingArguments(_) => get_command_usage(command),
+        _ => "",
+    };
+
+    match format {
+        ErrorFormat::Plain => {
+            writeln!(errors, "{}", message)?;
+            if let Some(hint) = hint {
+                writeln!(errors, "{}", hint)?;
+            }
+            if !usage.is_empty() {
+                writeln!(errors, "Usage: {}", usage)?;
+            }
+        }
+        ErrorFormat::Color => {
+            writeln!(errors, "\x1b[1;31m{}\x1b[0m", message)?;
+            if let Some(hint) = hint {
+                writeln!(errors, "\x1b[33m{}\x1b[0m", hint)?;
+            }
+            if !usage.is_empty() {
+                writeln!(errors, "\x1b[2mUsage: {}\x1b[0m", usage)?;
+            }
+        }
+        ErrorFormat::Json => {
+            let mut record = serde_json::json!({
+                "kind": error_kind(&error),
+                "message": message,
+            });
+            if !command.is_empty() {
+                record["command"] = serde_json::Value::from(command);
+            }
+            if let Some(hint) = hint {
+                record["hint"] = serde_json::Value::from(hint);
+            }
+            if !usage.is_empty() {
+                record["usage"] = serde_json::Value::from(usage);
+            }
+            writeln!(errors, "{}", record)?;
+        }
+    }
+    Ok(())
+}
+
+// The report's main line, and a suggestion to follow it where there is one
+fn describe_error(error: &ShellError, command: &str) -> (String, Option<String>) {
+    let hint = |hint: &str| Some(hint.to_string());
+    match error {
+        ShellError::Io(e) => {
+            let hint = (e.kind() == io::ErrorKind::PermissionDenied).then(|| "Try running with elevated privileges".to_string());
+            (format!("Error in {}: {}", command, e), hint)
+        }
+        ShellError::InvalidArgument(msg) => (format!("Invalid argument: {}", msg), None),
+        ShellError::MissingArguments(arg) => (format!("Missing required argument: {}", arg), None),
+        ShellError::FileNotFound(path) => (format!("File not found: {}", path), hint("Check the path and try again")),
+        ShellError::IsDirectory(path) => (format!("Is a directory: {}", path), hint("Did you mean to use a file instead?")),
+        ShellError::PermissionDenied(path) => {
+            (format!("Permission denied: {}", path), hint("Try running with elevated privileges"))
+        }
+        // Only when the command itself wasn't found, not a name that `which`
+        // or `timeout` was given
+        ShellError::CommandNotFound(name) if name == command => {
+            (error.to_string(), closest_builtin(name).map(|builtin| format!("did you mean '{}'?", builtin)))
+        }
+        ShellError::TimedOut(cmd, limit) => (format!("{}: timed out after {:?} (status 124)", cmd, limit), None),
+        e => (e.to_string(), None),
+    }
+}
+
+fn error_kind(error: &ShellError) -> &'static str {
+    match error {
+        ShellError::Io(_) => "io",
+        ShellError::InvalidArgument(_) => "invalid_argument",
+        ShellError::MissingArguments(_) => "missing_arguments",
+        ShellError::CommandNotFound(_) => "command_not_found",
+        ShellError::FileNotFound(_) => "file_not_found",
+        ShellError::IsDirectory(_) => "is_directory",
+        ShellError::NotADirectory(_) => "not_a_directory",
+        ShellError::PermissionDenied(_) => "permission_denied",
+        ShellError::Unavailable(_) => "unavailable",
+        ShellError::Reported(_) => "reported",
+        ShellError::TimedOut(..) => "timed_out",
+    }
+}
+
+// The builtin a mistyped command most likely meant: the nearest by edit
+// distance, if that is at most 2 and less than the name's own length (so
+// `ab` doesn't turn into `cd`). Ties go to the builtin listed first.
+fn closest_builtin(name: &str) -> Option<&'static str> {
+    let limit = name.chars().count().min(3);
+    BUILTINS
+        .iter()
+        .map(|builtin| (edit_distance(name, builtin.name), builtin.name))
+        .filter(|&(distance, _)| distance < limit)
+        .min_by_key(|&(distance, _)| distance)
+        .map(|(_, builtin)| builtin)
+}
+
+// Levenshtein distance: the fewest single-character insertions, deletions
+// and substitutions that turn `a` into `b`
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut current = vec![i + 1];
+        for (j, &cb) in b.iter().enumerate() {
+            let substitute = previous[j] + usize::from(ca != cb);
+            current.push(substitute.min(previous[j + 1] + 1).min(current[j] + 1));
+        }
+        previous = current;
+    }
+    previous[b.len()]
+}
+
+fn get_command_usage(command: &str) -> &'static str {
+    BUILTINS.iter().find(|builtin| builtin.name() == command).map_or("", |builtin| builtin.usage())
+}
+
+// Helper functions
+fn print_prompt(rl: &mut LineEditor) -> Result<String, ReadlineError> {
+    let current_dir = env::current_dir()
+        .unwrap_or_else(|_| PathBuf::from("."))
+        .display()
+        .to_string();
+    
+    let prompt = match env::var("ASH_PROMPT") {
+        Ok(template) if !template.is_empty() => render_prompt(&template, &current_dir),
+        _ => format!("ASH$ {} > ", current_dir),
+    };
+    let mut input = rl.readline(&prompt)?;
+    // An open quote or a trailing backslash carries on to the next line.
+    // Ctrl-C there abandons the whole command; at end of input, what was
+    // read so far runs and reports its unterminated quote.
+    while is_incomplete(&input) {
+        match rl.readline("> ") {
+            Ok(more) => {
+                input.push('\n');
+                input.push_str(&more);
+            }
+            Err(ReadlineError::Eof) => break,
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(input)
+}
+
+// Fills in an ASH_PROMPT template: `\w` is the working directory (with the
+// home directory shown as `~`), `\u` the user name, `\h` the host name up to
+// the first `.`, `\$` a `$` and `\\` a backslash. Anything else, including an
+// unknown escape, is printed as written.
+fn render_prompt(template: &str, current_dir: &str) -> String {
+    let mut prompt = String::new();
+    let mut chars = template.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            prompt.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('w') => {
+                let home = dirs::home_dir().map(|home| home.display().to_string());
+                match home.as_deref().and_then(|home| current_dir.strip_prefix(home)) {
+                    Some(rest) if rest.is_empty() || rest.starts_with('/') => {
+                        prompt.push('~');
+                        prompt.push_str(rest);
+                    }
+                    _ => prompt.push_str(current_dir),
+                }
+            }
+            Some('u') => prompt.push_str(&env::var("USER").or_else(|_| env::var("USERNAME")).unwrap_or_default()),
+            Some('h') => prompt.push_str(host_name().split('.').next().unwrap_or("")),
+            Some('$') => prompt.push('$'),
+            Some('\\') => prompt.push('\\'),
+            Some(other) => {
+                prompt.push('\\');
+                prompt.push(other);
+            }
+            None => prompt.push('\\'),
+        }
+    }
+    prompt
+}
+
+#[cfg(unix)]
+fn host_name() -> String {
+    let mut name = [0u8; 256];
+    if unsafe { libc::gethostname(name.as_mut_ptr() as *mut libc::c_char, name.len()) } != 0 {
+        return String::new();
+    }
+    let len = name.iter().position(|&b| b == 0).unwrap_or(name.len());
+    String::from_utf8_lossy(&name[..len]).into_owned()
+}
+
+#[cfg(not(unix))]
+fn host_name() -> String {
+    env::var("COMPUTERNAME").unwrap_or_default()
+}
+
+// Whether a command line stops inside a quote or right after a backslash
+fn is_incomplete(line: &str) -> bool {
+    let mut chars = line.chars();
+    let mut quoted = String::new();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' if chars.next().is_none() => return true,
+            '\'' | '"' if copy_quoted(c, &mut chars, &mut quoted).is_err() => return true,
+            _ => {}
+        }
+    }
+    false
+}
+
+fn _read_input() -> String {
+    let mut input = String::new();
+    io::stdin().read_line(&mut input).expect("Failed to read line");
+    input.trim_end().to_string()
+}
+
+// Splits a command into words and expands each one: `~`, then variables,
+// then wildcards. Single quotes keep everything inside them literal; double
+// quotes keep spaces and wildcards but still expand variables. Outside
+// quotes a backslash makes the next space, quote, backslash, `$`, `~`, `#`,
+// `<`, `>` or wildcard character literal, and is kept before anything else
+// (so `\n` reaches `echo -e` intact). A backslash before a newline joins the
+// lines. A word that expands to nothing (such as an unset `$VAR`) is
+// dropped, as in other shells, but `""` is an empty argument. An unquoted
+// variable's value is split into words at the characters in $IFS; see
+// `split_fields`. `status` is what `$?` expands to.
+fn parse_input(input: &str, status: i32) -> ShellResult<Vec<Word>> {
+    let ifs = env::var("IFS").unwrap_or_else(|_| DEFAULT_IFS.to_string());
+    let mut words = Vec::new();
+    let mut rest = input.trim_start();
+    while !rest.is_empty() {
+        let mut word = String::new();
+        let mut quoted = false;
+        let mut redirect = rest.starts_with(['<', '>']);
+        // Only wildcards that aren't quoted or escaped make a pattern
+        let mut pattern = false;
+        if rest.starts_with('~') {
+            let end = rest.find(|c: char| c == '/' || c.is_whitespace()).unwrap_or(rest.len());
+            if let Some((home, name)) = expand_tilde(&rest[..end])? {
+                word.push_str(&home);
+                word.push_str(name);
+            }
+            rest = &rest[end..];
+        }
+
+        while let Some(c) = rest.chars().next().filter(|c| !c.is_whitespace()) {
+            rest = &rest[c.len_utf8()..];
+            match c {
+                '\'' => {
+                    let (text, after) = rest.split_once('\'').ok_or_else(unterminated_quote)?;
+                    word.push_str(text);
+                    rest = after;
+                    quoted = true;
+                }
+                '"' => {
+                    rest = take_double_quoted(rest, &mut word, status)?;
+                    quoted = true;
+                }
+                '\\' => match rest.chars().next() {
+                    Some('\n') => rest = &rest[1..],
+                    Some(next) if next.is_whitespace() || "'\"\\$~#*?[<>&".contains(next) => {
+                        word.push(next);
+                        rest = &rest[next.len_utf8()..];
+                    }
+                    _ => word.push('\\'),
+                },
+                '$' => {
+                    let (value, after) = take_variable(rest, status)?;
+                    let mut fields = split_fields(&value, &ifs).into_iter().peekable();
+                    // The first field joins the text before the variable and
+                    // the last one the text after it
+                    if let Some(first) = fields.next() {
+                        pattern |= first.contains(['*', '?', '[']);
+                        word.push_str(first);
+                    }
+                    while let Some(field) = fields.next() {
+                        finish_word(&mut words, std::mem::take(&mut word), quoted, pattern, redirect);
+                        redirect = false;
+                        // An empty field between two separators is still a word
+                        quoted = field.is_empty() && fields.peek().is_some();
+                        pattern = field.contains(['*', '?', '[']);
+                        word.push_str(field);
+                    }
+                    rest = after;
+                }
+                '*' | '?' | '[' => {
+                    pattern = true;
+                    word.push(c);
+                }
+                // A trailing `&` is a word of its own even when attached, so
+                // `sleep 10&` runs in the background like `sleep 10 &`
+                '&' if rest.trim_start().is_empty() => {
+                    finish_word(&mut words, std::mem::take(&mut word), quoted, pattern, redirect);
+                    (quoted, pattern, redirect) = (false, false, false);
+                    word.push('&');
+                }
+                _ => word.push(c),
+            }
+        }
+        rest = rest.trim_start();
+        finish_word(&mut words, word, quoted, pattern, redirect);
+    }
+    Ok(words)
+}
+
+// A word of a command line after expansion
+struct Word {
+    text: String,
+    // Whether it began with an unquoted `<` or `>`, which makes it a
+    // redirection rather than an argument
+    redirect: bool,
+}
+
+// What $IFS is taken to be when it is unset
+const DEFAULT_IFS: &str = " \t\n";
+
+fn finish_word(words: &mut Vec<Word>, word: String, quoted: bool, pattern: bool, redirect: bool) {
+    if pattern {
+        words.extend(expand_glob(&word).into_iter().map(|text| Word { text, redirect: false }));
+    } else if !word.is_empty() || quoted {
+        words.push(Word { text: word, redirect });
+    }
+}
+
+// Splits a variable's value into fields as POSIX field splitting does. A
+// run of IFS whitespace is a single break, and whitespace at either end
+// only separates the value from the text around it. Every other IFS
+// character ends a field, so with IFS=: `a::b` gives `a`, an empty field
+// and `b`. An empty IFS leaves the value whole. Each returned field but the
+// last was ended by a break; an empty first or last field stands for a
+// break at that end.
+fn split_fields<'a>(value: &'a str, ifs: &str) -> Vec<&'a str> {
+    let mut fields = Vec::new();
+    let mut start = 0;
+    // Whether the field so far was started by IFS whitespace, which a
+    // following non-whitespace separator merges with
+    let mut after_space = false;
+    for (i, c) in value.char_indices() {
+        if !ifs.contains(c) {
+            after_space = false;
+            continue;
+        }
+        let field = &value[start..i];
+        if c.is_whitespace() {
+            if !field.is_empty() || fields.is_empty() {
+                fields.push(field);
+            }
+            after_space = true;
+        } else if !(field.is_empty() && after_space) {
+            fields.push(field);
+            after_space = false;
+        } else {
+            after_space = false;
+        }
+        start = i + c.len_utf8();
+    }
+    fields.push(&value[start..]);
+    fields
+}
+
+fn unterminated_quote() -> ShellError {
+    ShellError::InvalidArgument("unterminated quote".to_string())
+}
+
+// Copies the inside of a double-quoted string, given what follows the
+// opening quote, and returns what follows the closing one. Variables are
+// expanded, and a backslash only escapes `$`, `"` and another backslash,
+// or joins two lines.
+fn take_double_quoted<'a>(mut rest: &'a str, word: &mut String, status: i32) -> ShellResult<&'a str> {
+    while let Some(c) = rest.chars().next() {
+        rest = &rest[c.len_utf8()..];
+        match c {
+            '"' => return Ok(rest),
+            '\\' => match rest.chars().next() {
+                Some(next @ ('$' | '"' | '\\')) => {
+                    word.push(next);
+                    rest = &rest[1..];
+                }
+                Some('\n') => rest = &rest[1..],
+                _ => word.push('\\'),
+            },
+            '$' => {
+                let (value, after) = take_variable(rest, status)?;
+                word.push_str(&value);
+                rest = after;
+            }
+            _ => word.push(c),
+        }
+    }
+    Err(unterminated_quote())
+}
+
+// Copies a quoted section of a command line, quotes and all, so the `|`,
+// `;` and `&` inside it are left for parse_input to treat as text
+fn copy_quoted(quote: char, chars: &mut impl Iterator<Item = char>, out: &mut String) -> ShellResult<()> {
+    out.push(quote);
+    while let Some(c) = chars.next() {
+        out.push(c);
+        if c == quote {
+            return Ok(());
+        }
+        if c == '\\' && quote == '"' {
+            out.extend(chars.next());
+        }
+    }
+    Err(unterminated_quote())
+}
+
+// Expands `*`, `?` and `[...]` against the filesystem one path component at
+// a time, giving the sorted matches. A pattern that matches nothing is kept
+// as it is, as in sh. Names starting with `.` only match a component that
+// starts with `.` too.
+fn expand_glob(word: &str) -> Vec<String> {
+    let is_pattern = |text: &str| text.contains(['*', '?', '[']);
+    if !is_pattern(word) {
+        return vec![word.to_string()];
+    }
+    let join = |base: &str, name: &str| match base {
+        "" => name.to_string(),
+        _ if base.ends_with('/') => format!("{}{}", base, name),
+        _ => format!("{}/{}", base, name),
+    };
+
+    let (mut paths, rest) = match word.strip_prefix('/') {
+        Some(rest) => (vec![String::from("/")], rest),
+        None => (vec![String::new()], word),
+    };
+    for component in rest.split('/') {
+        let mut next = Vec::new();
+        for base in &paths {
+            if !is_pattern(component) {
+                next.push(join(base, component));
+                continue;
+            }
+            let Ok(entries) = fs::read_dir(if base.is_empty() { "." } else { base }) else {
+                continue;
+            };
+            let mut names: Vec<String> = entries
+                .flatten()
+                .map(|entry| entry.file_name().to_string_lossy().into_owned())
+                .filter(|name| (!name.starts_with('.') || component.starts_with('.')) && glob_match(component, name))
+                .collect();
+            names.sort();
+            next.extend(names.iter().map(|name| join(base, name)));
+        }
+        paths = next;
+    }
+
+    // Literal components after a wildcard, like the `src` in `*/src`, still
+    // have to exist
+    paths.retain(|path| fs::symlink_metadata(path).is_ok());
+    if paths.is_empty() {
+        vec![word.to_string()]
+    } else {
+        paths
+    }
+}
+
+// A leading `~` or `~/` stands for the home directory; returns it and the
+// rest of the word. A `~` anywhere else, as in `file~`, is left alone.
+fn expand_tilde(word: &str) -> ShellResult<Option<(String, &str)>> {
+    let Some(rest) = word.strip_prefix('~') else {
+        return Ok(None);
+    };
+    if !rest.is_empty() && !rest.starts_with('/') {
+        let user = rest.split('/').next().unwrap_or(rest);
+        return Err(ShellError::InvalidArgument(format!(
+            "cannot expand ~{}: other users' home directories are not supported",
+            user
+        )));
+    }
+    let home = dirs::home_dir().ok_or_else(|| ShellError::InvalidArgument("Home directory not found".into()))?;
+    Ok(Some((home.to_string_lossy().into_owned(), rest)))
+}
+
+fn split_command(words: &[String]) -> (&str, Vec<&str>) {
+    let mut parts = words.iter().map(String::as_str);
+    let command = parts.next().unwrap_or("");
+    (command, parts.collect())
+}
+
+// Expands the variable named at the start of `after` (the text following a
+// `$`): `NAME`, `?` or a `${...}` expression (see `expand_parameter`).
+// Returns its value, empty if unset, and the text after the name. A `$` not
+// followed by a name, or a `${` that is never closed, stays a `$`.
+fn take_variable(after: &str, status: i32) -> ShellResult<(String, &str)> {
+    if let Some(remainder) = after.strip_prefix('?') {
+        return Ok((status.to_string(), remainder));
+    }
+    if let Some(braced) = after.strip_prefix('{') {
+        return Ok(match closing_brace(braced) {
+            Some(end) => (expand_parameter(&braced[..end], status)?, &braced[end + 1..]),
+            None => ("$".to_string(), after),
+        });
+    }
+    let len = after.find(|c: char| c != '_' && !c.is_ascii_alphanumeric()).unwrap_or(after.len());
+    match after.split_at(len) {
+        (name, remainder) if is_variable_name(name) => Ok((env::var(name).unwrap_or_default(), remainder)),
+        _ => Ok(("$".to_string(), after)),
+    }
+}
+
+// Where the `}` closing a `${` is, allowing for `${...}` and quotes inside
+fn closing_brace(text: &str) -> Option<usize> {
+    let mut depth = 0;
+    let mut chars = text.char_indices();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '\\' => {
+                chars.next();
+            }
+            '\'' | '"' => skip_quoted(c, &mut chars),
+            '{' => depth += 1,
+            '}' if depth == 0 => return Some(i),
+            '}' => depth -= 1,
+            _ => {}
+        }
+    }
+    None
+}
+
+// The value of a `${...}` expression, given what is between the braces:
+//   NAME              the variable's value
+//   #NAME             its length in characters
+//   NAME:-WORD        WORD if NAME is unset or empty
+//   NAME:=WORD        the same, also setting NAME to WORD
+//   NAME:?MESSAGE     an error (MESSAGE, or a default one) if unset or empty
+//   NAME:+WORD        WORD if NAME is set and not empty, otherwise nothing
+//   NAME#PATTERN      the value without the shortest prefix matching PATTERN
+//                     (## for the longest), or suffix with % and %%
+//   NAME/OLD/NEW      the value with the first match of OLD replaced by NEW
+//                     (// for every match)
+// Without the `:`, -, =, ? and + only test whether NAME is unset, so an
+// empty value counts as set. WORD and the patterns have their own variables
+// expanded first.
+fn expand_parameter(body: &str, status: i32) -> ShellResult<String> {
+    if body == "?" {
+        return Ok(status.to_string());
+    }
+    if let Some(name) = body.strip_prefix('#').filter(|name| is_variable_name(name)) {
+        return Ok(env::var(name).unwrap_or_default().chars().count().to_string());
+    }
+    let bad_substitution = || ShellError::InvalidArgument(format!("bad substitution: ${{{}}}", body));
+    let len = body.find(|c: char| c != '_' && !c.is_ascii_alphanumeric()).unwrap_or(body.len());
+    let (name, op) = body.split_at(len);
+    if !is_variable_name(name) {
+        return Err(bad_substitution());
+    }
+    let value = env::var(name).ok();
+    if op.is_empty() {
+        return Ok(value.unwrap_or_default());
+    }
+
+    let (colon, test) = match op.strip_prefix(':') {
+        Some(rest) => (true, rest),
+        None => (false, op),
+    };
+    if let Some(kind) = test.chars().next().filter(|c| "-=?+".contains(*c)) {
+        let word = expand_word(&test[1..], status)?;
+        let missing = value.as_deref().is_none_or(|value| colon && value.is_empty());
+        return match kind {
+            '+' if missing => Ok(String::new()),
+            '+' => Ok(word),
+            _ if !missing => Ok(value.unwrap_or_default()),
+            '=' => {
+                env::set_var(name, &word);
+                Ok(word)
+            }
+            '?' if word.is_empty() => Err(ShellError::InvalidArgument(format!("{}: parameter null or not set", name))),
+            '?' => Err(ShellError::InvalidArgument(format!("{}: {}", name, word))),
+            _ => Ok(word),
+        };
+    }
+    if colon {
+        return Err(bad_substitution());
+    }
+
+    let value = value.unwrap_or_default();
+    // Every place a match can start or end
+    let bounds: Vec<usize> = value.char_indices().map(|(i, _)| i).chain([value.len()]).collect();
+    let cut = |pattern: &str, prefix: bool, longest: bool| -> ShellResult<String> {
+        let pattern = expand_word(pattern, status)?;
+        let matches = |&&i: &&usize| {
+            if prefix { pattern_match(&pattern, &value[..i]) } else { pattern_match(&pattern, &value[i..]) }
+        };
+        // Prefixes grow and suffixes shrink as the boundary moves right
+        let found = if prefix == longest { bounds.iter().rev().find(matches) } else { bounds.iter().find(matches) };
+        Ok(match found {
+            Some(&i) if prefix => value[i..].to_string(),
+            Some(&i) => value[..i].to_string(),
+            None => value.clone(),
+        })
+    };
+    if let Some(pattern) = op.strip_prefix("##") {
+        return cut(pattern, true, true);
+    }
+    if let Some(pattern) = op.strip_prefix('#') {
+        return cut(pattern, true, false);
+    }
+    if let Some(pattern) = op.strip_prefix("%%") {
+        return cut(pattern, false, true);
+    }
+    if let Some(pattern) = op.strip_prefix('%') {
+        return cut(pattern, false, false);
+    }
+    if let Some(rest) = op.strip_prefix('/') {
+        let (all, rest) = match rest.strip_prefix('/') {
+            Some(rest) => (true, rest),
+            None => (false, rest),
+        };
+        let (old, new) = rest.split_once('/').unwrap_or((rest, ""));
+        let (old, new) = (expand_word(old, status)?, expand_word(new, status)?);
+        return Ok(replace_matches(&value, &old, &new, all));
+    }
+    Err(bad_substitution())
+}
+
+// Replaces the longest match of `pattern` at the first place it matches (or
+// at every place, left to right, with `all`). An empty pattern replaces
+// nothing.
+fn replace_matches(value: &str, pattern: &str, replacement: &str, all: bool) -> String {
+    if pattern.is_empty() {
+        return value.to_string();
+    }
+    let bounds: Vec<usize> = value.char_indices().map(|(i, _)| i).chain([value.len()]).collect();
+    let mut replaced = String::with_capacity(value.len());
+    let mut copied = 0;
+    let mut start = 0;
+    while start < bounds.len() {
+        let from = bounds[start];
+        let end = bounds[start..].iter().rposition(|&to| pattern_match(pattern, &value[from..to]));
+        match end {
+            // An empty match would replace between every character
+            Some(end) if end > 0 => {
+                replaced.push_str(&value[copied..from]);
+                replaced.push_str(replacement);
+                copied = bounds[start + end];
+                start += end;
+                if !all {
+                    break;
+                }
+            }
+            _ => start += 1,
+        }
+    }
+    replaced.push_str(&value[copied..]);
+    replaced
+}
+
+// Expands the WORD of a `${...}` expression: variables, quotes and
+// backslash escapes, but no splitting or wildcards
+fn expand_word(mut rest: &str, status: i32) -> ShellResult<String> {
+    let mut word = String::new();
+    while let Some(c) = rest.chars().next() {
+        rest = &rest[c.len_utf8()..];
+        match c {
+            '\\' => match rest.chars().next() {
+                Some(next) => {
+                    word.push(next);
+                    rest = &rest[next.len_utf8()..];
+                }
+                None => word.push(c),
+            },
+            '\'' => {
+                let (text, after) = rest.split_once('\'').ok_or_else(unterminated_quote)?;
+                word.push_str(text);
+                rest = after;
+            }
+            '"' => rest = take_double_quoted(rest, &mut word, status)?,
+            '$' => {
+                let (value, after) = take_variable(rest, status)?;
+                word.push_str(&value);
+                rest = after;
+            }
+            _ => word.push(c),
+        }
+    }
+    Ok(word)
+}
+
+fn is_variable_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars.next().is_some_and(|c| c == '_' || c.is_ascii_alphabetic())
+        && chars.all(|c| c == '_' || c.is_ascii_alphanumeric())
+}
+
+// Replaces an aliased command word with the words of its definition, and
+// repeats that while the new command word is another alias. As in bash, each
+// alias is applied at most once per command, so one that names itself
+// (`alias ls='ls -a'`) or loops through others stops there. Arguments are
+// never expanded.
+fn expand_alias(mut words: Vec<Word>, state: &ShellState) -> ShellResult<Vec<Word>> {
+    let mut applied = HashSet::new();
+    while let Some((name, value)) = words.first().and_then(|command| state.aliases.get_key_value(&command.text)) {
+        if !applied.insert(name) {
+            break;
+        }
+        let mut expanded = parse_input(value, state.last_status)?;
+        expanded.extend(words.drain(1..));
+        words = expanded;
+    }
+    Ok(words)
+}
+
+fn alias(args: &[&str], state: &mut ShellState, out: &mut dyn Write) -> ShellResult<()> {
+    if args.is_empty() {
+        let mut aliases: Vec<_> = state.aliases.iter().collect();
+        aliases.sort();
+        for (name, value) in aliases {
+            writeln!(out, "alias {}='{}'", name, value.replace('\'', "'\\''"))?;
+        }
+        return Ok(());
+    }
+
+    // `alias ll='ls -l'` arrives as one word with its quotes removed; an
+    // unquoted `alias ll=ls -l` is joined back up, so everything after the
+    // first `=` is the definition either way
+    let definition = args.join(" ");
+    let Some((name, value)) = definition.split_once('=') else {
+        for name in args {
+            let value = state.aliases.get(*name).ok_or_else(|| ShellError::InvalidArgument(format!("{}: not found", name)))?;
+            writeln!(out, "alias {}='{}'", name, value.replace('\'', "'\\''"))?;
+        }
+        return Ok(());
+    };
+    if name.is_empty() || name.contains(char::is_whitespace) {
+        return Err(ShellError::InvalidArgument(format!("invalid alias name: {}", name)));
+    }
+    state.aliases.insert(name.to_string(), value.to_string());
+    Ok(())
+}
+
+fn unalias(args: &[&str], state: &mut ShellState) -> ShellResult<()> {
+    match args {
+        [] => Err(ShellError::MissingArguments("alias name")),
+        ["-a"] => {
+            state.aliases.clear();
+            Ok(())
+        }
+        names => {
+            for name in names {
+                if state.aliases.remove(*name).is_none() {
+                    return Err(ShellError::InvalidArgument(format!("{}: not found", name)));
+                }
+            }
+            Ok(())
+        }
+    }
+}
+
+// Anything the shell can run by name. Each Shell keeps a registry of these,
+// filled with BUILTINS when it is created and extended by `Shell::register`;
+// names not in the registry are looked up as plugins and then on PATH.
+pub trait Command {
+    fn name(&self) -> &str;
+    fn usage(&self) -> &str;
+    fn description(&self) -> &str;
+    fn run(&self, shell: &mut Shell, args: &[&str], io: &mut Streams) -> ShellResult<()>;
+}
+
+type Registry = HashMap<String, Rc<dyn Command>>;
+
+#[derive(Clone, Copy)]
+struct Builtin {
+    name: &'static str,
+    // Shown after usage errors, starting with the command's name
+    usage: &'static str,
+    // The short form and one-line description `help` lists
+    synopsis: &'static str,
+    description: &'static str,
+    run: fn(&mut Shell, &[&str], &mut Streams) -> ShellResult<()>,
+}
+
+impl Command for Builtin {
+    fn name(&self) -> &str {
+        self.name
+    }
+
+    fn usage(&self) -> &str {
+        self.usage
+    }
+
+    fn description(&self) -> &str {
+        self.description
+    }
+
+    fn run(&self, shell: &mut Shell, args: &[&str], io: &mut Streams) -> ShellResult<()> {
+        (self.run)(shell, args, io)
+    }
+}
+
+static BUILTINS: &[Builtin] = &[
+    Builtin {
+        name: "exit",
+        usage: "exit [status]",
+        synopsis: "exit [n]",
+        description: "Exit the shell (with status 0 unless given one)",
+        run: |shell, args, _| exit_shell(args, &mut shell.state),
+    },
+    Builtin {
+        name: "cd",
+        usage: "cd [directory | -]",
+        synopsis: "cd [dir]",
+        description: "Change directory (cd - returns to the previous one)",
+        run: |shell, args, io| cd(args, shell, &mut io.output),
+    },
+    Builtin {
+        name: "pushd",
+        usage: "pushd [directory]",
+        synopsis: "pushd [dir]",
+        description: "Save the current directory and cd (no args swaps the top two)",
+        run: |shell, args, io| pushd(args, shell, &mut io.output),
+    },
+    Builtin {
+        name: "popd",
+        usage: "popd",
+        synopsis: "popd",
+        description: "Return to the directory saved by pushd",
+        run: |shell, _, io| popd(shell, &mut io.output),
+    },
+    Builtin {
+        name: "dirs",
+        usage: "dirs",
+        synopsis: "dirs",
+        description: "Show the directory stack",
+        run: |shell, _, io| dirs(&shell.state, &mut io.output),
+    },
+    Builtin {
+        name: "ls",
+        usage: "ls [-la] [--color=always|never|auto] [directory]",
+        synopsis: "ls [path]",
+        description: "List directory contents (-l for details, -a for dotfiles)",
+        run: |_, args, io| ls(args, io.output.is_terminal(), &mut io.output),
+    },
+    Builtin {
+        name: "cat",
+        usage: "cat [-n] [file...]",
+        synopsis: "cat <file>",
+        description: "Display file content (-n numbers lines)",
+        run: |_, args, io| cat(args, io.input.as_deref(), &mut io.output),
+    },
+    Builtin {
+        name: "mkdir",
+        usage: "mkdir [-p] <directory>...",
+        synopsis: "mkdir <dir>",
+        description: "Create directories (-p to create parents as needed)",
+        run: |_, args, _| mkdir(args),
+    },
+    Builtin {
+        name: "touch",
+        usage: "touch [-c] <file>...",
+        synopsis: "touch <file>",
+        description: "Update file times, creating missing files empty (-c to not create)",
+        run: |_, args, _| touch(args),
+    },
+    Builtin {
+        name: "stat",
+        usage: "stat <file>...",
+        synopsis: "stat <file>",
+        description: "Show a file's type, size, permissions and timestamps",
+        run: |_, args, io| stat(args, &mut io.output),
+    },
+    Builtin {
+        name: "chmod",
+        usage: "chmod <octal-mode> <file>...",
+        synopsis: "chmod <mode> <file>",
+        description: "Set file permissions from an octal mode like 755",
+        run: |_, args, _| chmod(args),
+    },
+    Builtin {
+        name: "ln",
+        usage: "ln [-s] <target> <link_name>",
+        synopsis: "ln [-s] <tgt> <link>",
+        description: "Create a hard link (-s for a symbolic link)",
+        run: |_, args, _| ln(args),
+    },
+    Builtin {
+        name: "rm",
+        usage: "rm [-rfi] [--no-preserve-root] <file_or_directory>...",
+        synopsis: "rm <path>",
+        description: "Remove file/directory",
+        run: |shell, args, _| rm(args, &shell.state),
+    },
+    Builtin {
+        name: "cp",
+        usage: "cp [-r [-L | -P] [--max-depth N]] <source> <destination> | cp [-r] <source>... <directory>",
+        synopsis: "cp <src> <dst>",
+        description: "Copy file (-r for directories)",
+        run: |_, args, _| cp(args),
+    },
+    Builtin {
+        name: "mv",
+        usage: "mv <source> <destination> | mv <source>... <directory>",
+        synopsis: "mv <src> <dst>",
+        description: "Move/rename file",
+        run: |shell, args, _| mv(args, &shell.state),
+    },
+    Builtin {
+        name: "grep",
+        usage: "grep [-Eivncoqz] [-r [-L | -P]] [--include=GLOB | --name=GLOB] [--exclude=GLOB] [--no-ignore] [--binary-files=TYPE] [--total] [--max-depth N] <pattern | --last> <file|dir>...",
+        synopsis: "grep <pattern> <file>",
+        description: "Search text (-i, -v, -n, -c; -r to recurse, --include/--exclude GLOB)",
+        run: |_, args, io| grep(args, io.input.as_deref(), &mut io.output),
+    },
+    Builtin {
+        name: "du",
+        usage: "du [-shLP] [--max-depth <n>] [--exclude <glob>] [--sort[=size|name]] [path...]",
+        synopsis: "du [path]",
+        description: "Show the space used under each directory (-h for K/M/G, -s for totals only)",
+        run: |_, args, io| du(args, &mut io.output),
+    },
+    Builtin {
+        name: "find",
+        usage: "find [-L] [dir...] [-name <pattern>] [-type f|d|l] [-maxdepth N] [-print0] [--no-ignore]",
+        synopsis: "find [dir]",
+        description: "List paths under a directory (-name GLOB, -type f|d|l, -maxdepth N)",
+        run: |_, args, io| find(args, &mut io.output),
+    },
+    Builtin {
+        name: "summary",
+        usage: "summary [directory]",
+        synopsis: "summary [dir]",
+        description: "Count the files, directories, bytes, lines and words under a directory",
+        run: |_, args, io| summary(args, &mut io.output),
+    },
+    Builtin {
+        name: "pwd",
+        usage: "pwd",
+        synopsis: "pwd",
+        description: "Print working directory",
+        run: |_, _, io| pwd(&mut io.output),
+    },
+    Builtin {
+        name: "complete",
+        usage: "complete [-W word,...] [-f | -d | -G <pattern>] <command>... | -r <command>...",
+        synopsis: "complete <cmd>",
+        description: "Set what Tab offers for a command's arguments (-W words, -d, -G GLOB)",
+        run: |shell, args, io| complete(args, shell, &mut io.output),
+    },
+    Builtin {
+        name: "which",
+        usage: "which <name>...",
+        synopsis: "which <name>",
+        description: "Show what a command name runs: alias, builtin, or program path",
+        run: |shell, args, io| which(args, shell, &mut io.output),
+    },
+    Builtin {
+        name: "clear",
+        usage: "clear",
+        synopsis: "clear",
+        description: "Clear the terminal screen",
+        run: |_, args, io| clear(args, &mut io.output),
+    },
+    Builtin {
+        name: "echo",
+        usage: "echo [-neE] [text...]",
+        synopsis: "echo <text>",
+        description: "Display message",
+        run: |shell, args, io| echo(args, &shell.state, &mut io.output),
+    },
+    Builtin {
+        name: "alias",
+        usage: "alias [name[=value]...]",
+        synopsis: "alias [n=cmd]",
+        description: "Define a command alias (no args lists them)",
+        run: |shell, args, io| alias(args, &mut shell.state, &mut io.output),
+    },
+    Builtin {
+        name: "unalias",
+        usage: "unalias <name>... | unalias -a",
+        synopsis: "unalias <name>",
+        description: "Remove an alias (-a removes all)",
+        run: |shell, args, _| unalias(args, &mut shell.state),
+    },
+    Builtin {
+        name: "printf",
+        usage: "printf <format> [argument...]",
+        synopsis: "printf <fmt>",
+        description: "Print arguments through a format with %s, %d, %b and escapes",
+        run: |_, args, io| printf(args, &mut io.output),
+    },
+    Builtin {
+        name: "date",
+        usage: "date [-u] [-d <relative time>] [--iso-8601 | --rfc-3339 | --rfc-2822 | -f <format> | +<format>]",
+        synopsis: "date",
+        description: "Show current date/time (+FORMAT, -u for UTC, -d \"+1 day\")",
+        run: |shell, args, io| date(args, shell.clock.as_ref(), &mut io.output),
+    },
+    Builtin {
+        name: "cal",
+        usage: "cal [<month> <year> | -y [<year>]]",
+        synopsis: "cal [m y]",
+        description: "Show a month's calendar (-y for the whole year)",
+        run: |shell, args, io| cal(args, shell.clock.as_ref(), &mut io.output),
+    },
+    Builtin {
+        name: "at",
+        usage: "at <+delay> <command> [args...] | at -l | at -r <id>",
+        synopsis: "at <+delay> <cmd>",
+        description: "Run a command once after a delay (-l lists, -r cancels)",
+        run: |shell, args, io| at(args, &mut shell.state, shell.clock.as_ref(), &mut io.output),
+    },
+    Builtin {
+        name: "every",
+        usage: "every <interval> <command> [args...] | every -l | every -r <id>",
+        synopsis: "every <interval> <cmd>",
+        description: "Run a command repeatedly (-l lists, -r stops)",
+        run: |shell, args, io| every(args, &mut shell.state, shell.clock.as_ref(), &mut io.output),
+    },
+    Builtin {
+        name: "notify-send",
+        usage: "notify-send <title> [body...]",
+        synopsis: "notify-send <title> [body]",
+        description: "Show a desktop notification",
+        run: |_, args, io| notify_send(args, &mut io.output),
+    },
+    Builtin {
+        name: "prompt_command",
+        usage: "prompt_command [-c | <command> [args...]]",
+        synopsis: "prompt_command [cmd]",
+        description: "Set a command to run before each prompt (-c clears)",
+        run: |shell, args, io| set_prompt_command(args, &mut shell.state, &mut io.output),
+    },
+    Builtin {
+        name: "chpwd",
+        usage: "chpwd [-c | <command> [args...]]",
+        synopsis: "chpwd [cmd]",
+        description: "Add a command to run after each cd (-c clears, no args lists)",
+        run: |shell, args, io| chpwd(args, &mut shell.state, &mut io.output),
+    },
+    Builtin {
+        name: "jobs",
+        usage: "jobs [-l]",
+        synopsis: "jobs [-l]",
+        description: "List background jobs started with a trailing &",
+        run: |shell, args, io| jobs(args, &mut shell.state, &mut io.output),
+    },
+    Builtin {
+        name: "wait",
+        usage: "wait [job-id...]",
+        synopsis: "wait [id...]",
+        description: "Wait for background jobs to finish and report their status",
+        run: |shell, args, io| wait(args, &mut shell.state, &mut io.output),
+    },
+    Builtin {
+        name: "read",
+        usage: "read [-s] [-t <seconds>] [-p <prompt>] [name...]",
+        synopsis: "read [name...]",
+        description: "Read a line into variables (-p prompt, -s silent, -t timeout)",
+        run: |_, args, io| read(args, io.input.as_deref()),
+    },
+    Builtin {
+        name: "export",
+        usage: "export [NAME=value...]",
+        synopsis: "export [N=val]",
+        description: "Set environment variables (no args lists them)",
+        run: |_, args, io| export(args, &mut io.output),
+    },
+    Builtin {
+        name: "env",
+        usage: "env [NAME]",
+        synopsis: "env [NAME]",
+        description: "Print the environment, or one variable's value",
+        run: |_, args, io| env_builtin(args, &mut io.output),
+    },
+    Builtin {
+        name: "unset",
+        usage: "unset <NAME>...",
+        synopsis: "unset <NAME>",
+        description: "Remove environment variables",
+        run: |_, args, _| unset(args),
+    },
+    Builtin {
+        name: "help",
+        usage: "help [--search <term>]",
+        synopsis: "help",
+        description: "Show this help (--search <term> to find commands)",
+        run: |_, args, io| help(args, &mut io.output),
+    },
+    Builtin {
+        name: "history",
+        usage: "history [-a | --all] [count]",
+        synopsis: "history [n]",
+        description: "Show command history (a count for the last N, -a for the whole file)",
+        run: |shell, args, io| show_history(args, shell.rl.as_ref(), &shell.state, &mut io.output),
+    },
+    Builtin {
+        name: "notify",
+        usage: "notify <path> [--exec <command>]",
+        synopsis: "notify <path>",
+        description: "Print file changes under path (--exec <cmd> to react)",
+        run: |shell, args, io| watch_path(args, shell, io),
+    },
+    Builtin {
+        name: "wc",
+        usage: "wc [-lwcz] [file...]",
+        synopsis: "wc [file]",
+        description: "Count lines, words and bytes (-l, -w, -c to pick, -z for NUL records)",
+        run: |_, args, io| wc(args, io.input.as_deref(), &mut io.output),
+    },
+    Builtin {
+        name: "sort",
+        usage: "sort [-rnz] [file...]",
+        synopsis: "sort [file]",
+        description: "Sort lines (-r to reverse, -n by number)",
+        run: |_, args, io| sort(args, io.input.as_deref(), &mut io.output),
+    },
+    Builtin {
+        name: "uniq",
+        usage: "uniq [-cz] [file...]",
+        synopsis: "uniq [file]",
+        description: "Collapse repeated adjacent lines (-c to count them)",
+        run: |_, args, io| uniq(args, io.input.as_deref(), &mut io.output),
+    },
+    Builtin {
+        name: "nl",
+        usage: "nl [-z] [file...]",
+        synopsis: "nl [file]",
+        description: "Number the non-empty lines",
+        run: |_, args, io| nl(args, io.input.as_deref(), &mut io.output),
+    },
+    Builtin {
+        name: "xargs",
+        usage: "xargs [-0] [command [argument...]]",
+        synopsis: "xargs <cmd>",
+        description: "Run a command with its input as arguments (-0 for NUL-separated input)",
+        run: |shell, args, io| xargs(args, shell, io),
+    },
+    Builtin {
+        name: "head",
+        usage: "head [-n <lines>] [-z] <file>...",
+        synopsis: "head <file>",
+        description: "Show the first lines of a file (-n N, -z for NUL records)",
+        run: |_, args, io| head(args, io.input.as_deref(), &mut io.output),
+    },
+    Builtin {
+        name: "tail",
+        usage: "tail [-n <lines>] [-fz] <file>...",
+        synopsis: "tail <file>",
+        description: "Show the last lines of a file (-n N, -f to follow, -z for NUL records)",
+        run: |_, args, io| tail(args, io.input.as_deref(), &mut io.output),
+    },
+    Builtin {
+        name: "timeout",
+        usage: "timeout [-k <duration>] <duration> <command> [args...]",
+        synopsis: "timeout <dur> <cmd>",
+        description: "Run a command, stopping it after a time limit",
+        run: |shell, args, io| timeout(args, shell, io),
+    },
+    Builtin {
+        name: "nohup",
+        usage: "nohup <command> [args...]",
+        synopsis: "nohup <cmd>",
+        description: "Run a command detached, immune to hangups",
+        run: |_, args, io| nohup(args, &mut io.output),
+    },
+    Builtin {
+        name: "rehash",
+        usage: "rehash",
+        synopsis: "rehash",
+        description: "Rescan ~/.ash/commands for plugin commands",
+        run: |shell, args, _| rehash(args, &mut shell.state),
+    },
+    Builtin {
+        name: "bind",
+        usage: "bind [-l | -p | -r <keyseq> | '\"<keyseq>\": <action-or-\"macro\">']",
+        synopsis: "bind",
+        description: "Show or change key bindings (-l lists actions)",
+        run: |shell, args, io| bind(args, shell, &mut io.output),
+    },
+    Builtin {
+        name: "set",
+        usage: "set [-o | -o <option> | +o <option> | -x | +x]",
+        synopsis: "set -o <opt>",
+        description: "Enable a shell option (set +o to disable, set -o to list)",
+        run: |shell, args, io| set_option(args, shell, &mut io.output),
+    },
+    Builtin {
+        name: "require_version",
+        usage: "require_version <version>",
+        synopsis: "require_version <ver>",
+        description: "Fail unless this ASH is at least the given version",
+        run: |_, args, _| require_version(args, env!("CARGO_PKG_VERSION")),
+    },
+];
+
+// An editor that completes builtin names and paths on Tab
+fn line_editor(config: Config) -> rustyline::Result<LineEditor> {
+    let mut rl = Editor::with_config(config)?;
+    rl.set_helper(Some(ShellHelper::new(BUILTINS.iter().map(|builtin| builtin.name).collect())));
+    Ok(rl)
+}
+
+fn builtin_registry() -> Registry {
+    BUILTINS
+        .iter()
+        .map(|builtin| (builtin.name().to_string(), Rc::new(*builtin) as Rc<dyn Command>))
+        .collect()
+}
+
+// Where a command reads piped input from and writes its output. In a pipeline
+// each stage's output is captured and becomes the next stage's input.
+pub struct Streams {
+    input: Option<Vec<u8>>,
+    output: Sink,
+}
+
+impl Streams {
+    // What the previous pipeline stage wrote, if this isn't the first stage
+    pub fn input(&self) -> Option<&[u8]> {
+        self.input.as_deref()
+    }
+
+    pub fn output(&mut self) -> &mut dyn Write {
+        &mut self.output
+    }
+}
+
+enum Sink {
+    Terminal,
+    Capture(Vec<u8>),
+    // `> file` or `>> file`
+    File(fs::File),
+}
+
+impl Sink {
+    fn is_terminal(&self) -> bool {
+        matches!(self, Sink::Terminal) && io::stdout().is_terminal()
+    }
+}
+
+impl Write for Sink {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Sink::Terminal => io::stdout().write(buf),
+            Sink::Capture(captured) => captured.write(buf),
+            Sink::File(file) => file.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Sink::Terminal => io::stdout().flush(),
+            Sink::Capture(_) => Ok(()),
+            Sink::File(file) => file.flush(),
+        }
+    }
+}
+
+// How a pipeline is joined to the one before it
+#[derive(Clone, Copy)]
+enum Connector {
+    // `;`, or the start of the line: runs regardless
+    Then,
+    And,
+    Or,
+}
+
+// Cuts a line at a `#` that starts a word, so `ls # list files` runs `ls`.
+// A quoted or escaped `#`, or one inside a word as in `a#b`, is kept.
+fn strip_comment(line: &str) -> &str {
+    let mut chars = line.char_indices();
+    let mut word_start = true;
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '#' if word_start => return &line[..i],
+            '\\' => {
+                chars.next();
+            }
+            '\'' | '"' => skip_quoted(c, &mut chars),
+            _ => {}
+        }
+        word_start = c.is_whitespace() || ";|&".contains(c);
+    }
+    line
+}
+
+// Moves `chars` past the closing quote of a section opened by `quote`
+fn skip_quoted(quote: char, chars: &mut std::str::CharIndices) {
+    while let Some((_, c)) = chars.next() {
+        match c {
+            _ if c == quote => break,
+            '\\' if quote == '"' => {
+                chars.next();
+            }
+            _ => {}
+        }
+    }
+}
+
+// Splits a line into pipelines joined by `;`, `&&` and `||`. Each pipeline is
+// checked up front so a syntax error anywhere means nothing runs. Empty
+// commands between semicolons are skipped. A lone `|` or `&` is left for the
+// pipeline parser, and so is anything inside quotes.
+fn parse_command_list(line: &str) -> ShellResult<Vec<(Connector, Vec<String>)>> {
+    let mut pieces = vec![(Connector::Then, String::new())];
+    let mut chars = strip_comment(line).chars().peekable();
+    while let Some(c) = chars.next() {
+        let piece = &mut pieces.last_mut().unwrap().1;
+        match c {
+            // `\;` is literal; `\&`, `\|` and other escapes pass through
+            '\\' => match chars.next() {
+                Some(';') => piece.push(';'),
+                Some(next) => {
+                    piece.push(c);
+                    piece.push(next);
+                }
+                None => piece.push(c),
+            },
+            '\'' | '"' => copy_quoted(c, &mut chars, piece)?,
+            '&' | '|' if chars.peek() == Some(&c) => {
+                chars.next();
+                let connector = if c == '&' { Connector::And } else { Connector::Or };
+                pieces.push((connector, String::new()));
+            }
+            ';' => pieces.push((Connector::Then, String::new())),
+            _ => piece.push(c),
+        }
+    }
+
+    let mut list = Vec::with_capacity(pieces.len());
+    for (i, (connector, text)) in pieces.iter().enumerate() {
+        if text.trim().is_empty() {
+            let next = pieces.get(i + 1).map(|(c, _)| *c);
+            let missing = match (connector, next) {
+                (Connector::And, _) => "command after '&&'",
+                (Connector::Or, _) => "command after '||'",
+                (Connector::Then, Some(Connector::And)) => "command before '&&'",
+                (Connector::Then, Some(Connector::Or)) => "command before '||'",
+                (Connector::Then, _) => continue,
+            };
+            return Err(ShellError::MissingArguments(missing));
+        }
+        list.push((*connector, parse_pipeline(text)?));
+    }
+    Ok(list)
+}
+
+// `a ; b` runs both, `a && b` runs b only if a succeeded and `a || b` only
+// if it failed. A skipped pipeline leaves the previous status in place, so
+// in `a && b || c`, c runs whenever a or b fails.
+fn run_command_list(
+    list: &[(Connector, Vec<String>)],
+    shell: &mut Shell,
+    output: &mut Sink,
+    errors: &mut dyn Write,
+) -> bool {
+    let mut succeeded = true;
+    for (connector, stages) in list {
+        if shell.state.exit_status.is_some() {
+            break;
+        }
+        let run = match connector {
+            Connector::Then => true,
+            Connector::And => succeeded,
+            Connector::Or => !succeeded,
+        };
+        if run {
+            succeeded = run_pipeline(stages, shell, output, errors);
+        }
+    }
+    succeeded
+}
+
+// Splits a command line on `|` into pipeline stages; `\|` and a quoted `|`
+// are literal bars.
+fn parse_pipeline(line: &str) -> ShellResult<Vec<String>> {
+    let mut stages = vec![String::new()];
+    let mut chars = line.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => match chars.next() {
+                Some('|') => stages.last_mut().unwrap().push('|'),
+                Some(other) => {
+                    let stage = stages.last_mut().unwrap();
+                    stage.push('\\');
+                    stage.push(other);
+                }
+                None => stages.last_mut().unwrap().push('\\'),
+            },
+            '\'' | '"' => copy_quoted(c, &mut chars, stages.last_mut().unwrap())?,
+            '|' => stages.push(String::new()),
+            _ => stages.last_mut().unwrap().push(c),
+        }
+    }
+
+    if stages.len() > 1 {
+        if stages[0].trim().is_empty() {
+            return Err(ShellError::MissingArguments("command before '|'"));
+        }
+        if stages[1..].iter().any(|stage| stage.trim().is_empty()) {
+            return Err(ShellError::MissingArguments("command after '|'"));
+        }
+    }
+    Ok(stages)
+}
+
+// A stage's `< file` input and `> file` / `>> file` output
+#[derive(Default)]
+struct Redirects {
+    input: Option<Vec<u8>>,
+    output: Option<fs::File>,
+}
+
+// Removes redirections (`< file`, `> file`, `>> file`, also written without
+// the space) from a command's arguments and opens the files they name. If a
+// kind is given more than once, the last one wins. A quoted `">"` is an
+// ordinary argument.
+fn take_redirects(words: &mut Vec<Word>, state: &ShellState) -> ShellResult<Redirects> {
+    let mut input = None;
+    let mut output = None;
+    // Redirections may come before the command too, as in `> file echo x`
+    let mut i = 0;
+    while i < words.len() {
+        if !words[i].redirect {
+            i += 1;
+            continue;
+        }
+        let word = words.remove(i).text;
+        let (op, inline) = if let Some(rest) = word.strip_prefix(">>") {
+            (">>", rest)
+        } else if let Some(rest) = word.strip_prefix('>') {
+            (">", rest)
+        } else {
+            ("<", &word[1..])
+        };
+        let path = if inline.is_empty() {
+            let missing = if op == "<" { "file after '<'" } else { "file after '>'" };
+            if i == words.len() {
+                return Err(ShellError::MissingArguments(missing));
+            }
+            words.remove(i).text
+        } else {
+            inline.to_string()
+        };
+        match op {
+            "<" => input = Some(path),
+            _ => output = Some((path, op == ">>")),
+        }
+    }
+
+    let mut redirects = Redirects::default();
+    if let Some(path) = input.as_deref() {
+        if !Path::new(path).exists() {
+            return Err(ShellError::FileNotFound(path.to_string()));
+        }
+        let content = fs::read(path)
+            .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path, io_error_reason(&e))))?;
+        redirects.input = Some(content);
+    }
+    if let Some((path, append)) = output {
+        let path = path.as_str();
+        if !append
+            && Path::new(path).is_file()
+            && blocked_by_safe_mode(state, &format!("> truncating '{}'", path))
+        {
+            return Err(ShellError::Reported("redirection"));
+        }
+        let file = fs::OpenOptions::new()
+            .write(true)
+            .create(true)
+            .append(append)
+            .truncate(!append)
+            .open(path)
+            .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path, io_error_reason(&e))))?;
+        redirects.output = Some(file);
+    }
+    Ok(redirects)
+}
+
+// Runs the stages in order, feeding each one's output to the next. A failing
+// stage is reported and passes on whatever it wrote before failing.
+// The last stage writes to `output` unless it is redirected, and errors are
+// reported to `errors`. Returns whether the last stage succeeded: a builtin
+// that returned Ok, or an external program that exited with status 0. With
+// pipefail, the pipeline's status is instead that of the rightmost stage
+// that failed, and 0 only if every stage succeeded.
+fn run_pipeline(
+    stages: &[String],
+    shell: &mut Shell,
+    output: &mut Sink,
+    errors: &mut dyn Write,
+) -> bool {
+    // Every stage sees the status of the command before the pipeline
+    let status = shell.state.last_status;
+    let mut failed = 0;
+    let mut input = None;
+    for (i, stage) in stages.iter().enumerate() {
+        let last = i + 1 == stages.len();
+        let mut io = Streams {
+            input: input.take(),
+            output: if last { std::mem::replace(output, Sink::Terminal) } else { Sink::Capture(Vec::new()) },
+        };
+        let mut redirected = false;
+
+        let (mut words, expanded) = match parse_input(stage, status).and_then(|words| expand_alias(words, &shell.state)) {
+            Ok(words) => (words, Ok(())),
+            Err(e) => (Vec::new(), Err(e)),
+        };
+        let redirects = expanded.and_then(|()| take_redirects(&mut words, &shell.state));
+        let words: Vec<String> = words.into_iter().map(|word| word.text).collect();
+        let (command, mut args) = split_command(&words);
+        // A trailing `&` starts an external program in the background. A
+        // builtin has nothing to background and just runs, as does a pipeline.
+        let background = args.last() == Some(&"&");
+        if background {
+            args.pop();
+        }
+        let background = background && stages.len() == 1 && !shell.commands.contains_key(command);
+        match redirects {
+            Ok(redirects) => {
+                // `< file` takes the place of anything piped in
+                if let Some(content) = redirects.input {
+                    io.input = Some(content);
+                }
+                if let Some(file) = redirects.output {
+                    let replaced = std::mem::replace(&mut io.output, Sink::File(file));
+                    if last {
+                        *output = replaced;
+                    }
+                    redirected = true;
+                }
+                let cwd = env::current_dir().unwrap_or_default();
+                shell.state.last_status = 0;
+                let result = if background {
+                    start_job(command, &args, &mut shell.state, &mut io, errors)
+                } else {
+                    shell.execute(command, &args, &mut io)
+                };
+                let _ = io.output.flush();
+                if let Err(e) = &result {
+                    shell.state.last_status = e.exit_code();
+                }
+                audit_command(&mut shell.state, shell.clock.now(), &cwd, command, &args, &result);
+                if let Err(e) = result {
+                    let _ = report_error(e, command, shell.state.error_format, errors);
+                }
+            }
+            // A stage whose words or redirections can't be set up doesn't
+            // run at all
+            Err(e) => {
+                shell.state.last_status = e.exit_code();
+                let _ = report_error(e, command, shell.state.error_format, errors);
+            }
+        }
+
+        // A stage redirected to a file passes nothing down the pipe
+        input = match io.output {
+            _ if last => {
+                if !redirected {
+                    *output = io.output;
+                }
+                None
+            }
+            Sink::Capture(captured) => Some(captured),
+            _ => Some(Vec::new()),
+        };
+        if shell.state.last_status != 0 {
+            failed = shell.state.last_status;
+        }
+    }
+    if shell.state.pipefail {
+        shell.state.last_status = failed;
+    }
+    shell.state.last_status == 0
+}
+
+impl Shell {
+    fn execute(&mut self, command: &str, args: &[&str], io: &mut Streams) -> ShellResult<()> {
+        if self.state.xtrace && !command.is_empty() {
+            eprintln!("+ {}", std::iter::once(command).chain(args.iter().copied()).collect::<Vec<_>>().join(" "));
+        }
+        if command.is_empty() {
+            return Ok(());
+        }
+        // Cloned out of the registry so the command can borrow the shell
+        match self.commands.get(command).cloned() {
+            Some(builtin) if io.output.is_terminal() && auto_pages(command, args) => {
+                let terminal = std::mem::replace(&mut io.output, Sink::Capture(Vec::new()));
+                let result = builtin.run(self, args, io);
+                if let Sink::Capture(captured) = std::mem::replace(&mut io.output, terminal) {
+                    page(&captured)?;
+                }
+                result
+            }
+            Some(builtin) => builtin.run(self, args, io),
+            None => run_program(command, args, &mut self.state, io),
+        }
+    }
+}
+
+// With ASH_PAGER_AUTO=1, `cat` and `ls -l` output bound for the terminal is
+// collected first and shown through $PAGER when it wouldn't fit on screen
+fn auto_pages(command: &str, args: &[&str]) -> bool {
+    if env::var("ASH_PAGER_AUTO").as_deref() != Ok("1") {
+        return false;
+    }
+    match command {
+        "cat" => true,
+        "ls" => args
+            .iter()
+            .take_while(|arg| **arg != "--")
+            .any(|arg| arg.starts_with('-') && !arg.starts_with("--") && arg.contains('l')),
+        _ => false,
+    }
+}
+
+// Output that fits is printed as is; longer output goes to $PAGER (less by
+// default), falling back to printing it when the pager can't be started
+fn page(output: &[u8]) -> ShellResult<()> {
+    let lines = output.iter().filter(|&&byte| byte == b'\n').count();
+    if lines < layout::terminal_height() {
+        io::stdout().write_all(output)?;
+        return Ok(());
+    }
+    let pager = env::var("PAGER").ok().filter(|pager| !pager.trim().is_empty()).unwrap_or_else(|| "less".to_string());
+    let mut words = pager.split_whitespace();
+    let program = words.next().unwrap_or("less");
+    let child = process::Command::new(program).args(words).stdin(process::Stdio::piped()).spawn();
+    let Ok(mut child) = child else {
+        io::stdout().write_all(output)?;
+        return Ok(());
+    };
+    if let Some(mut stdin) = child.stdin.take() {
+        // The pager quitting early closes the pipe; that isn't an error
+        match stdin.write_all(output) {
+            Err(e) if e.kind() != io::ErrorKind::BrokenPipe => return Err(e.into()),
+            _ => {}
+        }
+    }
+    child.wait()?;
+    Ok(())
+}
+
+// Command implementations
+fn cd(args: &[&str], shell: &mut Shell, out: &mut dyn Write) -> ShellResult<()> {
+    let path = args.first().unwrap_or(&"");
+    let path = match *path {
+        "" => env::var("HOME").map_err(|_| ShellError::InvalidArgument("Home directory not found".into()))?,
+        // `cd -` goes back to the previous directory and, like bash, says where it landed
+        "-" => {
+            let previous = shell.state.previous_dir.clone().ok_or_else(|| ShellError::InvalidArgument("OLDPWD not set".into()))?;
+            change_dir(&previous.to_string_lossy(), shell)?;
+            writeln!(out, "{}", previous.display())?;
+            return Ok(());
+        }
+        path => path.to_string(),
+    };
+    change_dir(&path, shell)
+}
+
+// `pushd dir` saves the current directory and changes to `dir`; with no
+// argument it swaps the current directory with the one on top of the stack
+fn pushd(args: &[&str], shell: &mut Shell, out: &mut dyn Write) -> ShellResult<()> {
+    let cwd = env::current_dir()?;
+    let target = match args {
+        [] => shell
+            .state
+            .dir_stack
+            .pop()
+            .ok_or_else(|| ShellError::InvalidArgument("no other directory".into()))?,
+        [dir] => PathBuf::from(dir),
+        _ => return Err(ShellError::InvalidArgument(args.join(" "))),
+    };
+    if let Err(e) = change_dir(&target.to_string_lossy(), shell) {
+        // Leave the stack as it was when the swap can't happen
+        if args.is_empty() {
+            shell.state.dir_stack.push(target);
+        }
+        return Err(e);
+    }
+    shell.state.dir_stack.push(cwd);
+    dirs(&shell.state, out)
+}
+
+fn popd(shell: &mut Shell, out: &mut dyn Write) -> ShellResult<()> {
+    let target = shell
+        .state
+        .dir_stack
+        .pop()
+        .ok_or_else(|| ShellError::InvalidArgument("directory stack empty".into()))?;
+    if let Err(e) = change_dir(&target.to_string_lossy(), shell) {
+        shell.state.dir_stack.push(target);
+        return Err(e);
+    }
+    dirs(&shell.state, out)
+}
+
+// The current directory, then the stack from the top down, like bash's dirs
+fn dirs(state: &ShellState, out: &mut dyn Write) -> ShellResult<()> {
+    let cwd = env::current_dir()?;
+    let stack: Vec<String> = std::iter::once(&cwd)
+        .chain(state.dir_stack.iter().rev())
+        .map(|dir| dir.display().to_string())
+        .collect();
+    writeln!(out, "{}", stack.join(" "))?;
+    Ok(())
+}
+
+// The directory change behind cd: remembers where we were for `cd -`, then
+// runs the autoenv and chpwd hooks
+fn change_dir(path: &str, shell: &mut Shell) -> ShellResult<()> {
+    let path = path.to_string();
+    let path_buf = PathBuf::from(&path);
+    if !path_buf.exists() {
+        return Err(ShellError::FileNotFound(path));
+    }
+    if !path_buf.is_dir() {
+        return Err(ShellError::NotADirectory(path));
+    }
+    
+    let previous = env::current_dir().ok();
+    env::set_current_dir(&path_buf).map_err(|e| match e.kind() {
+        io::ErrorKind::PermissionDenied => ShellError::PermissionDenied(path.clone()),
+        io::ErrorKind::NotADirectory => ShellError::NotADirectory(path.clone()),
+        io::ErrorKind::NotFound => ShellError::FileNotFound(path.clone()),
+        _ => ShellError::Io(e),
+    })?;
+    if let Some(previous) = previous {
+        env::set_var("OLDPWD", &previous);
+        shell.state.previous_dir = Some(previous);
+    }
+    if shell.state.posix {
+        return Ok(());
+    }
+    if env::var("ASH_AUTOENV").as_deref() == Ok("1") {
+        update_autoenv(&mut shell.state);
+    }
+    run_chpwd_hooks(shell);
+    Ok(())
+}
+
+// Unloads .env files from directories we've left, then offers to load the
+// one in the new directory. Files are only parsed as KEY=VALUE lines, never
+// executed, and each file is confirmed once per session before loading.
+fn update_autoenv(state: &mut ShellState) {
+    let Ok(cwd) = env::current_dir() else {
+        return;
+    };
+
+    while state.autoenv_loaded.last().is_some_and(|loaded| !cwd.starts_with(&loaded.dir)) {
+        if let Some(loaded) = state.autoenv_loaded.pop() {
+            unload_env(loaded);
+        }
+    }
+
+    let env_file = cwd.join(".env");
+    let already_loaded = state.autoenv_loaded.last().is_some_and(|loaded| loaded.dir == cwd);
+    if already_loaded || !env_file.is_file() {
+        return;
+    }
+
+    let vars = match fs::read(&env_file) {
+        Ok(content) => parse_env_file(&String::from_utf8_lossy(&content)),
+        Err(e) => {
+            eprintln!("autoenv: {}: {}", env_file.display(), e);
+            return;
+        }
+    };
+    if vars.is_empty() {
+        return;
+    }
+
+    let trusted = *state.autoenv_trusted.entry(env_file.clone()).or_insert_with(|| {
+        let names: Vec<&str> = vars.iter().map(|(key, _)| key.as_str()).collect();
+        confirm(&format!("autoenv: load {} ({})?", env_file.display(), names.join(", ")))
+    });
+    if !trusted {
+        return;
+    }
+
+    let saved = vars
+        .into_iter()
+        .map(|(key, value)| {
+            let previous = env::var(&key).ok();
+            env::set_var(&key, value);
+            (key, previous)
+        })
+        .collect();
+    state.autoenv_loaded.push(LoadedEnv { dir: cwd, saved });
+}
+
+fn unload_env(loaded: LoadedEnv) {
+    for (key, previous) in loaded.saved.into_iter().rev() {
+        match previous {
+            Some(value) => env::set_var(&key, value),
+            None => env::remove_var(&key),
+        }
+    }
+}
+
+// Reads `KEY=VALUE` lines, allowing an `export ` prefix, `#` comments and
+// values wrapped in matching single or double quotes.
+fn parse_env_file(content: &str) -> Vec<(String, String)> {
+    content
+        .lines()
+        .filter_map(|line| {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                return None;
+            }
+            let line = line.strip_prefix("export ").unwrap_or(line);
+            let (key, value) = line.split_once('=')?;
+            let key = key.trim();
+            if key.is_empty() || !key.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+                return None;
+            }
+            let value = value.trim();
+            let unquoted = ['"', '\'']
+                .iter()
+                .find_map(|q| value.strip_prefix(*q).and_then(|v| v.strip_suffix(*q)))
+                .unwrap_or(value);
+            Some((key.to_string(), unquoted.to_string()))
+        })
+        .collect()
+}
+
+// Shell variables are the process environment, so every variable is already
+// exported to the programs the shell starts
+fn export(args: &[&str], out: &mut dyn Write) -> ShellResult<()> {
+    if args.is_empty() {
+        return print_environment(out);
+    }
+
+    // The value has already been unquoted, so spaces in it (as in
+    // `export IFS=" :"`) are kept as they are
+    for arg in args {
+        match arg.split_once('=') {
+            Some((key, value)) if is_variable_name(key) => env::set_var(key, value),
+            None if is_variable_name(arg) => {}
+            _ => return Err(ShellError::InvalidArgument(format!("not a valid variable name: {}", arg))),
+        }
+    }
+    Ok(())
+}
+
+fn env_builtin(args: &[&str], out: &mut dyn Write) -> ShellResult<()> {
+    match args {
+        [] => print_environment(out),
+        [name] => {
+            let value = env::var_os(name).ok_or_else(|| ShellError::InvalidArgument(format!("{}: not set", name)))?;
+            writeln!(out, "{}", value.to_string_lossy())?;
+            Ok(())
+        }
+        _ => Err(ShellError::InvalidArgument(args.join(" "))),
+    }
+}
+
+// Every environment variable as `KEY=value`, sorted by name
+fn print_environment(out: &mut dyn Write) -> ShellResult<()> {
+    let mut vars: Vec<(String, String)> = env::vars_os()
+        .map(|(key, value)| (key.to_string_lossy().into_owned(), value.to_string_lossy().into_owned()))
+        .collect();
+    vars.sort();
+    for (key, value) in vars {
+        writeln!(out, "{}={}", key, value)?;
+    }
+    Ok(())
+}
+
+fn unset(args: &[&str]) -> ShellResult<()> {
+    if args.is_empty() {
+        return Err(ShellError::MissingArguments("variable name"));
+    }
+    for name in args {
+        if !is_variable_name(name) {
+            return Err(ShellError::InvalidArgument(format!("not a valid variable name: {}", name)));
+        }
+        env::remove_var(name);
+    }
+    Ok(())
+}
+
+// A hook that itself changes directory doesn't re-trigger the hooks.
+fn run_chpwd_hooks(shell: &mut Shell) {
+    if shell.state.running_chpwd {
+        return;
+    }
+    shell.state.running_chpwd = true;
+    for hook in shell.state.chpwd_hooks.clone() {
+        run_hook(&hook, shell);
+    }
+    shell.state.running_chpwd = false;
+}
+
+// `help` lists every builtin; `help --search <term>` only those whose name
+// or description mentions the term, ignoring case
+fn help(args: &[&str], out: &mut dyn Write) -> ShellResult<()> {
+    let term = match args {
+        [] => None,
+        ["--search" | "-s", term] => Some(term.to_lowercase()),
+        ["--search" | "-s"] => return Err(ShellError::MissingArguments("search term")),
+        _ => return Err(ShellError::InvalidArgument(args.join(" "))),
+    };
+    let matches: Vec<&Builtin> = BUILTINS
+        .iter()
+        .filter(|builtin| match &term {
+            Some(term) => {
+                builtin.name().to_lowercase().contains(term) || builtin.description().to_lowercase().contains(term)
+            }
+            None => true,
+        })
+        .collect();
+    if let (Some(term), true) = (&term, matches.is_empty()) {
+        eprintln!("help: no commands match '{}'", term);
+        return Err(ShellError::Reported("help"));
+    }
+
+    if term.is_none() {
+        writeln!(out, "Implemented commands:")?;
+    }
+    // Long descriptions wrap onto lines indented to line up under the first
+    const INDENT: usize = 18;
+    let width = layout::terminal_width().saturating_sub(INDENT).max(20);
+    for builtin in matches {
+        let synopsis = format!("  {:<13} - ", builtin.synopsis);
+        for (i, line) in layout::wrap(builtin.description(), width).iter().enumerate() {
+            let lead = if i == 0 { synopsis.as_str() } else { "" };
+            writeln!(out, "{:<INDENT$}{}", lead, line)?;
+        }
+    }
+    Ok(())
+}
+
+// `terminal` says whether the output goes straight to a terminal, which is
+// when names are colored unless --color says otherwise
+fn ls(args: &[&str], terminal: bool, out: &mut dyn Write) -> ShellResult<()> {
+    const FLAGS: &[Flag] = &[Flag::switch("l", "long"), Flag::switch("a", "all"), Flag::value("", "color")];
+    let parsed = parse_args(args, FLAGS)?;
+    let long = parsed.has("long");
+    let color = match parsed.value("color") {
+        None | Some("auto") => terminal,
+        Some("always") => true,
+        Some("never") => false,
+        Some(other) => {
+            return Err(ShellError::InvalidArgument(format!("invalid --color: {} (expected always, never or auto)", other)))
+        }
+    };
+    let path = parsed.operands.first().unwrap_or(&".");
+    let mut entries: Vec<fs::DirEntry> = fs::read_dir(path)?.collect::<Result<_, _>>()?;
+    entries.sort_by_key(|entry| entry.file_name());
+
+    let mut names = Vec::new();
+    let mut failed = false;
+    for entry in entries {
+        // A name that isn't valid UTF-8 is shown lossily rather than failing the listing
+        let fname = entry.file_name().to_string_lossy().into_owned();
+        // Dotfiles are hidden unless -a is given
+        if fname.starts_with('.') && !parsed.has("all") {
+            continue;
+        }
+        let shown = match ls_color(&entry.path()).filter(|_| color) {
+            Some(code) => format!("\x1b[{}m{}\x1b[0m", code, fname),
+            None => fname.clone(),
+        };
+        if !long {
+            names.push(shown);
+            continue;
+        }
+        // An entry that can't be described (say it vanished since the
+        // directory was read) is reported, and the rest are still listed
+        match long_listing(&entry.path(), &shown) {
+            Ok(line) => writeln!(out, "{}", line)?,
+            Err(e) => {
+                eprintln!("ls: {}: {}", fname, operand_error_reason(&e));
+                failed = true;
+            }
+        }
+    }
+    for row in layout::columns(&names, layout::terminal_width()) {
+        writeln!(out, "{}", row)?;
+    }
+    if failed {
+        return Err(ShellError::Reported("ls"));
+    }
+    Ok(())
+}
+
+// The SGR code `ls` colors an entry's name with, as GNU ls does by default:
+// bold blue for directories, bold cyan for symlinks and bold green for
+// executable files. Other files stay uncolored.
+fn ls_color(path: &Path) -> Option<&'static str> {
+    let metadata = fs::symlink_metadata(path).ok()?;
+    if metadata.file_type().is_symlink() {
+        Some("01;36")
+    } else if metadata.is_dir() {
+        Some("01;34")
+    // Without mode bits every file would count as executable
+    } else if cfg!(unix) && metadata.is_file() && is_executable(&metadata) {
+        Some("01;32")
+    } else {
+        None
+    }
+}
+
+// One `ls -l` line: type and permissions, size in bytes, modification time
+// and name. Symlinks are described themselves rather than their targets.
+fn long_listing(path: &Path, name: &str) -> ShellResult<String> {
+    let metadata = fs::symlink_metadata(path)?;
+    let file_type = metadata.file_type();
+    let kind = if file_type.is_dir() {
+        'd'
+    } else if file_type.is_symlink() {
+        'l'
+    } else {
+        '-'
+    };
+    let modified = DateTime::<Local>::from(metadata.modified()?).format(DATE_FORMAT);
+    let mut line = format!("{}{} {:>10} {} {}", kind, permission_string(&metadata), metadata.len(), modified, name);
+    if file_type.is_symlink() {
+        line.push_str(&format!(" -> {}", fs::read_link(path)?.display()));
+    }
+    Ok(line)
+}
+
+// One block per file, separated by blank lines. Like `ls -l`, a symlink is
+// described itself rather than its target.
+fn stat(args: &[&str], out: &mut dyn Write) -> ShellResult<()> {
+    let operands = parse_args(args, &[])?.operands;
+    if operands.is_empty() {
+        return Err(ShellError::MissingArguments("file"));
+    }
+    for (i, file) in operands.iter().enumerate() {
+        let metadata = fs::symlink_metadata(file).map_err(|e| match e.kind() {
+            io::ErrorKind::NotFound => ShellError::FileNotFound(file.to_string()),
+            _ => e.into(),
+        })?;
+        if i > 0 {
+            writeln!(out)?;
+        }
+        let file_type = metadata.file_type();
+        let kind = if file_type.is_dir() {
+            "directory"
+        } else if file_type.is_symlink() {
+            "symbolic link"
+        } else if file_type.is_file() {
+            "regular file"
+        } else {
+            "special file"
+        };
+        writeln!(out, "    File: {}", file)?;
+        if file_type.is_symlink() {
+            writeln!(out, "  Target: {}", fs::read_link(file)?.display())?;
+        }
+        writeln!(out, "    Type: {}", kind)?;
+        writeln!(out, "    Size: {}", metadata.len())?;
+        write_owner_and_mode(&metadata, out)?;
+        // Not every filesystem records every time, creation least of all
+        let time = |time: io::Result<std::time::SystemTime>| match time {
+            Ok(time) => DateTime::<Local>::from(time).format(DATE_FORMAT).to_string(),
+            Err(_) => "-".to_string(),
+        };
+        writeln!(out, "Modified: {}", time(metadata.modified()))?;
+        writeln!(out, "Accessed: {}", time(metadata.accessed()))?;
+        writeln!(out, " Created: {}", time(metadata.created()))?;
+    }
+    Ok(())
+}
+
+// A symbolic link may point at something that doesn't exist (yet); a hard
+// link needs its target. An existing `link_name` is never replaced.
+fn ln(args: &[&str]) -> ShellResult<()> {
+    let parsed = parse_args(args, &[Flag::switch("s", "symbolic")])?;
+    let [target, link] = parsed.operands[..] else {
+        return Err(ShellError::MissingArguments("target and link name"));
+    };
+    if fs::symlink_metadata(link).is_ok() {
+        return Err(ShellError::InvalidArgument(format!("{}: already exists", link)));
+    }
+    let linked = if parsed.has("symbolic") {
+        symlink(target, link)
+    } else {
+        if fs::symlink_metadata(target).is_err() {
+            return Err(ShellError::FileNotFound(target.to_string()));
+        }
+        fs::hard_link(target, link)
+    };
+    linked.map_err(|e| io::Error::new(e.kind(), format!("{}: {}", link, io_error_reason(&e))).into())
+}
+
+#[cfg(unix)]
+fn symlink(target: impl AsRef<Path>, link: impl AsRef<Path>) -> io::Result<()> {
+    std::os::unix::fs::symlink(target, link)
+}
+
+// Windows has separate kinds of link for files and directories; a target
+// that doesn't exist gets a file link
+#[cfg(windows)]
+fn symlink(target: impl AsRef<Path>, link: impl AsRef<Path>) -> io::Result<()> {
+    // A relative target is relative to the link's directory, not ours
+    let resolved = link.as_ref().parent().unwrap_or(Path::new("")).join(&target);
+    if resolved.is_dir() {
+        std::os::windows::fs::symlink_dir(target, link)
+    } else {
+        std::os::windows::fs::symlink_file(target, link)
+    }
+}
+
+#[cfg(not(any(unix, windows)))]
+fn symlink(_: impl AsRef<Path>, _: impl AsRef<Path>) -> io::Result<()> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "symbolic links are not supported on this platform"))
+}
+
+// Every file that exists gets the mode, even when some don't; those are
+// reported together afterwards
+#[cfg(unix)]
+fn chmod(args: &[&str]) -> ShellResult<()> {
+    use std::os::unix::fs::PermissionsExt;
+    let operands = parse_args(args, &[])?.operands;
+    let (mode, files) = match operands.split_first() {
+        Some((mode, files)) if !files.is_empty() => (mode, files),
+        _ => return Err(ShellError::MissingArguments("mode and file")),
+    };
+    let mode = Some(mode)
+        .filter(|mode| mode.len() <= 4 && mode.chars().all(|c| c.is_digit(8)))
+        .and_then(|mode| u32::from_str_radix(mode, 8).ok())
+        .ok_or_else(|| ShellError::InvalidArgument(format!("invalid mode: {} (expected an octal mode such as 755 or 0644)", mode)))?;
+
+    let mut missing = Vec::new();
+    for file in files {
+        match fs::set_permissions(file, fs::Permissions::from_mode(mode)) {
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::NotFound => missing.push(*file),
+            Err(e) => return Err(io::Error::new(e.kind(), format!("{}: {}", file, io_error_reason(&e))).into()),
+        }
+    }
+    if !missing.is_empty() {
+        return Err(ShellError::FileNotFound(missing.join(", ")));
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn chmod(_: &[&str]) -> ShellResult<()> {
+    Err(ShellError::Unavailable("chmod is not supported on this platform".to_string()))
+}
+
+#[cfg(unix)]
+fn write_owner_and_mode(metadata: &fs::Metadata, out: &mut dyn Write) -> io::Result<()> {
+    use std::os::unix::fs::MetadataExt;
+    writeln!(out, "    Mode: {:04o} ({})", metadata.mode() & 0o7777, permission_string(metadata))?;
+    writeln!(out, "     Uid: {}", metadata.uid())?;
+    writeln!(out, "     Gid: {}", metadata.gid())
+}
+
+#[cfg(not(unix))]
+fn write_owner_and_mode(metadata: &fs::Metadata, out: &mut dyn Write) -> io::Result<()> {
+    let access = if metadata.permissions().readonly() { "read-only" } else { "read-write" };
+    writeln!(out, "  Access: {}", access)
+}
+
+#[cfg(unix)]
+fn permission_string(metadata: &fs::Metadata) -> String {
+    use std::os::unix::fs::PermissionsExt;
+    let mode = metadata.permissions().mode();
+    (0..9)
+        .map(|bit| if mode & (0o400 >> bit) != 0 { b"rwx"[bit % 3] as char } else { '-' })
+        .collect()
+}
+
+// Without Unix mode bits the only thing to show is whether the file is read-only
+#[cfg(not(unix))]
+fn permission_string(metadata: &fs::Metadata) -> String {
+    if metadata.permissions().readonly() { "r-" } else { "rw" }.to_string()
+}
+
+fn cat(args: &[&str], input: Option<&[u8]>, out: &mut dyn Write) -> ShellResult<()> {
+    let parsed = parse_args(args, &[Flag::switch("n", "number")])?;
+    // With -n, numbering carries on from one file to the next
+    let mut numbered = parsed.has("number").then_some(0);
+    let mut write = |mut content: Box<dyn BufRead + '_>, out: &mut dyn Write| match &mut numbered {
+        Some(count) => for_each_line(content, b'\n', |_, line, terminated| {
+            *count += 1;
+            write!(out, "{:>6}\t{}", count, line)?;
+            if terminated {
+                writeln!(out)?;
+            }
+            Ok(())
+        }),
+        // Bytes go out exactly as stored, a buffer at a time, so a huge file
+        // is never held in memory
+        None => io::copy(&mut content, out).map(drop).map_err(ShellError::from),
+    };
+    // With no files, or for `-`, cat passes on piped input, or else what
+    // arrives on the shell's own stdin
+    let files = if parsed.operands.is_empty() { vec!["-"] } else { parsed.operands };
+
+    // A lone file's error is the command's own. Among several, like GNU
+    // cat, a bad file is reported and the rest are still printed.
+    if let [file] = files[..] {
+        return write(open_lines(file, input)?, out);
+    }
+    let mut failed = false;
+    for file in files {
+        match open_lines(file, input) {
+            Ok(content) => write(content, out)?,
+            Err(e) => {
+                eprintln!("cat: {}: {}", file, operand_error_reason(&e));
+                failed = true;
+            }
+        }
+    }
+    if failed {
+        return Err(ShellError::Reported("cat"));
+    }
+    Ok(())
+}
+
+// Why one operand of a multi-file command failed, worded like coreutils
+fn operand_error_reason(e: &ShellError) -> String {
+    match e {
+        ShellError::Io(e) => io_error_reason(e),
+        ShellError::FileNotFound(_) => "No such file or directory".to_string(),
+        ShellError::IsDirectory(_) => "Is a directory".to_string(),
+        e => e.to_string(),
+    }
+}
+
+// The message part of an io::Error, without Rust's "(os error N)" suffix
+fn io_error_reason(e: &io::Error) -> String {
+    match e.kind() {
+        io::ErrorKind::NotFound => "No such file or directory".to_string(),
+        io::ErrorKind::PermissionDenied => "Permission denied".to_string(),
+        _ => e.to_string(),
+    }
+}
+
+// With -p, missing parents are created too and a directory that already
+// exists is fine
+fn mkdir(args: &[&str]) -> ShellResult<()> {
+    let parsed = parse_args(args, &[Flag::switch("p", "parents")])?;
+    if parsed.operands.is_empty() {
+        return Err(ShellError::MissingArguments("directory name"));
+    }
+
+    for &dir in &parsed.operands {
+        let created = if parsed.has("parents") { fs::create_dir_all(dir) } else { fs::create_dir(dir) };
+        match created {
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {
+                return Err(ShellError::InvalidArgument(format!("{}: already exists", dir)));
+            }
+            Err(e) => return Err(io::Error::new(e.kind(), format!("{}: {}", dir, io_error_reason(&e))).into()),
+        }
+    }
+    Ok(())
+}
+
+// An existing file keeps its contents and just gets its access and
+// modification times set to now. A missing one is created empty, unless -c.
+fn touch(args: &[&str]) -> ShellResult<()> {
+    let parsed = parse_args(args, &[Flag::switch("c", "no-create")])?;
+    if parsed.operands.is_empty() {
+        return Err(ShellError::MissingArguments("file name"));
+    }
+
+    for &file in &parsed.operands {
+        let path = Path::new(file);
+        let touched = match fs::metadata(path) {
+            Ok(_) => open_for_times(path).and_then(set_times_to_now),
+            Err(_) if parsed.has("no-create") => continue,
+            Err(_) => fs::File::options().write(true).create_new(true).open(path).map(drop),
+        };
+        touched.map_err(|e| io::Error::new(e.kind(), format!("{}: {}", file, io_error_reason(&e))))?;
+    }
+    Ok(())
+}
+
+// Setting times needs no write access to the contents, so this works for
+// directories and for read-only files we own
+#[cfg(not(windows))]
+fn open_for_times(path: &Path) -> io::Result<fs::File> {
+    fs::File::open(path)
+}
+
+// Windows wants the handle opened for writing attributes, and a directory
+// only opens with backup semantics
+#[cfg(windows)]
+fn open_for_times(path: &Path) -> io::Result<fs::File> {
+    use std::os::windows::fs::OpenOptionsExt;
+    const FILE_WRITE_ATTRIBUTES: u32 = 0x100;
+    const FILE_FLAG_BACKUP_SEMANTICS: u32 = 0x0200_0000;
+    fs::File::options().access_mode(FILE_WRITE_ATTRIBUTES).custom_flags(FILE_FLAG_BACKUP_SEMANTICS).open(path)
+}
+
+fn set_times_to_now(file: fs::File) -> io::Result<()> {
+    let now = std::time::SystemTime::now();
+    file.set_times(fs::FileTimes::new().set_accessed(now).set_modified(now))
+}
+
+// A flag a built-in accepts: any of the `short` letters or `--long`, and
+// whether it takes a value (`-n 5`, `-n5`, `--lines 5`, `--lines=5`)
+struct Flag {
+    short: &'static str,
+    long: &'static str,
+    takes_value: bool,
+    // The value can only be given as `--flag=value`, and may be left off
+    optional_value: bool,
+}
+
+impl Flag {
+    const fn switch(short: &'static str, long: &'static str) -> Self {
+        Flag { short, long, takes_value: false, optional_value: false }
+    }
+
+    const fn value(short: &'static str, long: &'static str) -> Self {
+        Flag { short, long, takes_value: true, optional_value: false }
+    }
+
+    const fn optional(long: &'static str) -> Self {
+        Flag { short: "", long, takes_value: true, optional_value: true }
+    }
+}
+
+// Flags (by long name, in the order given) and operands of a command line
+struct ParsedArgs<'a> {
+    flags: Vec<(&'static str, Option<&'a str>)>,
+    operands: Vec<&'a str>,
+}
+
+impl<'a> ParsedArgs<'a> {
+    fn has(&self, long: &str) -> bool {
+        self.flags.iter().any(|(name, _)| *name == long)
+    }
+
+    // The last value given for a flag, so later flags override earlier ones
+    fn value(&self, long: &str) -> Option<&'a str> {
+        self.values(long).last()
+    }
+
+    // `--max-depth N`, shared by the commands that walk directory trees. Depth
+    // counts levels below the starting directory: 0 is the directory alone,
+    // 1 adds what it directly contains.
+    fn max_depth(&self) -> ShellResult<Option<usize>> {
+        self.value("max-depth")
+            .map(|depth| {
+                depth
+                    .parse()
+                    .map_err(|_| ShellError::InvalidArgument(format!("invalid depth: {}", depth)))
+            })
+            .transpose()
+    }
+
+    fn values<'s>(&'s self, long: &'s str) -> impl Iterator<Item = &'a str> + 's {
+        self.flags.iter().filter(move |(name, _)| *name == long).filter_map(|(_, value)| *value)
+    }
+}
+
+// Separates flags from operands. Short flags can be combined (`-rf`), flags
+// and operands can be interleaved, a lone `-` is an operand, and everything
+// after `--` is an operand, so `rm -- -rf` removes a file named "-rf".
+fn parse_args<'a>(args: &[&'a str], spec: &[Flag]) -> ShellResult<ParsedArgs<'a>> {
+    let mut parsed = ParsedArgs { flags: Vec::new(), operands: Vec::new() };
+    let mut iter = args.iter();
+    while let Some(&arg) = iter.next() {
+        if arg == "--" {
+            parsed.operands.extend(iter.by_ref());
+            break;
+        }
+
+        if let Some(long) = arg.strip_prefix("--") {
+            let (name, inline) = match long.split_once('=') {
+                Some((name, value)) => (name, Some(value)),
+                None => (long, None),
+            };
+            let flag = spec
+                .iter()
+                .find(|flag| flag.long == name)
+                .ok_or_else(|| ShellError::InvalidArgument(format!("unknown option: --{}", name)))?;
+            let value = match (flag.takes_value, inline) {
+                (false, None) => None,
+                (false, Some(_)) => {
+                    return Err(ShellError::InvalidArgument(format!("--{} doesn't take a value", name)));
+                }
+                (true, Some(value)) => Some(value),
+                (true, None) if flag.optional_value => None,
+                (true, None) => Some(*iter.next().ok_or_else(|| missing_flag_value(&format!("--{}", name)))?),
+            };
+            parsed.flags.push((flag.long, value));
+        } else if arg.len() > 1 && arg.starts_with('-') {
+            for (i, c) in arg.char_indices().skip(1) {
+                let flag = spec
+                    .iter()
+                    .find(|flag| flag.short.contains(c))
+                    .ok_or_else(|| ShellError::InvalidArgument(format!("unknown option: -{}", c)))?;
+                if !flag.takes_value {
+                    parsed.flags.push((flag.long, None));
+                    continue;
+                }
+                // The rest of the word is the value, or else the next word is
+                let rest = &arg[i + c.len_utf8()..];
+                let value = if rest.is_empty() {
+                    *iter.next().ok_or_else(|| missing_flag_value(&format!("-{}", c)))?
+                } else {
+                    rest
+                };
+                parsed.flags.push((flag.long, Some(value)));
+                break;
+            }
+        } else {
+            parsed.operands.push(arg);
+        }
+    }
+    Ok(parsed)
+}
+
+fn missing_flag_value(spelled: &str) -> ShellError {
+    ShellError::InvalidArgument(format!("{} requires a value", spelled))
+}
+
+// Asks a yes/no question on the terminal; anything but y/yes means no
+fn confirm(question: &str) -> bool {
+    eprint!("{} [y/N] ", question);
+    let _ = io::stderr().flush();
+    let mut answer = String::new();
+    io::stdin().read_line(&mut answer).is_ok() && matches!(answer.trim(), "y" | "Y" | "yes")
+}
+
+// Reads a line into variables: each name gets one word, and the last gets
+// whatever is left (REPLY when no name is given). Piped input supplies its
+// first line; otherwise the line comes from the terminal, where -p prompts,
+// -s keeps the answer off the screen and -t gives up after a while, leaving
+// the variables untouched.
+fn read(args: &[&str], input: Option<&[u8]>) -> ShellResult<()> {
+    const FLAGS: &[Flag] = &[Flag::value("t", "timeout"), Flag::switch("s", "silent"), Flag::value("p", "prompt")];
+    let parsed = parse_args(args, FLAGS)?;
+    let timeout = parsed
+        .value("timeout")
+        .map(|spec| parse_duration(spec).ok_or_else(|| ShellError::InvalidArgument(format!("invalid timeout: {}", spec))))
+        .transpose()?;
+    let names = if parsed.operands.is_empty() { vec!["REPLY"] } else { parsed.operands.clone() };
+    if let Some(name) = names.iter().find(|name| !is_variable_name(name)) {
+        return Err(ShellError::InvalidArgument(format!("not a valid variable name: {}", name)));
+    }
+
+    let line = match input {
+        Some(input) => {
+            let mut line = String::new();
+            open_lines("-", Some(input))?.read_line(&mut line)?;
+            line
+        }
+        None => {
+            if let Some(prompt) = parsed.value("prompt") {
+                eprint!("{}", prompt);
+                let _ = io::stderr().flush();
+            }
+            let silent = parsed.has("silent").then(hide_input);
+            let line = read_terminal_line(timeout);
+            if silent.is_some() {
+                eprintln!();
+            }
+            line?
+        }
+    };
+    if line.is_empty() {
+        // End of input (or the timeout) with nothing read
+        return Err(ShellError::Reported("read"));
+    }
+
+    let mut rest = line.trim_end_matches(['\n', '\r']).trim_start();
+    for (i, name) in names.iter().enumerate() {
+        let value = if i + 1 == names.len() {
+            rest.trim_end()
+        } else {
+            let end = rest.find(char::is_whitespace).unwrap_or(rest.len());
+            let (word, after) = rest.split_at(end);
+            rest = after.trim_start();
+            word
+        };
+        env::set_var(name, value);
+    }
+    Ok(())
+}
+
+// A line from the terminal, or "" if `timeout` passes before one is entered
+fn read_terminal_line(timeout: Option<Duration>) -> ShellResult<String> {
+    let mut line = String::new();
+    if timeout.is_none_or(input_ready) {
+        io::stdin().read_line(&mut line)?;
+    }
+    Ok(line)
+}
+
+// The terminal hands over a line only once Enter is pressed, so waiting for
+// stdin to become readable is waiting for the whole line
+#[cfg(unix)]
+fn input_ready(timeout: Duration) -> bool {
+    let mut poll = libc::pollfd { fd: libc::STDIN_FILENO, events: libc::POLLIN, revents: 0 };
+    let millis = timeout.as_millis().min(i32::MAX as u128) as i32;
+    unsafe { libc::poll(&mut poll, 1, millis) > 0 }
+}
+
+#[cfg(not(unix))]
+fn input_ready(_timeout: Duration) -> bool {
+    true
+}
+
+// Turns off terminal echo until the returned guard is dropped
+#[cfg(unix)]
+fn hide_input() -> Option<EchoGuard> {
+    let mut saved: libc::termios = unsafe { std::mem::zeroed() };
+    if unsafe { libc::tcgetattr(libc::STDIN_FILENO, &mut saved) } != 0 {
+        return None;
+    }
+    let mut silent = saved;
+    silent.c_lflag &= !libc::ECHO;
+    (unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &silent) } == 0).then_some(EchoGuard(saved))
+}
+
+#[cfg(unix)]
+struct EchoGuard(libc::termios);
+
+#[cfg(unix)]
+impl Drop for EchoGuard {
+    fn drop(&mut self) {
+        unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &self.0) };
+    }
+}
+
+#[cfg(not(unix))]
+fn hide_input() -> Option<()> {
+    None
+}
+
+// Prints the warning that replaces a destructive action in safe mode and
+// returns whether the action should be skipped.
+fn blocked_by_safe_mode(state: &ShellState, action: &str) -> bool {
+    if state.safe_mode {
+        eprintln!("Safe mode: blocked {}", action);
+    }
+    state.safe_mode
+}
+
+fn rm(args: &[&str], state: &ShellState) -> ShellResult<()> {
+    const FLAGS: &[Flag] = &[
+        Flag::switch("rR", "recursive"),
+        Flag::switch("f", "force"),
+        Flag::switch("i", "interactive"),
+        Flag::switch("", "preserve-root"),
+        Flag::switch("", "no-preserve-root"),
+    ];
+    let parsed = parse_args(args, FLAGS)?;
+    let recursive = parsed.has("recursive");
+    let force = parsed.has("force");
+    let interactive = parsed.has("interactive");
+    // --preserve-root is the default; whichever of the pair comes last wins
+    let preserve_root = parsed
+        .flags
+        .iter()
+        .rev()
+        .find_map(|(name, _)| match *name {
+            "preserve-root" => Some(true),
+            "no-preserve-root" => Some(false),
+            _ => None,
+        })
+        .unwrap_or(true);
+    // Flags may come before or after the paths; `rm -r` alone is an error
+    let paths = parsed.operands;
+    if paths.is_empty() {
+        return Err(ShellError::MissingArguments("file or directory"));
+    }
+    if blocked_by_safe_mode(state, &format!("rm {}", args.join(" "))) {
+        return Ok(());
+    }
+
+    for path in paths {
+        let metadata = match fs::symlink_metadata(path) {
+            Ok(metadata) => metadata,
+            Err(_) if force => continue,
+            Err(_) => return Err(ShellError::FileNotFound(path.to_string())),
+        };
+        // -i asks once per operand, so `rm -ri dir` asks about the directory
+        // rather than everything in it
+        if interactive && !confirm(&format!("remove '{}'?", path)) {
+            continue;
+        }
+
+        if metadata.is_dir() {
+            if recursive {
+                // remove_dir_all deletes symlinks themselves and never
+                // follows them out of the tree
+                if preserve_root && is_protected_dir(path) {
+                    eprintln!("rm: WARNING: refusing to recursively remove '{}'", path);
+                    eprintln!("rm: use --no-preserve-root to override this failsafe");
+                    continue;
+                }
+                fs::remove_dir_all(path)?;
+            } else {
+                fs::remove_dir(path)?;
+            }
+        } else {
+            fs::remove_file(path)?;
+        }
+    }
+    Ok(())
+}
+
+// Directories `rm -r` won't delete without --no-preserve-root: the
+// filesystem root, the home directory and the current directory.
+fn is_protected_dir(path: &str) -> bool {
+    let Ok(target) = fs::canonicalize(path) else {
+        return false;
+    };
+    let protected = [Some(PathBuf::from("/")), dirs::home_dir(), env::current_dir().ok()];
+    protected
+        .into_iter()
+        .flatten()
+        .filter_map(|dir| fs::canonicalize(dir).ok())
+        .any(|dir| dir == target)
+}
+
+fn cp(args: &[&str]) -> ShellResult<()> {
+    const FLAGS: &[Flag] = &[
+        Flag::switch("rR", "recursive"),
+        Flag::value("", "max-depth"),
+        Flag::switch("L", "dereference"),
+        Flag::switch("P", "no-dereference"),
+    ];
+    let parsed = parse_args(args, FLAGS)?;
+    let recursive = parsed.has("recursive");
+    let max_depth = parsed.max_depth()?;
+    // With -r, symlinks are copied as links unless -L says to copy what they
+    // point to; whichever of -L and -P comes last wins
+    let follow = parsed
+        .flags
+        .iter()
+        .rev()
+        .find_map(|(name, _)| match *name {
+            "dereference" => Some(true),
+            "no-dereference" => Some(false),
+            _ => None,
+        })
+        .unwrap_or(false);
+    let plan = transfer_plan(&parsed.operands)?;
+
+    // Directories are expanded into the files under them up front, so the
+    // progress bar knows the whole size before anything is copied
+    let mut files = Vec::new();
+    let mut dirs = Vec::new();
+    let mut links = Vec::new();
+    for (src, dest) in plan {
+        let is_link = fs::symlink_metadata(&src).is_ok_and(|metadata| metadata.file_type().is_symlink());
+        if recursive && is_link && !follow {
+            links.push((src, dest));
+            continue;
+        }
+        if !src.is_dir() {
+            files.push((src, dest));
+            continue;
+        }
+        if !recursive {
+            return Err(ShellError::IsDirectory(src.display().to_string()));
+        }
+        if env::current_dir()?.join(&dest).starts_with(src.canonicalize()?) {
+            return Err(ShellError::InvalidArgument(format!(
+                "cannot copy '{}' into itself",
+                src.display()
+            )));
+        }
+        collect_tree(&src, &dest, max_depth, follow, &mut dirs, &mut files, &mut links)?;
+    }
+    for dir in &dirs {
+        fs::create_dir_all(dir)?;
+    }
+    for (link, dest) in &links {
+        if fs::symlink_metadata(dest).is_ok_and(|metadata| !metadata.is_dir()) {
+            fs::remove_file(dest)?;
+        }
+        symlink(fs::read_link(link)?, dest)?;
+    }
+
+    let bar = transfer_bar(&files);
+    for (src, dest) in &files {
+        copy_with_progress(src, dest, bar.as_ref())?;
+    }
+    if let Some(bar) = bar {
+        bar.finish_and_clear();
+    }
+    Ok(())
+}
+
+// The directories, (source, destination) file pairs and symlinks that
+// recreate `src` at `dest`, in name order, going at most `depth` levels down.
+// Unless `follow` is set, symlinks are listed as links to recreate rather
+// than followed.
+fn collect_tree(
+    src: &Path,
+    dest: &Path,
+    depth: Option<usize>,
+    follow: bool,
+    dirs: &mut Vec<PathBuf>,
+    files: &mut Vec<(PathBuf, PathBuf)>,
+    links: &mut Vec<(PathBuf, PathBuf)>,
+) -> ShellResult<()> {
+    for entry in Walk::new(src).follow_links(follow).max_depth(depth) {
+        let entry = entry?;
+        let target = match entry.path.strip_prefix(src) {
+            Ok(rel) if entry.depth > 0 => dest.join(rel),
+            _ => dest.to_path_buf(),
+        };
+        if entry.is_dir {
+            dirs.push(target);
+        } else if entry.is_symlink && !follow {
+            links.push((entry.path, target));
+        } else {
+            files.push((entry.path, target));
+        }
+    }
+    Ok(())
+}
+
+fn mv(args: &[&str], state: &ShellState) -> ShellResult<()> {
+    let plan = transfer_plan(&parse_args(args, &[])?.operands)?;
+
+    let bar = transfer_bar(&plan);
+    for (src, dest) in &plan {
+        if dest.is_file() && blocked_by_safe_mode(state, &format!("mv overwriting '{}'", dest.display())) {
+            continue;
+        }
+        match fs::rename(src, dest) {
+            Ok(()) => {
+                if let Some(bar) = &bar {
+                    bar.inc(fs::metadata(dest).map(|m| m.len()).unwrap_or(0));
+                }
+            }
+            // Renaming across filesystems fails, so fall back to copy + delete
+            Err(e) if e.raw_os_error() == Some(libc::EXDEV) && src.is_file() => {
+                copy_with_progress(src, dest, bar.as_ref())?;
+                fs::remove_file(src)?;
+            }
+            Err(e) => return Err(e.into()),
+        }
+    }
+    if let Some(bar) = bar {
+        bar.finish_and_clear();
+    }
+    Ok(())
+}
+
+// Resolves `src dest` or `src... dir` into (source, destination) pairs
+fn transfer_plan(args: &[&str]) -> ShellResult<Vec<(PathBuf, PathBuf)>> {
+    if args.len() < 2 {
+        return Err(ShellError::MissingArguments("source and destination"));
+    }
+
+    // Several sources, or a trailing slash, mean the destination must be an
+    // existing directory; extra operands are never silently dropped
+    let (sources, dest) = args.split_at(args.len() - 1);
+    let wants_dir = sources.len() > 1 || dest[0].ends_with('/');
+    let dest = Path::new(dest[0]);
+    if wants_dir && !dest.is_dir() {
+        return Err(ShellError::InvalidArgument(format!("target '{}' is not a directory", dest.display())));
+    }
+
+    let mut plan = Vec::new();
+    for src in sources {
+        // Check if source exists
+        let src = Path::new(src);
+        if !src.exists() {
+            return Err(ShellError::FileNotFound(src.display().to_string()));
+        }
+        let target = match src.file_name() {
+            Some(name) if dest.is_dir() => dest.join(name),
+            _ => dest.to_path_buf(),
+        };
+        plan.push((src.to_path_buf(), target));
+    }
+    Ok(plan)
+}
+
+// Transfers smaller than this finish too quickly for a progress bar to help
+const PROGRESS_THRESHOLD: u64 = 64 * 1024 * 1024;
+
+// One bar covering every file in the plan, shown only for large transfers
+// to a terminal
+fn transfer_bar(plan: &[(PathBuf, PathBuf)]) -> Option<indicatif::ProgressBar> {
+    let total: u64 = plan
+        .iter()
+        .filter_map(|(src, _)| fs::metadata(src).ok())
+        .filter(|m| m.is_file())
+        .map(|m| m.len())
+        .sum();
+    if total < PROGRESS_THRESHOLD || !io::stdout().is_terminal() {
+        return None;
+    }
+
+    let style = indicatif::ProgressStyle::with_template(
+        "{msg:20!} [{bar:30}] {bytes}/{total_bytes} {bytes_per_sec} ETA {eta}",
+    )
+    .ok()?
+    .progress_chars("=> ");
+    let bar = indicatif::ProgressBar::with_draw_target(Some(total), indicatif::ProgressDrawTarget::stdout());
+    bar.set_style(style);
+    Some(bar)
+}
+
+// fs::copy in fixed-size chunks so the bar can advance as bytes land
+fn copy_with_progress(src: &Path, dest: &Path, bar: Option<&indicatif::ProgressBar>) -> io::Result<()> {
+    let Some(bar) = bar else {
+        return fs::copy(src, dest).map(|_| ());
+    };
+
+    if let Some(name) = src.file_name() {
+        bar.set_message(name.to_string_lossy().into_owned());
+    }
+    let mut reader = fs::File::open(src)?;
+    let mut writer = fs::File::create(dest)?;
+    let mut buf = vec![0; 1024 * 1024];
+    loop {
+        let n = reader.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        writer.write_all(&buf[..n])?;
+        bar.inc(n as u64);
+    }
+    fs::set_permissions(dest, reader.metadata()?.permissions())
+}
+
+// The line loop shared by the text commands. Lines end at `separator`, which
+// is `\n` except for NUL-delimited records (-z). Each line is handed over with
+// its number, without its ending (`\r\n` counts as one), and whether it had
+// one at all (only the last line of a file can lack it). Text that isn't
+// valid UTF-8 is decoded lossily rather than failing the command. Once whoever
+// is reading the output has gone away, the rest of the input is skipped quietly.
+fn for_each_line(
+    mut source: impl BufRead,
+    separator: u8,
+    mut f: impl FnMut(usize, &str, bool) -> ShellResult<()>,
+) -> ShellResult<()> {
+    let mut buf = Vec::new();
+    for number in 1.. {
+        let Some((line, terminated)) = read_line(&mut source, separator, &mut buf)? else {
+            break;
+        };
+        match f(number, &line, terminated) {
+            Err(ShellError::Io(e)) if e.kind() == io::ErrorKind::BrokenPipe => return Ok(()),
+            result => result?,
+        }
+    }
+    Ok(())
+}
+
+// One step of the line loop, for callers that stop partway through: the
+// next line and whether it was terminated, or `None` at the end
+fn read_line<'b>(
+    source: &mut impl BufRead,
+    separator: u8,
+    buf: &'b mut Vec<u8>,
+) -> io::Result<Option<(std::borrow::Cow<'b, str>, bool)>> {
+    buf.clear();
+    if source.read_until(separator, buf)? == 0 {
+        return Ok(None);
+    }
+    let terminated = buf.last() == Some(&separator);
+    let mut line = &buf[..buf.len() - terminated as usize];
+    if separator == b'\n' {
+        line = line.strip_suffix(b"\r").unwrap_or(line);
+    }
+    Ok(Some((String::from_utf8_lossy(line), terminated)))
+}
+
+// Opens a text command's file operand; `-` names piped input, or the
+// terminal when nothing is piped in
+fn open_lines<'a>(file: &str, input: Option<&'a [u8]>) -> ShellResult<Box<dyn BufRead + 'a>> {
+    if file == "-" {
+        return Ok(match input {
+            Some(input) => Box::new(input),
+            None => Box::new(io::stdin().lock()),
+        });
+    }
+    let path = Path::new(file);
+    if path.is_dir() {
+        return Err(ShellError::IsDirectory(file.to_string()));
+    }
+    match fs::File::open(path) {
+        Ok(file) => Ok(Box::new(io::BufReader::new(file))),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Err(ShellError::FileNotFound(file.to_string())),
+        Err(e) => Err(e.into()),
+    }
+}
+
+// The shell variable holding the pattern of the most recent grep
+const LAST_PATTERN_VAR: &str = "ASH_LAST_PATTERN";
+
+fn grep(args: &[&str], input: Option<&[u8]>, out: &mut dyn Write) -> ShellResult<()> {
+    const FLAGS: &[Flag] = &[
+        Flag::switch("rR", "recursive"),
+        Flag::switch("", "respect-gitignore"),
+        Flag::switch("", "no-ignore"),
+        Flag::value("", "include"),
+        Flag::value("", "name"),
+        Flag::value("", "exclude"),
+        Flag::switch("L", "dereference"),
+        Flag::switch("P", "no-dereference"),
+        Flag::switch("i", "ignore-case"),
+        Flag::switch("v", "invert-match"),
+        Flag::switch("n", "line-number"),
+        Flag::switch("c", "count"),
+        Flag::switch("E", "extended-regexp"),
+        Flag::value("", "binary-files"),
+        Flag::switch("z", "null-data"),
+        Flag::switch("", "last"),
+        Flag::switch("o", "only-matching"),
+        Flag::switch("", "total"),
+        Flag::value("", "max-depth"),
+        Flag::switch("q", "quiet"),
+    ];
+    let parsed = parse_args(args, FLAGS)?;
+    let recursive = parsed.has("recursive");
+    // Symlinks met during a recursive walk are skipped unless -L is given;
+    // -P restores the default, and the last of the two wins
+    let follow_links = parsed.flags.iter().rev().find_map(|(name, _)| match *name {
+        "dereference" => Some(true),
+        "no-dereference" => Some(false),
+        _ => None,
+    });
+    let ignore_mode = parsed.flags.iter().rev().find_map(|(name, _)| match *name {
+        "respect-gitignore" => Some(true),
+        "no-ignore" => Some(false),
+        _ => None,
+    });
+    let filter = GrepFilter {
+        // `--name` is `find -name` spelled for grep; both restrict which files
+        // a recursive search opens, checked as the directory is walked
+        include: parsed.values("include").chain(parsed.values("name")).map(String::from).collect(),
+        exclude: parsed.values("exclude").map(String::from).collect(),
+        follow_links: follow_links.unwrap_or(false),
+        max_depth: parsed.max_depth()?,
+    };
+    // Every pattern is remembered in $ASH_LAST_PATTERN, and `--last` searches
+    // with it again, so all the operands are files
+    let (pattern, files) = if parsed.has("last") {
+        let pattern = env::var(LAST_PATTERN_VAR)
+            .map_err(|_| ShellError::InvalidArgument("no previous grep pattern".to_string()))?;
+        (pattern, &parsed.operands[..])
+    } else {
+        let Some((pattern, files)) = parsed.operands.split_first() else {
+            return Err(ShellError::MissingArguments("pattern and file"));
+        };
+        (pattern.to_string(), files)
+    };
+    env::set_var(LAST_PATTERN_VAR, &pattern);
+    let ignore_case = parsed.has("ignore-case");
+    // -E compiles the pattern once up front; without it the pattern is
+    // matched as a literal substring. -o needs to find each match in a line,
+    // so a literal pattern is escaped into a regex for it.
+    let only_matching = parsed.has("only-matching");
+    let regex = if parsed.has("extended-regexp") || only_matching {
+        let source = if parsed.has("extended-regexp") { pattern.clone() } else { regex::escape(&pattern) };
+        let regex = RegexBuilder::new(&source)
+            .case_insensitive(ignore_case)
+            .build()
+            .map_err(|e| ShellError::InvalidArgument(format!("invalid regex '{}': {}", pattern, e)))?;
+        Some(regex)
+    } else {
+        None
+    };
+    let binary_files = match parsed.value("binary-files") {
+        None | Some("binary") => BinaryFiles::Binary,
+        Some("text") => BinaryFiles::Text,
+        Some("without-match") => BinaryFiles::WithoutMatch,
+        Some(other) => return Err(ShellError::InvalidArgument(format!("invalid --binary-files type: {}", other))),
+    };
+    let matcher = GrepMatcher {
+        regex,
+        binary_files,
+        pattern: if ignore_case { pattern.to_lowercase() } else { pattern },
+        ignore_case,
+        invert: parsed.has("invert-match"),
+        line_numbers: parsed.has("line-number"),
+        count: parsed.has("count"),
+        // Like standard grep, matches are only labelled with their file when
+        // more than one file could be searched
+        with_filename: files.len() > 1 || recursive,
+        separator: if parsed.has("null-data") { b'\0' } else { b'\n' },
+        only_matching,
+        total: parsed.has("total").then(|| Cell::new(0)),
+        quiet: parsed.has("quiet"),
+        matched: Cell::new(false),
+    };
+
+    // Without a file operand, grep searches piped or `<` input; file operands
+    // take precedence over it
+    let grep_operand = |file: &str, out: &mut dyn Write| match file {
+        "-" => grep_lines(&matcher, open_lines(file, input)?, Some(Path::new("(standard input)")), out),
+        _ => grep_path(&matcher, file, recursive, ignore_mode, &filter, out),
+    };
+    match (files, input) {
+        ([], Some(input)) => grep_lines(&matcher, input, None, out)?,
+        ([], None) => return Err(ShellError::MissingArguments("pattern and file")),
+        ([file], _) => grep_operand(file, out)?,
+        // With several files, a bad one is reported and the rest are still
+        // searched; grep only fails if none of them could be
+        (files, _) => {
+            let mut searched = 0;
+            for file in files {
+                if matcher.done() {
+                    break;
+                }
+                match grep_operand(file, out) {
+                    Ok(()) => searched += 1,
+                    Err(e) => eprintln!("grep: {}: {}", file, e),
+                }
+            }
+            if searched == 0 {
+                return Err(ShellError::Reported("grep"));
+            }
+        }
+    }
+    if let Some(total) = &matcher.total {
+        writeln!(out, "{}", total.get())?;
+    }
+    // As in standard grep, finding nothing is a failure (status 1) but not
+    // an error worth a message, with or without -q
+    if !matcher.matched.get() {
+        return Err(ShellError::Reported("grep"));
+    }
+    Ok(())
+}
+
+fn grep_path(
+    matcher: &GrepMatcher,
+    file: &str,
+    recursive: bool,
+    ignore_mode: Option<bool>,
+    filter: &GrepFilter,
+    out: &mut dyn Write,
+) -> ShellResult<()> {
+    let path = Path::new(file);
+    
+    if !path.exists() {
+        return Err(ShellError::FileNotFound(file.to_string()));
+    }
+
+    if path.is_dir() {
+        if !recursive {
+            return Err(ShellError::IsDirectory(file.to_string()));
+        }
+        // .gitignore rules apply by default whenever the search starts inside a git repo
+        let ignores = if ignore_mode.unwrap_or_else(|| find_git_root(path).is_some()) {
+            Some(load_parent_gitignores(path))
+        } else {
+            None
+        };
+        return grep_dir(matcher, path, filter, ignores, out);
+    }
+    
+    grep_file(matcher, path, out)
+}
+
+// What grep looks for, and how it reports what it finds
+struct GrepMatcher {
+    regex: Option<Regex>,
+    binary_files: BinaryFiles,
+    // Already lowercased when matching ignores case
+    pattern: String,
+    ignore_case: bool,
+    invert: bool,
+    line_numbers: bool,
+    // Print only the number of selected lines in each input
+    count: bool,
+    with_filename: bool,
+    // Input and output records end in NUL instead of newline with -z
+    separator: u8,
+    // -o: print (and count) each match rather than each matching line
+    only_matching: bool,
+    // --total: add every file's count here and print only the sum
+    total: Option<Cell<usize>>,
+    // -q: print nothing and stop at the first match
+    quiet: bool,
+    // Whether any line in any file matched, which decides the exit status
+    matched: Cell<bool>,
+}
+
+// What grep does with a file that has a NUL byte near the start
+#[derive(Clone, Copy, PartialEq)]
+enum BinaryFiles {
+    // Only say whether it matches
+    Binary,
+    // Search and print it like any other file
+    Text,
+    // Skip it
+    WithoutMatch,
+}
+
+impl GrepMatcher {
+    // Whether a quiet search has its answer, so nothing more needs reading
+    fn done(&self) -> bool {
+        self.quiet && self.matched.get()
+    }
+
+    fn matches(&self, line: &str) -> bool {
+        let found = if let Some(regex) = &self.regex {
+            regex.is_match(line)
+        } else if self.ignore_case {
+            line.to_lowercase().contains(&self.pattern)
+        } else {
+            line.contains(&self.pattern)
+        };
+        found != self.invert
+    }
+}
+
+struct GrepFilter {
+    include: Vec<String>,
+    exclude: Vec<String>,
+    follow_links: bool,
+    max_depth: Option<usize>,
+}
+
+impl GrepFilter {
+    // Exclude globs are tried against both the entry name and its path relative
+    // to the search root, so `target` and `target/*` both prune a build directory.
+    fn excludes(&self, name: &str, rel: &str, is_dir: bool) -> bool {
+        self.exclude.iter().any(|glob| {
+            glob_match(glob, name)
+                || glob_match(glob, rel)
+                || (is_dir && glob_match(glob, &format!("{}/", rel)))
+        })
+    }
+
+    fn includes(&self, name: &str) -> bool {
+        self.include.is_empty() || self.include.iter().any(|glob| glob_match(glob, name))
+    }
+}
+
+fn grep_dir(
+    matcher: &GrepMatcher,
+    root: &Path,
+    filter: &GrepFilter,
+    ignores: Option<Vec<Gitignore>>,
+    out: &mut dyn Write,
+) -> ShellResult<()> {
+    let walk = Walk::new(root)
+        .follow_links(filter.follow_links)
+        .max_depth(filter.max_depth)
+        .ignores(ignores)
+        .filter_entry(|entry| {
+            let rel = entry.path.strip_prefix(root).unwrap_or(&entry.path).to_string_lossy();
+            !filter.excludes(&entry.name, &rel, entry.is_dir)
+        });
+    for entry in walk {
+        if matcher.done() {
+            break;
+        }
+        let entry = match entry {
+            Ok(entry) => entry,
+            // Walk errors already name the path they are about
+            Err(ShellError::Io(e)) => {
+                eprintln!("grep: {}", e);
+                continue;
+            }
+            Err(e) => return Err(e),
+        };
+        // Like GNU grep -r, symlinks are not followed unless asked to (-L);
+        // a dangling link is skipped either way
+        if entry.is_dir || (entry.is_symlink && !(filter.follow_links && entry.path.exists())) {
+            continue;
+        }
+        if filter.includes(&entry.name) {
+            if let Err(e) = grep_file(matcher, &entry.path, out) {
+                eprintln!("grep: {}: {}", entry.path.display(), e);
+            }
+        }
+    }
+    Ok(())
+}
+
+// Disk usage of each path (the current directory by default): every
+// directory's total comes after those of the directories inside it, as du
+// prints them, ending with the path itself. Only regular files add to the
+// totals; symlinks are skipped unless followed (-L), so nothing is counted
+// twice. Unreadable directories are reported and counted as empty.
+fn du(args: &[&str], out: &mut dyn Write) -> ShellResult<()> {
+    const FLAGS: &[Flag] = &[
+        Flag::switch("s", "summarize"),
+        Flag::switch("h", "human-readable"),
+        Flag::switch("L", "dereference"),
+        Flag::switch("P", "no-dereference"),
+        Flag::value("", "max-depth"),
+        Flag::value("", "exclude"),
+        Flag::optional("sort"),
+    ];
+    let parsed = parse_args(args, FLAGS)?;
+    let follow_links = parsed.flags.iter().rev().find_map(|(name, _)| match *name {
+        "dereference" => Some(true),
+        "no-dereference" => Some(false),
+        _ => None,
+    });
+    let follow_links = follow_links.unwrap_or(false);
+    // Unlike for the other walks, --max-depth only limits which totals are
+    // printed; everything below still counts towards them
+    let max_depth = if parsed.has("summarize") { Some(0) } else { parsed.max_depth()? };
+    let by_name = match parsed.has("sort").then(|| parsed.value("sort")) {
+        None => None,
+        Some(None | Some("size")) => Some(false),
+        Some(Some("name")) => Some(true),
+        Some(Some(other)) => {
+            return Err(ShellError::InvalidArgument(format!("invalid --sort: {} (expected size or name)", other)))
+        }
+    };
+    let excludes: Vec<&str> = parsed.values("exclude").collect();
+    let roots = if parsed.operands.is_empty() { vec!["."] } else { parsed.operands.clone() };
+
+    let mut rows: Vec<(u64, PathBuf)> = Vec::new();
+    let mut failed = false;
+    for root in roots {
+        let root = Path::new(root);
+        if fs::symlink_metadata(root).is_err() {
+            eprintln!("du: {}: No such file or directory", root.display());
+            failed = true;
+            continue;
+        }
+        // The directories being walked, outermost first, with the bytes
+        // found in each so far
+        let mut open: Vec<(PathBuf, u64)> = Vec::new();
+        // An excluded directory isn't read at all, so nothing in it counts
+        let walk = Walk::new(root).follow_links(follow_links).filter_entry(|entry| {
+            let rel = entry.path.strip_prefix(root).unwrap_or(&entry.path).to_string_lossy();
+            !excludes.iter().any(|glob| glob_match(glob, &entry.name) || glob_match(glob, &rel))
+        });
+        for entry in walk {
+            let entry = match entry {
+                Ok(entry) => entry,
+                Err(ShellError::Io(e)) => {
+                    eprintln!("du: {}", e);
+                    failed = true;
+                    continue;
+                }
+                Err(e) => return Err(e),
+            };
+            while open.len() > entry.depth {
+                close_du_dir(&mut open, max_depth, &mut rows);
+            }
+            if entry.is_dir {
+                open.push((entry.path, 0));
+                continue;
+            }
+            if entry.is_symlink && !follow_links {
+                continue;
+            }
+            let metadata = if follow_links { fs::metadata(&entry.path) } else { fs::symlink_metadata(&entry.path) };
+            let size = match metadata {
+                Ok(metadata) => metadata.len(),
+                Err(e) => {
+                    eprintln!("du: {}: {}", entry.path.display(), io_error_reason(&e));
+                    failed = true;
+                    continue;
+                }
+            };
+            match open.last_mut() {
+                Some((_, total)) => *total += size,
+                // A file given by name is reported on its own
+                None => rows.push((size, entry.path)),
+            }
+        }
+        while !open.is_empty() {
+            close_du_dir(&mut open, max_depth, &mut rows);
+        }
+    }
+
+    // Sizes are all known before anything is printed, so --sort can reorder
+    // them; ties stay in walk order
+    match by_name {
+        Some(true) => rows.sort_by(|a, b| a.1.cmp(&b.1)),
+        Some(false) => rows.sort_by_key(|(size, _)| std::cmp::Reverse(*size)),
+        None => {}
+    }
+    for (size, path) in rows {
+        let size = if parsed.has("human-readable") { human_size(size) } else { size.to_string() };
+        writeln!(out, "{}\t{}", size, path.display())?;
+    }
+    if failed {
+        return Err(ShellError::Reported("du"));
+    }
+    Ok(())
+}
+
+// Once the walk moves back out of a directory its total is final: it goes
+// into the directory around it, and into the output if it isn't too deep
+fn close_du_dir(open: &mut Vec<(PathBuf, u64)>, max_depth: Option<usize>, rows: &mut Vec<(u64, PathBuf)>) {
+    let Some((path, size)) = open.pop() else {
+        return;
+    };
+    if let Some((_, parent)) = open.last_mut() {
+        *parent += size;
+    }
+    if max_depth.is_none_or(|max| open.len() <= max) {
+        rows.push((size, path));
+    }
+}
+
+// Sizes as `du -h` prints them: bytes below 1K, then one decimal place of
+// K, M, G or T (powers of 1024)
+fn human_size(bytes: u64) -> String {
+    const UNITS: &[&str] = &["K", "M", "G", "T"];
+    if bytes < 1024 {
+        return format!("{}B", bytes);
+    }
+    let mut size = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while size >= 1024.0 && unit + 1 < UNITS.len() {
+        size /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1}{}", size, UNITS[unit])
+}
+
+// What `find` lists: everything under its starting points, narrowed by the
+// expression that follows them
+struct FindQuery<'a> {
+    roots: Vec<&'a str>,
+    name: Option<&'a str>,
+    kind: Option<char>,
+    follow_links: bool,
+    max_depth: Option<usize>,
+    separator: char,
+    // --respect-gitignore / --no-ignore; by default .gitignore rules apply
+    // inside a git repo, as for grep -r
+    ignore_mode: Option<bool>,
+}
+
+// find's options are whole words with a single dash (`-name`, `-type`), so
+// they don't go through parse_args
+fn parse_find<'a>(args: &[&'a str]) -> ShellResult<FindQuery<'a>> {
+    let mut query = FindQuery { roots: Vec::new(), name: None, kind: None, follow_links: false, max_depth: None, separator: '\n', ignore_mode: None };
+    let mut words = args.iter().copied();
+    while let Some(word) = words.next() {
+        let mut value = |option: &str| {
+            words.next().ok_or_else(|| ShellError::InvalidArgument(format!("{} needs a value", option)))
+        };
+        match word {
+            "-name" => query.name = Some(value(word)?),
+            "-type" => match value(word)? {
+                kind @ ("f" | "d" | "l") => query.kind = kind.chars().next(),
+                kind => return Err(ShellError::InvalidArgument(format!("unknown type: {}", kind))),
+            },
+            "-maxdepth" => {
+                let depth = value(word)?;
+                query.max_depth = Some(
+                    depth.parse().map_err(|_| ShellError::InvalidArgument(format!("invalid depth: {}", depth)))?,
+                );
+            }
+            "-L" => query.follow_links = true,
+            "-print" => query.separator = '\n',
+            "-print0" => query.separator = '\0',
+            "--respect-gitignore" => query.ignore_mode = Some(true),
+            "--no-ignore" => query.ignore_mode = Some(false),
+            _ if word.starts_with('-') && word.len() > 1 => return Err(ShellError::InvalidArgument(word.to_string())),
+            _ => query.roots.push(word),
+        }
+    }
+    if query.roots.is_empty() {
+        query.roots.push(".");
+    }
+    Ok(query)
+}
+
+impl FindQuery<'_> {
+    fn matches(&self, entry: &WalkEntry) -> bool {
+        let kind_matches = match self.kind {
+            Some('d') => entry.is_dir,
+            Some('l') => entry.is_symlink && !self.follow_links,
+            // With -L a symlink counts as whatever it points to
+            Some('f') => match entry.is_symlink {
+                true => self.follow_links && entry.path.is_file(),
+                false => !entry.is_dir,
+            },
+            _ => true,
+        };
+        kind_matches && self.name.is_none_or(|pattern| glob_match(pattern, &entry.name))
+    }
+}
+
+// Lists the paths under each starting point (the current directory by
+// default) in name order. Unreadable directories and symlink loops are
+// reported and skipped, and the walk carries on.
+fn find(args: &[&str], out: &mut dyn Write) -> ShellResult<()> {
+    let query = parse_find(args)?;
+    let mut failed = false;
+    for root in &query.roots {
+        let root = Path::new(root);
+        if fs::symlink_metadata(root).is_err() {
+            eprintln!("find: {}: No such file or directory", root.display());
+            failed = true;
+            continue;
+        }
+        let ignores = query
+            .ignore_mode
+            .unwrap_or_else(|| find_git_root(root).is_some())
+            .then(|| load_parent_gitignores(root));
+        let walk = Walk::new(root).follow_links(query.follow_links).max_depth(query.max_depth).ignores(ignores);
+        for entry in walk {
+            match entry {
+                Ok(entry) if query.matches(&entry) => write!(out, "{}{}", entry.path.display(), query.separator)?,
+                Ok(_) => {}
+                Err(ShellError::Io(e)) => {
+                    eprintln!("find: {}", e);
+                    failed = true;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+    if failed {
+        return Err(ShellError::Reported("find"));
+    }
+    Ok(())
+}
+
+// Totals for everything below a directory, for `summary`
+#[derive(Default)]
+struct TreeStats {
+    files: u64,
+    dirs: u64,
+    bytes: u64,
+    lines: u64,
+    words: u64,
+    binary: u64,
+}
+
+fn summary(args: &[&str], out: &mut dyn Write) -> ShellResult<()> {
+    let root = match args {
+        [] => Path::new("."),
+        [dir] => Path::new(dir),
+        _ => return Err(ShellError::InvalidArgument(args[1..].join(" "))),
+    };
+    if !root.exists() {
+        return Err(ShellError::FileNotFound(root.display().to_string()));
+    }
+    if !root.is_dir() {
+        return Err(ShellError::NotADirectory(root.display().to_string()));
+    }
+
+    let mut stats = TreeStats::default();
+    for entry in Walk::new(root) {
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(ShellError::Io(e)) => {
+                eprintln!("summary: {}", e);
+                continue;
+            }
+            Err(e) => return Err(e),
+        };
+        if entry.depth == 0 || entry.is_symlink {
+            continue;
+        }
+        if entry.is_dir {
+            stats.dirs += 1;
+        } else if let Err(e) = count_file(&entry.path, &mut stats) {
+            eprintln!("summary: {}: {}", entry.path.display(), e);
+        }
+    }
+
+    let rows = [
+        ("Directory", root.display().to_string()),
+        ("Files", stats.files.to_string()),
+        ("Directories", stats.dirs.to_string()),
+        ("Total size", format!("{} ({} bytes)", human_size(stats.bytes), stats.bytes)),
+        ("Lines", stats.lines.to_string()),
+        ("Words", stats.words.to_string()),
+        ("Binary files", format!("{} (not counted in lines or words)", stats.binary)),
+    ];
+    for (label, value) in rows {
+        writeln!(out, "{:<14}{}", format!("{}:", label), value)?;
+    }
+    Ok(())
+}
+
+// Files with a NUL byte in the first read are binary, as grep decides it
+fn count_file(path: &Path, stats: &mut TreeStats) -> ShellResult<()> {
+    let mut reader = io::BufReader::new(fs::File::open(path)?);
+    stats.files += 1;
+    stats.bytes += reader.get_ref().metadata()?.len();
+    if reader.fill_buf()?.contains(&0) {
+        stats.binary += 1;
+        return Ok(());
+    }
+    for_each_line(reader, b'\n', |_, line, _| {
+        stats.lines += 1;
+        stats.words += line.split_whitespace().count() as u64;
+        Ok(())
+    })
+}
+
+fn grep_file(matcher: &GrepMatcher, path: &Path, out: &mut dyn Write) -> ShellResult<()> {
+    let mut reader = io::BufReader::new(fs::File::open(path)?);
+    // Like GNU grep, a NUL byte in the first read marks a binary file, whose
+    // lines would only flood the terminal with garbage. With -z NULs are just
+    // the record separator.
+    let binary = matcher.separator != b'\0' && reader.fill_buf()?.contains(&0);
+    if !binary || matcher.binary_files == BinaryFiles::Text || (matcher.quiet && matcher.binary_files == BinaryFiles::Binary) {
+        return grep_lines(matcher, reader, Some(path), out);
+    }
+    if matcher.binary_files == BinaryFiles::WithoutMatch {
+        return Ok(());
+    }
+    let mut found = false;
+    for_each_line(reader, matcher.separator, |_, line, _| {
+        found = found || matcher.matches(line);
+        Ok(())
+    })?;
+    if found {
+        matcher.matched.set(true);
+        writeln!(out, "Binary file {} matches", path.display())?;
+    }
+    Ok(())
+}
+
+// Matches are printed as `path:line: text` when several files are searched;
+// a single file or piped input prints its matching lines as they are.
+fn grep_lines(matcher: &GrepMatcher, reader: impl BufRead, path: Option<&Path>, out: &mut dyn Write) -> ShellResult<()> {
+    let path = path.filter(|_| matcher.with_filename);
+    if matcher.quiet {
+        let (mut reader, mut buf) = (reader, Vec::new());
+        while let Some((line, _)) = read_line(&mut reader, matcher.separator, &mut buf)? {
+            if matcher.matches(&line) {
+                matcher.matched.set(true);
+                break;
+            }
+        }
+        return Ok(());
+    }
+    let mut count = 0;
+    for_each_line(reader, matcher.separator, |number, line, _| {
+        if !matcher.matches(line) {
+            return Ok(());
+        }
+        matcher.matched.set(true);
+        // An inverted match has no matched text for -o to show
+        let hits: Vec<&str> = match &matcher.regex {
+            Some(regex) if matcher.only_matching => match matcher.invert {
+                true => Vec::new(),
+                false => regex.find_iter(line).map(|hit| hit.as_str()).collect(),
+            },
+            _ => vec![line],
+        };
+        count += hits.len();
+        if matcher.count || matcher.total.is_some() {
+            return Ok(());
+        }
+        for text in hits {
+            match (path, matcher.line_numbers) {
+                (Some(path), true) => write!(out, "{}:{}: {}", path.display(), number, text)?,
+                (Some(path), false) => write!(out, "{}: {}", path.display(), text)?,
+                (None, true) => write!(out, "{}: {}", number, text)?,
+                (None, false) => write!(out, "{}", text)?,
+            }
+            // Selected records keep the separator they were read with
+            out.write_all(&[matcher.separator])?;
+        }
+        Ok(())
+    })?;
+    if let Some(total) = &matcher.total {
+        total.set(total.get() + count);
+        return Ok(());
+    }
+    if matcher.count {
+        match path {
+            Some(path) => writeln!(out, "{}:{}", path.display(), count)?,
+            None => writeln!(out, "{}", count)?,
+        }
+    }
+    Ok(())
+}
+
+// A parsed .gitignore file. Walked paths are made relative to `base` and
+// then joined onto `prefix`, which is only non-empty for .gitignore files
+// found above the directory a search was started from.
+struct Gitignore {
+    base: PathBuf,
+    prefix: PathBuf,
+    rules: Vec<IgnoreRule>,
+}
+
+struct IgnoreRule {
+    glob: String,
+    negated: bool,
+    dir_only: bool,
+    // Patterns containing a `/` match against the path relative to the
+    // .gitignore's directory; others match the bare name at any depth
+    anchored: bool,
+}
+
+impl Gitignore {
+    fn load(dir: &Path) -> Option<Gitignore> {
+        let content = fs::read_to_string(dir.join(".gitignore")).ok()?;
+        let rules = content.lines().filter_map(IgnoreRule::parse).collect();
+        Some(Gitignore { base: dir.to_path_buf(), prefix: PathBuf::new(), rules })
+    }
+
+    // Returns `Some(true)` if the path is ignored, `Some(false)` if a negated
+    // rule re-includes it, and `None` if no rule mentions it. Later rules win.
+    fn matched(&self, path: &Path, is_dir: bool) -> Option<bool> {
+        let rel = self.prefix.join(path.strip_prefix(&self.base).ok()?);
+        let rel = rel.to_string_lossy().replace('\\', "/");
+        let name = path.file_name()?.to_string_lossy();
+
+        self.rules.iter().rev().find_map(|rule| {
+            if rule.dir_only && !is_dir {
+                return None;
+            }
+            let target = if rule.anchored { rel.as_str() } else { &*name };
+            glob_match(&rule.glob, target).then_some(!rule.negated)
+        })
+    }
+}
+
+impl IgnoreRule {
+    fn parse(line: &str) -> Option<IgnoreRule> {
+        let line = line.trim_end();
+        if line.is_empty() || line.starts_with('#') {
+            return None;
+        }
+
+        let (negated, line) = match line.strip_prefix('!') {
+            Some(rest) => (true, rest),
+            None => (false, line.strip_prefix('\\').unwrap_or(line)),
+        };
+        let (dir_only, line) = match line.strip_suffix('/') {
+            Some(rest) => (true, rest),
+            None => (false, line),
+        };
+        let anchored = line.contains('/');
+        let glob = line.strip_prefix('/').unwrap_or(line);
+        if glob.is_empty() {
+            return None;
+        }
+
+        Some(IgnoreRule { glob: glob.to_string(), negated, dir_only, anchored })
+    }
+}
+
+// Deeper .gitignore files take precedence over their parents.
+fn gitignore_stack_matches(stack: &[Gitignore], path: &Path, is_dir: bool) -> bool {
+    stack
+        .iter()
+        .rev()
+        .find_map(|gitignore| gitignore.matched(path, is_dir))
+        .unwrap_or(false)
+}
+
+fn find_git_root(start: &Path) -> Option<PathBuf> {
+    let start = fs::canonicalize(start).ok()?;
+    start.ancestors().find(|dir| dir.join(".git").exists()).map(Path::to_path_buf)
+}
+
+// Collects the .gitignore files between the repository root and `dir`
+// (exclusive), so a search started in a subdirectory still honors them.
+fn load_parent_gitignores(dir: &Path) -> Vec<Gitignore> {
+    let (Some(root), Ok(canonical)) = (find_git_root(dir), fs::canonicalize(dir)) else {
+        return Vec::new();
+    };
+
+    let mut ancestors: Vec<&Path> = canonical.ancestors().skip(1).take_while(|p| p.starts_with(&root)).collect();
+    ancestors.reverse();
+    ancestors
+        .into_iter()
+        .filter_map(|ancestor| {
+            let mut gitignore = Gitignore::load(ancestor)?;
+            // Re-express the rules in terms of the path as typed by the user
+            gitignore.prefix = canonical.strip_prefix(ancestor).ok()?.to_path_buf();
+            gitignore.base = dir.to_path_buf();
+            Some(gitignore)
+        })
+        .collect()
+}
+
+// Shell-style wildcard matching: `*` and `?` stop at `/`, `**` crosses it,
+// and `[...]` matches a character class (`[!...]` or `[^...]` negates).
+fn glob_match(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    glob_match_chars(&pattern, &text, true)
+}
+
+// Wildcard matching for `${var#pattern}` and friends, where the text isn't
+// a path: `*`, `?` and `[...]` match `/` like any other character
+fn pattern_match(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    glob_match_chars(&pattern, &text, false)
+}
+
+// `paths` selects glob_match's rules for `/`; without it `/` is ordinary
+fn glob_match_chars(p: &[char], t: &[char], paths: bool) -> bool {
+    match p.first() {
+        None => t.is_empty(),
+        Some('*') if paths && p.get(1) == Some(&'*') => {
+            let rest = &p[2..];
+            // `**/` may also match zero directories
+            if rest.first() == Some(&'/') && glob_match_chars(&rest[1..], t, paths) {
+                return true;
+            }
+            (0..=t.len()).any(|i| glob_match_chars(rest, &t[i..], paths))
+        }
+        Some('*') => {
+            let rest = &p[1..];
+            for i in 0..=t.len() {
+                if glob_match_chars(rest, &t[i..], paths) {
+                    return true;
+                }
+                if paths && i < t.len() && t[i] == '/' {
+                    break;
+                }
+            }
+            false
+        }
+        Some('?') => !t.is_empty() && (!paths || t[0] != '/') && glob_match_chars(&p[1..], &t[1..], paths),
+        Some('[') => match (t.first(), GlobClass::parse(&p[1..])) {
+            (Some(&c), Some(class)) => {
+                (!paths || c != '/') && class.matches(c) && glob_match_chars(&p[class.len + 1..], &t[1..], paths)
+            }
+            (None, Some(_)) => false,
+            (_, None) => t.first() == Some(&'[') && glob_match_chars(&p[1..], &t[1..], paths),
+        },
+        Some('\\') if p.len() > 1 => t.first() == Some(&p[1]) && glob_match_chars(&p[2..], &t[1..], paths),
+        Some(&c) => t.first() == Some(&c) && glob_match_chars(&p[1..], &t[1..], paths),
+    }
+}
+
+struct GlobClass {
+    ranges: Vec<(char, char)>,
+    negated: bool,
+    // Pattern characters spanned after the opening `[`, including the `]`
+    len: usize,
+}
+
+impl GlobClass {
+    fn parse(p: &[char]) -> Option<GlobClass> {
+        let negated = matches!(p.first(), Some('!') | Some('^'));
+        let start = if negated { 1 } else { 0 };
+
+        let mut ranges = Vec::new();
+        let mut i = start;
+        while i < p.len() {
+            if p[i] == ']' && i > start {
+                return Some(GlobClass { ranges, negated, len: i + 1 });
+            }
+            if i + 2 < p.len() && p[i + 1] == '-' && p[i + 2] != ']' {
+                ranges.push((p[i], p[i + 2]));
+                i += 3;
+            } else {
+                ranges.push((p[i], p[i]));
+                i += 1;
+            }
+        }
+        None
+    }
+
+    fn matches(&self, c: char) -> bool {
+        self.ranges.iter().any(|&(lo, hi)| lo <= c && c <= hi) != self.negated
+    }
+}
+
+fn pwd(out: &mut dyn Write) -> ShellResult<()> {
+    let path = env::current_dir()?;
+    writeln!(out, "{}", path.display())?;
+    Ok(())
+}
+
+fn clear(args: &[&str], out: &mut dyn Write) -> ShellResult<()> {
+    if !args.is_empty() {
+        return Err(ShellError::InvalidArgument(args.join(" ")));
+    }
+    clear_screen(out)
+}
+
+// Erase the screen, then move the cursor to the top left
+#[cfg(not(windows))]
+fn clear_screen(out: &mut dyn Write) -> ShellResult<()> {
+    write!(out, "\x1B[2J\x1B[1;1H")?;
+    out.flush()?;
+    Ok(())
+}
+
+// Older Windows consoles don't understand the escape codes, so leave it to cls
+#[cfg(windows)]
+fn clear_screen(_out: &mut dyn Write) -> ShellResult<()> {
+    process::Command::new("cmd").args(["/C", "cls"]).status()?;
+    Ok(())
+}
+
+// echo comes in two styles, chosen with ASH_ECHO_STYLE (`gnu` or `bsd`):
+//
+// - gnu (the default): leading -n, -e and -E flags are processed. -n drops
+//   the trailing newline, and backslash escapes are only interpreted with -e.
+// - bsd, also used in POSIX mode: no flags at all, so `echo -n` prints "-n",
+//   and escapes are always interpreted, as POSIX/XSI echo does.
+//
+// In either style `\c` stops output there, including the trailing newline.
+fn echo(args: &[&str], state: &ShellState, out: &mut dyn Write) -> ShellResult<()> {
+    let bsd = state.posix || env::var("ASH_ECHO_STYLE").as_deref() == Ok("bsd");
+    let mut newline = true;
+    let mut escapes = bsd;
+    let mut words = args;
+    if !bsd {
+        while let Some((flag, rest)) = words.split_first() {
+            let valid = flag.len() > 1 && flag.starts_with('-') && flag[1..].chars().all(|c| "neE".contains(c));
+            if !valid {
+                break;
+            }
+            for c in flag[1..].chars() {
+                match c {
+                    'n' => newline = false,
+                    'e' => escapes = true,
+                    _ => escapes = false,
+                }
+            }
+            words = rest;
+        }
+    }
+
+    let mut text = words.join(" ");
+    if escapes {
+        let (expanded, stop) = escape::expand(&text, escape::Octal::Echo);
+        text = expanded;
+        newline &= !stop;
+    }
+    if newline {
+        text.push('\n');
+    }
+    write!(out, "{}", text)?;
+    out.flush()?;
+    Ok(())
+}
+
+// Fills in FORMAT's `%s`, `%b`, `%c`, `%d`/`%i` and `%%` directives (with an
+// optional `-` or `0` flag and a width) from the arguments in turn, and
+// expands its backslash escapes. As in other shells, the format is reused
+// until every argument has been consumed, and a missing argument counts as
+// empty (or 0).
+fn printf(args: &[&str], out: &mut dyn Write) -> ShellResult<()> {
+    let Some((format, mut args)) = args.split_first() else {
+        return Err(ShellError::MissingArguments("format"));
+    };
+    let mut text = String::new();
+    loop {
+        let before = args.len();
+        let mut rest = *format;
+        while !rest.is_empty() {
+            let literal_len = rest.find('%').unwrap_or(rest.len());
+            let (literal, stop) = escape::expand(&rest[..literal_len], escape::Octal::Printf);
+            text.push_str(&literal);
+            if stop {
+                return finish_printf(&text, out);
+            }
+            rest = &rest[literal_len..];
+            let Some(directive) = rest.strip_prefix('%') else {
+                break;
+            };
+
+            let spec_len = directive.find(|c: char| !"-0123456789".contains(c)).unwrap_or(directive.len());
+            let (spec, after) = directive.split_at(spec_len);
+            let mut conversion = after.chars();
+            let kind = conversion.next().ok_or_else(|| ShellError::InvalidArgument("missing format character".to_string()))?;
+            rest = conversion.as_str();
+            if kind == '%' {
+                text.push('%');
+                continue;
+            }
+            let arg = args.first().copied().unwrap_or("");
+            args = args.get(1..).unwrap_or(&[]);
+            let value = match kind {
+                's' => arg.to_string(),
+                'c' => arg.chars().take(1).collect(),
+                'b' => {
+                    let (expanded, stop) = escape::expand(arg, escape::Octal::Echo);
+                    if stop {
+                        text.push_str(&pad(&expanded, spec));
+                        return finish_printf(&text, out);
+                    }
+                    expanded
+                }
+                'd' | 'i' => match arg {
+                    "" => "0".to_string(),
+                    _ => arg
+                        .parse::<i64>()
+                        .map_err(|_| ShellError::InvalidArgument(format!("invalid number: {}", arg)))?
+                        .to_string(),
+                },
+                other => return Err(ShellError::InvalidArgument(format!("unsupported format: %{}", other))),
+            };
+            text.push_str(&pad(&value, spec));
+        }
+        if args.is_empty() || args.len() == before {
+            break;
+        }
+    }
+    finish_printf(&text, out)
+}
+
+// Pads to the width in a directive's flags: on the left by default, on the
+// right with `-`, and with zeros for a number given `0`
+fn pad(value: &str, spec: &str) -> String {
+    let width: usize = spec.trim_start_matches(['-', '0']).parse().unwrap_or(0);
+    let len = value.chars().count();
+    if len >= width {
+        return value.to_string();
+    }
+    let fill = width - len;
+    if spec.starts_with('-') {
+        format!("{}{}", value, " ".repeat(fill))
+    } else if spec.starts_with('0') && value.parse::<i64>().is_ok() {
+        match value.strip_prefix('-') {
+            Some(digits) => format!("-{}{}", "0".repeat(fill), digits),
+            None => format!("{}{}", "0".repeat(fill), value),
+        }
+    } else {
+        format!("{}{}", " ".repeat(fill), value)
+    }
+}
+
+fn finish_printf(text: &str, out: &mut dyn Write) -> ShellResult<()> {
+    write!(out, "{}", text)?;
+    out.flush()?;
+    Ok(())
+}
+
+// How `date` prints the time when no +FORMAT is given
+const DATE_FORMAT: &str = "%Y-%m-%d %H:%M:%S";
+
+// Machine-readable timestamp formats for `date`, as GNU date prints them
+const DATE_MODES: &[(&str, &str)] = &[
+    ("iso-8601", "%Y-%m-%dT%H:%M:%S%:z"),
+    ("rfc-3339", "%Y-%m-%d %H:%M:%S%:z"),
+    ("rfc-2822", "%a, %d %b %Y %H:%M:%S %z"),
+];
+
+fn date(args: &[&str], clock: &dyn Clock, out: &mut dyn Write) -> ShellResult<()> {
+    const FLAGS: &[Flag] = &[
+        Flag::switch("u", "utc"),
+        Flag::value("d", "date"),
+        Flag::value("f", "format"),
+        Flag::switch("", "iso-8601"),
+        Flag::switch("", "rfc-3339"),
+        Flag::switch("", "rfc-2822"),
+    ];
+    let parsed = parse_args(args, FLAGS)?;
+    let utc = parsed.has("utc");
+    let offset = match parsed.value("date") {
+        Some(spec) => parse_relative_time(spec)
+            .ok_or_else(|| ShellError::InvalidArgument(format!("invalid date: {}", spec)))?,
+        None => TimeDelta::zero(),
+    };
+
+    // The last format option wins, and a `+FORMAT` operand overrides them all
+    let mut format = DATE_FORMAT;
+    for (flag, value) in &parsed.flags {
+        match (*flag, value) {
+            ("format", Some(fmt)) => format = fmt,
+            (mode, _) => {
+                if let Some((_, fmt)) = DATE_MODES.iter().find(|(name, _)| *name == mode) {
+                    format = fmt;
+                }
+            }
+        }
+    }
+    for arg in parsed.operands {
+        match arg.strip_prefix('+') {
+            Some(fmt) => format = fmt,
+            None => return Err(ShellError::InvalidArgument(arg.to_string())),
+        }
+    }
+
+    let now = clock.now() + offset;
+    let output = if utc {
+        format_time(&now.with_timezone(&Utc), format)?
+    } else {
+        format_time(&now, format)?
+    };
+    writeln!(out, "{}", output)?;
+    Ok(())
+}
+
+fn at(args: &[&str], state: &mut ShellState, clock: &dyn Clock, out: &mut dyn Write) -> ShellResult<()> {
+    match args {
+        [] => Err(ShellError::MissingArguments("delay and command")),
+        ["-l"] => list_scheduled(state, false, out),
+        ["-r"] => Err(ShellError::MissingArguments("job id")),
+        ["-r", id] => cancel_scheduled(state, id),
+        [_] => Err(ShellError::MissingArguments("command")),
+        [delay, command @ ..] => {
+            let wait = parse_duration(delay.strip_prefix('+').unwrap_or(delay))
+                .ok_or_else(|| ShellError::InvalidArgument(format!("invalid delay: {}", delay)))?;
+            schedule(state, clock, wait, None, command.join(" "), out)
+        }
+    }
+}
+
+fn every(args: &[&str], state: &mut ShellState, clock: &dyn Clock, out: &mut dyn Write) -> ShellResult<()> {
+    match args {
+        [] => Err(ShellError::MissingArguments("interval and command")),
+        ["-l"] => list_scheduled(state, true, out),
+        ["-r"] => Err(ShellError::MissingArguments("timer id")),
+        ["-r", id] => cancel_scheduled(state, id),
+        [_] => Err(ShellError::MissingArguments("command")),
+        [interval, command @ ..] => {
+            let interval = parse_duration(interval)
+                .filter(|interval| !interval.is_zero())
+                .ok_or_else(|| ShellError::InvalidArgument(format!("invalid interval: {}", interval)))?;
+            schedule(state, clock, interval, Some(interval), command.join(" "), out)
+        }
+    }
+}
+
+fn list_scheduled(state: &ShellState, repeating: bool, out: &mut dyn Write) -> ShellResult<()> {
+    let jobs = state.scheduled.lock().expect("scheduler lock poisoned");
+    for job in jobs.iter().filter(|job| job.interval.is_some() == repeating) {
+        match job.interval {
+            Some(interval) => writeln!(out, "{}\tevery {:?}\t{}", job.id, interval, job.command)?,
+            None => writeln!(out, "{}\t{}\t{}", job.id, job.due.format("%Y-%m-%d %H:%M:%S"), job.command)?,
+        }
+    }
+    Ok(())
+}
+
+fn cancel_scheduled(state: &ShellState, id: &str) -> ShellResult<()> {
+    let id: usize = id.parse().map_err(|_| ShellError::InvalidArgument(format!("invalid job id: {}", id)))?;
+    let mut jobs = state.scheduled.lock().expect("scheduler lock poisoned");
+    let index = jobs
+        .iter()
+        .position(|job| job.id == id)
+        .ok_or_else(|| ShellError::InvalidArgument(format!("no such job: {}", id)))?;
+    jobs.remove(index).cancelled.store(true, Ordering::SeqCst);
+    Ok(())
+}
+
+// Starts a timer thread that runs `command` after `wait`, and then again every
+// `interval` until cancelled when one is given.
+fn schedule(
+    state: &mut ShellState,
+    clock: &dyn Clock,
+    wait: Duration,
+    interval: Option<Duration>,
+    command: String,
+    out: &mut dyn Write,
+) -> ShellResult<()> {
+    let id = state.next_scheduled_id;
+    state.next_scheduled_id += 1;
+    let due = clock.now() + TimeDelta::from_std(wait).unwrap_or(TimeDelta::zero());
+    let cancelled = Arc::new(AtomicBool::new(false));
+    state.scheduled.lock().expect("scheduler lock poisoned").push(ScheduledJob {
+        id,
+        command: command.clone(),
+        due,
+        interval,
+        cancelled: Arc::clone(&cancelled),
+    });
+
+    let scheduled = Arc::clone(&state.scheduled);
+    thread::spawn(move || {
+        let mut deadline = Instant::now() + wait;
+        loop {
+            while Instant::now() < deadline {
+                if cancelled.load(Ordering::SeqCst) {
+                    return;
+                }
+                thread::sleep(Duration::from_millis(100).min(deadline.saturating_duration_since(Instant::now())));
+            }
+            if cancelled.load(Ordering::SeqCst) {
+                return;
+            }
+
+            let Some(interval) = interval else {
+                scheduled.lock().expect("scheduler lock poisoned").retain(|job| job.id != id);
+                run_detached(&command);
+                return;
+            };
+            run_detached(&command);
+            deadline += interval;
+        }
+    });
+
+    match interval {
+        Some(interval) => writeln!(out, "timer {} every {:?}", id, interval)?,
+        None => writeln!(out, "job {} at {}", id, due.format("%Y-%m-%d %H:%M:%S"))?,
+    }
+    Ok(())
+}
+
+fn notify_send(args: &[&str], out: &mut dyn Write) -> ShellResult<()> {
+    let (title, body) = args.split_first().ok_or(ShellError::MissingArguments("title"))?;
+    let body = body.join(" ");
+    // Don't lose the message just because no daemon is listening
+    if send_notification(title, &body).is_err() {
+        print_notification(title, &body, out)?;
+    }
+    Ok(())
+}
+
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+fn send_notification(title: &str, body: &str) -> ShellResult<()> {
+    notify_rust::Notification::new()
+        .appname("ash")
+        .summary(title)
+        .body(body)
+        .show()
+        .map(|_| ())
+        .map_err(|e| ShellError::Unavailable(format!("could not reach the notification daemon: {}", e)))
+}
+
+// Platforms without a notification backend fall back to printing the message
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+fn send_notification(_title: &str, _body: &str) -> ShellResult<()> {
+    Err(ShellError::Unavailable("no notification backend on this platform".to_string()))
+}
+
+fn print_notification(title: &str, body: &str, out: &mut dyn Write) -> ShellResult<()> {
+    if body.is_empty() {
+        writeln!(out, "{}", title)?;
+    } else {
+        writeln!(out, "{}: {}", title, body)?;
+    }
+    Ok(())
+}
+
+// Runs a command line off the main thread. Like at(1), the command gets its
+// own fresh shell context rather than sharing the interactive session's.
+fn run_detached(line: &str) {
+    let mut shell = Shell::without_editor();
+    run_hook(line, &mut shell);
+}
+
+fn cal(args: &[&str], clock: &dyn Clock, out: &mut Sink) -> ShellResult<()> {
+    let today = clock.now().date_naive();
+    let parse = |value: &str, what: &str| {
+        value.parse::<i32>().map_err(|_| ShellError::InvalidArgument(format!("invalid {}: {}", what, value)))
+    };
+
+    let (year, month) = match args {
+        [] => (today.year(), Some(today.month())),
+        ["-y"] => (today.year(), None),
+        ["-y", year] => (parse(year, "year")?, None),
+        [month, year] => {
+            let month = parse(month, "month")?;
+            if !(1..=12).contains(&month) {
+                return Err(ShellError::InvalidArgument(format!("invalid month: {}", month)));
+            }
+            (parse(year, "year")?, Some(month as u32))
+        }
+        _ => return Err(ShellError::InvalidArgument(args.join(" "))),
+    };
+    if !(1..=9999).contains(&year) {
+        return Err(ShellError::InvalidArgument(format!("invalid year: {}", year)));
+    }
+
+    // Only highlight today when writing to a terminal
+    let on_terminal = out.is_terminal();
+    let highlight = |m: u32| {
+        (on_terminal && year == today.year() && m == today.month()).then(|| today.day())
+    };
+
+    match month {
+        Some(month) => {
+            for line in month_calendar(year, month, true, highlight(month)) {
+                writeln!(out, "{}", line.trim_end())?;
+            }
+        }
+        None => {
+            writeln!(out, "{}", format!("{:^64}", year).trim_end())?;
+            writeln!(out)?;
+            for quarter in 0..4 {
+                let months: Vec<Vec<String>> = (1..=3)
+                    .map(|i| quarter * 3 + i)
+                    .map(|m| month_calendar(year, m, false, highlight(m)))
+                    .collect();
+                for row in 0..months[0].len() {
+                    let line: Vec<&str> = months.iter().map(|lines| lines[row].as_str()).collect();
+                    writeln!(out, "{}", line.join("  ").trim_end())?;
+                }
+                writeln!(out)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+// Lays out one month as eight 20-column lines: title, weekday header and six
+// week rows (blank-padded so months can be placed side by side).
+fn month_calendar(year: i32, month: u32, show_year: bool, highlight: Option<u32>) -> Vec<String> {
+    let first = NaiveDate::from_ymd_opt(year, month, 1).expect("validated month and year");
+    let next = if month == 12 {
+        NaiveDate::from_ymd_opt(year + 1, 1, 1)
+    } else {
+        NaiveDate::from_ymd_opt(year, month + 1, 1)
+    };
+    let days = next.map_or(31, |next| next.signed_duration_since(first).num_days() as u32);
+
+    let title = if show_year { first.format("%B %Y").to_string() } else { first.format("%B").to_string() };
+    let mut lines = vec![format!("{:^20}", title), "Su Mo Tu We Th Fr Sa".to_string()];
+
+    let mut cells: Vec<String> = vec!["  ".to_string(); first.weekday().num_days_from_sunday() as usize];
+    for day in 1..=days {
+        if highlight == Some(day) {
+            cells.push(format!("\x1B[7m{:>2}\x1B[0m", day));
+        } else {
+            cells.push(format!("{:>2}", day));
+        }
+    }
+    for week in 0..6 {
+        let row: Vec<&str> = cells.iter().skip(week * 7).take(7).map(String::as_str).collect();
+        // Pad by visible width, since a highlighted day carries escape codes
+        let visible = (row.len() * 3).saturating_sub(1);
+        lines.push(format!("{}{}", row.join(" "), " ".repeat(20 - visible)));
+    }
+    lines
+}
+
+// Accepts `now`, `today`, `tomorrow`, `yesterday`, or one or more signed
+// offsets such as `+1 day`, `-3 hours`, `2w 4d` and `90 minutes ago`.
+fn parse_relative_time(spec: &str) -> Option<TimeDelta> {
+    let spec = spec.trim();
+    match spec {
+        "now" | "today" => return Some(TimeDelta::zero()),
+        "tomorrow" => return Some(TimeDelta::days(1)),
+        "yesterday" => return Some(TimeDelta::days(-1)),
+        _ => {}
+    }
+    let (spec, ago) = match spec.strip_suffix("ago") {
+        Some(rest) => (rest.trim_end(), true),
+        None => (spec, false),
+    };
+
+    let mut total = TimeDelta::zero();
+    let mut rest = spec;
+    while !rest.is_empty() {
+        let sign_len = usize::from(rest.starts_with(['+', '-']));
+        let number_len = rest[sign_len..].find(|c: char| !c.is_ascii_digit()).unwrap_or(rest.len() - sign_len);
+        if number_len == 0 {
+            return None;
+        }
+        let amount: i64 = rest[..sign_len + number_len].parse().ok()?;
+        rest = rest[sign_len + number_len..].trim_start();
+
+        let unit_len = rest.find(|c: char| !c.is_ascii_alphabetic()).unwrap_or(rest.len());
+        let delta = match &rest[..unit_len] {
+            "s" | "sec" | "secs" | "second" | "seconds" => TimeDelta::try_seconds(amount),
+            "m" | "min" | "mins" | "minute" | "minutes" => TimeDelta::try_minutes(amount),
+            "h" | "hour" | "hours" => TimeDelta::try_hours(amount),
+            "d" | "day" | "days" => TimeDelta::try_days(amount),
+            "w" | "week" | "weeks" => TimeDelta::try_weeks(amount),
+            _ => None,
+        }?;
+        total = total.checked_add(&delta)?;
+        rest = rest[unit_len..].trim_start();
+    }
+
+    if spec.is_empty() {
+        None
+    } else if ago {
+        Some(-total)
+    } else {
+        Some(total)
+    }
+}
+
+// Lines (or with -z, NUL-separated records), words and bytes, as wc counts
+// them: a last line without a newline isn't counted as a line, and a `\r`
+// before the newline counts as a byte. Read a line at a time so a big file is
+// never held in memory.
+fn wc_counts(mut content: impl BufRead, separator: u8) -> ShellResult<[usize; 3]> {
+    let mut counts = [0; 3];
+    let mut line = Vec::new();
+    // A NUL isn't whitespace, so with -z a word can run on into the next record
+    let mut in_word = false;
+    loop {
+        line.clear();
+        if content.read_until(separator, &mut line)? == 0 {
+            return Ok(counts);
+        }
+        counts[0] += (line.last() == Some(&separator)) as usize;
+        for byte in &line {
+            let starts_word = !in_word && !byte.is_ascii_whitespace();
+            counts[1] += starts_word as usize;
+            in_word = !byte.is_ascii_whitespace();
+        }
+        counts[2] += line.len();
+    }
+
+}
+
+fn wc(args: &[&str], input: Option<&[u8]>, out: &mut dyn Write) -> ShellResult<()> {
+    const FLAGS: &[Flag] = &[
+        Flag::switch("l", "lines"),
+        Flag::switch("w", "words"),
+        Flag::switch("c", "bytes"),
+        Flag::switch("z", "zero-terminated"),
+    ];
+    let parsed = parse_args(args, FLAGS)?;
+    let separator = record_separator(&parsed);
+    // Counts are always printed in this order, whatever order the flags came in
+    let mut shown = [parsed.has("lines"), parsed.has("words"), parsed.has("bytes")];
+    if !shown.contains(&true) {
+        shown = [true; 3];
+    }
+
+    let mut rows: Vec<([usize; 3], Option<&str>)> = Vec::new();
+    let mut failed = false;
+    // Piped input on its own is counted without a name
+    let named = !parsed.operands.is_empty();
+    for file in input_files(&parsed.operands, input)? {
+        match open_lines(file, input).and_then(|content| wc_counts(content, separator)) {
+            Ok(counts) => rows.push((counts, named.then_some(file))),
+
+            Err(e) => {
+                eprintln!("wc: {}: {}", file, operand_error_reason(&e));
+                failed = true;
+            }
+        }
+    }
+    if rows.len() > 1 {
+        let mut total = [0; 3];
+        for (counts, _) in &rows {
+            for (sum, count) in total.iter_mut().zip(counts) {
+                *sum += count;
+            }
+        }
+        rows.push((total, Some("total")));
+    }
+
+    // Columns are right-aligned to the widest number shown
+    let width = rows
+        .iter()
+        .flat_map(|(counts, _)| counts.iter().zip(shown).filter(|(_, shown)| *shown).map(|(count, _)| count.to_string().len()))
+        .max()
+        .unwrap_or(1);
+    for (counts, name) in rows {
+        let mut fields: Vec<String> = counts
+            .iter()
+            .zip(shown)
+            .filter(|(_, shown)| *shown)
+            .map(|(count, _)| format!("{:>width$}", count, width = width))
+            .collect();
+        fields.extend(name.map(str::to_string));
+        writeln!(out, "{}", fields.join(" "))?;
+    }
+    if failed {
+        return Err(ShellError::Reported("wc"));
+    }
+    Ok(())
+}
+
+// Runs a command (echo by default) once, with the words of its input added
+// as arguments. With -0 the input is split on NUL bytes instead of
+// whitespace, so names from `find -print0` can hold spaces and newlines.
+// Options come before the command; everything after it is the command's.
+fn xargs(args: &[&str], shell: &mut Shell, io: &mut Streams) -> ShellResult<()> {
+    let mut null = false;
+    let mut rest = args;
+    while let Some((&flag, after)) = rest.split_first() {
+        match flag {
+            "-0" | "--null" => null = true,
+            "--" => {
+                rest = after;
+                break;
+            }
+            _ if flag.starts_with('-') => return Err(ShellError::InvalidArgument(flag.to_string())),
+            _ => break,
+        }
+        rest = after;
+    }
+
+    let input = match io.input.take() {
+        Some(input) => input,
+        None => {
+            let mut input = Vec::new();
+            io::stdin().lock().read_to_end(&mut input)?;
+            input
+        }
+    };
+    let items: Vec<String> = if null {
+        input
+            .split(|&byte| byte == 0)
+            .filter(|item| !item.is_empty())
+            .map(|item| String::from_utf8_lossy(item).into_owned())
+            .collect()
+    } else {
+        String::from_utf8_lossy(&input).split_whitespace().map(str::to_string).collect()
+    };
+
+    let (command, initial) = rest.split_first().map_or(("echo", &[][..]), |(command, initial)| (*command, initial));
+    let mut command_args = initial.to_vec();
+    command_args.extend(items.iter().map(String::as_str));
+    shell.execute(command, &command_args, io)
+}
+
+// The files a head/tail command reads, with `-n N` as the line count (10 by
+// default). With no files they read piped input.
+fn head_tail_args<'a>(parsed: &ParsedArgs<'a>, input: Option<&[u8]>) -> ShellResult<(usize, Vec<&'a str>)> {
+    let lines = match parsed.value("lines") {
+        Some(count) => count
+            .parse()
+            .map_err(|_| ShellError::InvalidArgument(format!("invalid line count: {}", count)))?,
+        None => 10,
+    };
+    Ok((lines, input_files(&parsed.operands, input)?))
+}
+
+// Text filters split their input at newlines, or with -z at NUL bytes, and
+// end each record they print the same way
+fn record_separator(parsed: &ParsedArgs) -> u8 {
+    if parsed.has("zero-terminated") { b'\0' } else { b'\n' }
+}
+
+// The files a text filter reads: those given, or `-` for piped input
+fn input_files<'a>(operands: &[&'a str], input: Option<&[u8]>) -> ShellResult<Vec<&'a str>> {
+    match (operands, input) {
+        ([], Some(_)) => Ok(vec!["-"]),
+        ([], None) => Err(ShellError::MissingArguments("file")),
+        (files, _) => Ok(files.to_vec()),
+    }
+}
+
+// Runs `f` on every line of the files in turn, as if they were one
+fn for_each_input_line(
+    files: &[&str],
+    input: Option<&[u8]>,
+    separator: u8,
+    mut f: impl FnMut(&str) -> ShellResult<()>,
+) -> ShellResult<()> {
+    for file in files {
+        for_each_line(open_lines(file, input)?, separator, |_, line, _| f(line))?;
+    }
+    Ok(())
+}
+
+// With -n, lines that start with a number are ordered by its value. Lines
+// that don't come before them, in ordinary string order, as do numbers
+// that tie.
+fn sort(args: &[&str], input: Option<&[u8]>, out: &mut dyn Write) -> ShellResult<()> {
+    const FLAGS: &[Flag] = &[
+        Flag::switch("r", "reverse"),
+        Flag::switch("n", "numeric-sort"),
+        Flag::switch("z", "zero-terminated"),
+    ];
+    let parsed = parse_args(args, FLAGS)?;
+    let separator = record_separator(&parsed);
+    let mut lines = Vec::new();
+    for_each_input_line(&input_files(&parsed.operands, input)?, input, separator, |line| {
+        lines.push(line.to_string());
+        Ok(())
+    })?;
+
+    if parsed.has("numeric-sort") {
+        lines.sort_by(|a, b| match (leading_number(a), leading_number(b)) {
+            (Some(x), Some(y)) => x.total_cmp(&y).then_with(|| a.cmp(b)),
+            (x, y) => x.is_some().cmp(&y.is_some()).then_with(|| a.cmp(b)),
+        });
+    } else {
+        lines.sort();
+    }
+    if parsed.has("reverse") {
+        lines.reverse();
+    }
+    for line in lines {
+        write!(out, "{}", line)?;
+        out.write_all(&[separator])?;
+    }
+    Ok(())
+}
+
+// The number a line starts with, after any leading blanks: an optional
+// minus sign, digits and an optional fraction
+fn leading_number(line: &str) -> Option<f64> {
+    let line = line.trim_start();
+    let digits = line.strip_prefix('-').unwrap_or(line);
+    let mut end = digits.find(|c: char| !c.is_ascii_digit()).unwrap_or(digits.len());
+    if let Some(fraction) = digits[end..].strip_prefix('.') {
+        end += 1 + fraction.find(|c: char| !c.is_ascii_digit()).unwrap_or(fraction.len());
+    }
+    let number = &line[..line.len() - digits.len() + end];
+    number.trim_start_matches('-').chars().any(|c| c.is_ascii_digit()).then(|| number.parse().ok()).flatten()
+}
+
+// Only adjacent repeats collapse, so unsorted input may still hold duplicates
+fn uniq(args: &[&str], input: Option<&[u8]>, out: &mut dyn Write) -> ShellResult<()> {
+    let parsed = parse_args(args, &[Flag::switch("c", "count"), Flag::switch("z", "zero-terminated")])?;
+    let counted = parsed.has("count");
+    let separator = record_separator(&parsed);
+    let write = |line: &str, count: usize, out: &mut dyn Write| -> ShellResult<()> {
+        if counted {
+            write!(out, "{:>7} ", count)?;
+        }
+        write!(out, "{}", line)?;
+        Ok(out.write_all(&[separator])?)
+    };
+
+    let mut current: Option<(String, usize)> = None;
+    for_each_input_line(&input_files(&parsed.operands, input)?, input, separator, |line| {
+        match &mut current {
+            Some((last, count)) if last == line => *count += 1,
+            _ => {
+                if let Some((last, count)) = current.replace((line.to_string(), 1)) {
+                    write(&last, count, out)?;
+                }
+            }
+        }
+        Ok(())
+    })?;
+    if let Some((last, count)) = current {
+        write(&last, count, out)?;
+    }
+    Ok(())
+}
+
+// Numbers the non-empty lines as nl does by default; blank lines are kept
+// but not counted
+fn nl(args: &[&str], input: Option<&[u8]>, out: &mut dyn Write) -> ShellResult<()> {
+    let parsed = parse_args(args, &[Flag::switch("z", "zero-terminated")])?;
+    let separator = record_separator(&parsed);
+    let mut count = 0;
+    for_each_input_line(&input_files(&parsed.operands, input)?, input, separator, |line| {
+        if line.is_empty() {
+            write!(out, "{:7}", "")?;
+        } else {
+            count += 1;
+            write!(out, "{:>6}\t{}", count, line)?;
+        }
+        Ok(out.write_all(&[separator])?)
+    })
+}
+
+// With several files each one's lines come under a `==> name <==` header,
+// and a blank line separates one file from the next
+fn write_file_header(file: &str, index: usize, files: usize, out: &mut dyn Write) -> io::Result<()> {
+    if files < 2 {
+        return Ok(());
+    }
+    if index > 0 {
+        writeln!(out)?;
+    }
+    let name = if file == "-" { "standard input" } else { file };
+    writeln!(out, "==> {} <==", name)
+}
+
+fn head(args: &[&str], input: Option<&[u8]>, out: &mut dyn Write) -> ShellResult<()> {
+    const FLAGS: &[Flag] = &[Flag::value("n", "lines"), Flag::switch("z", "zero-terminated")];
+    let parsed = parse_args(args, FLAGS)?;
+    let separator = record_separator(&parsed);
+    let (lines, files) = head_tail_args(&parsed, input)?;
+    for (index, file) in files.iter().enumerate() {
+        let mut reader = open_lines(file, input)?;
+        write_file_header(file, index, files.len(), out)?;
+        // Reading stops after the last line wanted, so a huge file costs no
+        // more than a small one
+        let mut line = Vec::new();
+        for _ in 0..lines {
+            line.clear();
+            if reader.read_until(separator, &mut line)? == 0 {
+                break;
+            }
+            out.write_all(&line)?;
+        }
+    }
+    Ok(())
+}
+
+fn tail(args: &[&str], input: Option<&[u8]>, out: &mut dyn Write) -> ShellResult<()> {
+    const FLAGS: &[Flag] = &[
+        Flag::switch("f", "follow"),
+        Flag::value("n", "lines"),
+        Flag::switch("z", "zero-terminated"),
+    ];
+    let parsed = parse_args(args, FLAGS)?;
+    let follow = parsed.has("follow");
+    let separator = record_separator(&parsed);
+    let (lines, files) = head_tail_args(&parsed, input)?;
+    if follow && files.len() > 1 {
+        return Err(ShellError::InvalidArgument("-f follows a single file".to_string()));
+    }
+    if follow && files[0] == "-" {
+        return Err(ShellError::InvalidArgument("cannot follow standard input".to_string()));
+    }
+
+    let mut length = 0;
+    for (index, file) in files.iter().enumerate() {
+        let mut content = Vec::new();
+        open_lines(file, input)?.read_to_end(&mut content)?;
+        write_file_header(file, index, files.len(), out)?;
+        let mut last = VecDeque::with_capacity(lines);
+        for_each_line(&content[..], separator, |_, line, terminated| {
+            if last.len() == lines {
+                last.pop_front();
+            }
+            if lines > 0 {
+                last.push_back((line.to_string(), terminated));
+            }
+            Ok(())
+        })?;
+        for (line, terminated) in last {
+            write!(out, "{}", line)?;
+            if terminated {
+                out.write_all(&[separator])?;
+            }
+        }
+        length = content.len();
+    }
+
+    if follow {
+        follow_file(Path::new(files[0]), length as u64, out)?;
+    }
+    Ok(())
+}
+
+// Polls the file for growth until Ctrl-C. A file that shrinks (truncated) or
+// is replaced by a new one (log rotation) is reopened and read from the start.
+fn follow_file(path: &Path, mut position: u64, out: &mut dyn Write) -> ShellResult<()> {
+    let mut handle = fs::File::open(path)?;
+    let mut identity = file_identity(&handle.metadata()?);
+
+    INTERRUPTED.store(false, Ordering::SeqCst);
+    while !INTERRUPTED.load(Ordering::SeqCst) {
+        thread::sleep(Duration::from_millis(250));
+
+        let metadata = match fs::metadata(path) {
+            Ok(metadata) => metadata,
+            // The file may briefly disappear while being rotated
+            Err(_) => continue,
+        };
+
+        if file_identity(&metadata) != identity {
+            eprintln!("tail: {}: file replaced; following new file", path.display());
+            handle = fs::File::open(path)?;
+            identity = file_identity(&metadata);
+            position = 0;
+        } else if metadata.len() < position {
+            eprintln!("tail: {}: file truncated", path.display());
+            position = 0;
+        }
+
+        if metadata.len() > position {
+            handle.seek(SeekFrom::Start(position))?;
+            let mut appended = Vec::new();
+            position += handle.read_to_end(&mut appended)? as u64;
+            write!(out, "{}", String::from_utf8_lossy(&appended))?;
+            out.flush()?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+fn file_identity(metadata: &fs::Metadata) -> Option<(u64, u64)> {
+    use std::os::unix::fs::MetadataExt;
+    Some((metadata.dev(), metadata.ino()))
+}
+
+#[cfg(not(unix))]
+fn file_identity(_metadata: &fs::Metadata) -> Option<(u64, u64)> {
+    None
+}
+
+// Durations are a number with an optional s/m/h/d suffix; seconds by default.
+fn parse_duration(spec: &str) -> Option<Duration> {
+    let (number, scale) = match spec.char_indices().last()? {
+        (i, 's') => (&spec[..i], 1.0),
+        (i, 'm') => (&spec[..i], 60.0),
+        (i, 'h') => (&spec[..i], 3600.0),
+        (i, 'd') => (&spec[..i], 86400.0),
+        _ => (spec, 1.0),
+    };
+    let seconds: f64 = number.parse().ok()?;
+    Duration::try_from_secs_f64(seconds * scale).ok()
+}
+
+fn timeout(args: &[&str], shell: &mut Shell, io: &mut Streams) -> ShellResult<()> {
+    let mut grace = Duration::from_secs(2);
+    let mut args = args;
+    if args.first() == Some(&"-k") {
+        let spec = args.get(1).ok_or(ShellError::MissingArguments("kill-after duration"))?;
+        grace = parse_duration(spec)
+            .ok_or_else(|| ShellError::InvalidArgument(format!("invalid duration: {}", spec)))?;
+        args = &args[2..];
+    }
+    // Only a `--` before the duration is ours; later ones belong to the command
+    if args.first() == Some(&"--") {
+        args = &args[1..];
+    }
+
+    if args.len() < 2 {
+        return Err(ShellError::MissingArguments("duration and command"));
+    }
+    let limit = parse_duration(args[0])
+        .ok_or_else(|| ShellError::InvalidArgument(format!("invalid duration: {}", args[0])))?;
+    let (command, cmd_args) = (args[1], &args[2..]);
+
+    if shell.commands.contains_key(command) {
+        timeout_builtin(limit, command, cmd_args, shell, io)
+    } else {
+        let program = plugin_path(command, &mut shell.state).unwrap_or_else(|| PathBuf::from(command));
+        timeout_external(limit, grace, &program, cmd_args, &mut shell.state, io)
+    }
+}
+
+// Builtins run in-process, so on expiry they are asked to stop the same way
+// Ctrl-C would; only builtins that poll for interruption (tail -f, notify)
+// can actually be cut short.
+fn timeout_builtin(
+    limit: Duration,
+    command: &str,
+    args: &[&str],
+    shell: &mut Shell,
+    io: &mut Streams,
+) -> ShellResult<()> {
+    let (done_tx, done_rx) = mpsc::channel::<()>();
+    let timer = thread::spawn(move || {
+        let expired = done_rx.recv_timeout(limit) == Err(mpsc::RecvTimeoutError::Timeout);
+        if expired {
+            INTERRUPTED.store(true, Ordering::SeqCst);
+        }
+        expired
+    });
+
+    let result = shell.execute(command, args, io);
+    let _ = done_tx.send(());
+    if timer.join().unwrap_or(false) {
+        return Err(ShellError::TimedOut(command.to_string(), limit));
+    }
+    result
+}
+
+fn timeout_external(
+    limit: Duration,
+    grace: Duration,
+    program: &Path,
+    args: &[&str],
+    state: &mut ShellState,
+    io: &mut Streams,
+) -> ShellResult<()> {
+    let command = program.display().to_string();
+    let mut cmd = process::Command::new(program);
+    cmd.args(args);
+    if io.input.is_some() {
+        cmd.stdin(process::Stdio::piped());
+    }
+    match &io.output {
+        Sink::Terminal => {}
+        Sink::Capture(_) => {
+            cmd.stdout(process::Stdio::piped());
+        }
+        Sink::File(file) => {
+            cmd.stdout(file.try_clone()?);
+        }
+    }
+    let mut child = cmd.spawn().map_err(|e| match e.kind() {
+        io::ErrorKind::NotFound => ShellError::CommandNotFound(command.clone()),
+        io::ErrorKind::PermissionDenied => ShellError::PermissionDenied(command.clone()),
+        _ => ShellError::Io(e),
+    })?;
+
+    // Both ends are serviced from threads so the deadline keeps ticking
+    // while the program reads its input or fills the pipe
+    let feeder = match (io.input.take(), child.stdin.take()) {
+        (Some(input), Some(mut stdin)) => Some(thread::spawn(move || stdin.write_all(&input))),
+        _ => None,
+    };
+    let reader = child.stdout.take().map(|mut stdout| {
+        thread::spawn(move || {
+            let mut captured = Vec::new();
+            stdout.read_to_end(&mut captured).map(|_| captured)
+        })
+    });
+
+    let status = match wait_with_deadline(&mut child, Instant::now() + limit)? {
+        Some(status) => Some(status),
+        None => {
+            // Ask politely first, then force the issue once the grace period runs out
+            #[cfg(unix)]
+            unsafe {
+                libc::kill(child.id() as libc::pid_t, libc::SIGTERM);
+            }
+            if wait_with_deadline(&mut child, Instant::now() + grace)?.is_none() {
+                child.kill()?;
+                child.wait()?;
+            }
+            None
+        }
+    };
+
+    if let Some(feeder) = feeder {
+        // The program may exit without reading everything; that's not an error
+        let _ = feeder.join();
+    }
+    if let (Sink::Capture(captured), Some(reader)) = (&mut io.output, reader) {
+        if let Ok(Ok(bytes)) = reader.join() {
+            captured.extend(bytes);
+        }
+    }
+    match status {
+        Some(status) => {
+            state.last_status = exit_code(status);
+            Ok(())
+        }
+        None => Err(ShellError::TimedOut(command, limit)),
+    }
+}
+
+fn wait_with_deadline(child: &mut process::Child, deadline: Instant) -> ShellResult<Option<process::ExitStatus>> {
+    loop {
+        if let Some(status) = child.try_wait()? {
+            return Ok(Some(status));
+        }
+        if Instant::now() >= deadline {
+            return Ok(None);
+        }
+        thread::sleep(Duration::from_millis(20));
+    }
+}
+
+// Starts the command in its own session with SIGHUP ignored, so it keeps
+// running after the terminal or the shell goes away. Output is appended to
+// nohup.out (falling back to ~/nohup.out when the cwd isn't writable).
+fn nohup(args: &[&str], out: &mut dyn Write) -> ShellResult<()> {
+    let (command, cmd_args) = args.split_first().ok_or(ShellError::MissingArguments("command"))?;
+
+    let open_log = |path: &Path| fs::OpenOptions::new().create(true).append(true).open(path);
+    let mut log_path = PathBuf::from("nohup.out");
+    let log = match open_log(&log_path) {
+        Ok(log) => log,
+        Err(_) => {
+            log_path = dirs::home_dir().unwrap_or_else(|| PathBuf::from(".")).join("nohup.out");
+            open_log(&log_path)?
+        }
+    };
+
+    let mut cmd = process::Command::new(command);
+    cmd.args(cmd_args)
+        .stdin(process::Stdio::null())
+        .stdout(log.try_clone()?)
+        .stderr(log);
+
+    #[cfg(unix)]
+    unsafe {
+        use std::os::unix::process::CommandExt;
+        cmd.pre_exec(|| {
+            libc::signal(libc::SIGHUP, libc::SIG_IGN);
+            libc::setsid();
+            Ok(())
+        });
+    }
+
+    let mut child = cmd.spawn().map_err(|e| {
+        if e.kind() == io::ErrorKind::NotFound {
+            ShellError::CommandNotFound(command.to_string())
+        } else {
+            ShellError::Io(e)
+        }
+    })?;
+
+    writeln!(out, "nohup: [{}] appending output to '{}'", child.id(), log_path.display())?;
+    // Reap the child whenever it finishes so it doesn't linger as a zombie
+    thread::spawn(move || child.wait());
+    Ok(())
+}
+
+fn watch_path(args: &[&str], shell: &mut Shell, io: &mut Streams) -> ShellResult<()> {
+    let (path, exec) = match args.iter().position(|arg| *arg == "--exec") {
+        Some(i) => (args[..i].first(), Some(&args[i + 1..])),
+        None => (args.first(), None),
+    };
+    let path = path.ok_or(ShellError::MissingArguments("path"))?;
+    if exec.is_some_and(|cmd| cmd.is_empty()) {
+        return Err(ShellError::MissingArguments("--exec command"));
+    }
+    if !Path::new(path).exists() {
+        return Err(ShellError::FileNotFound(path.to_string()));
+    }
+
+    let (tx, rx) = mpsc::channel();
+    let mut watcher = notify::recommended_watcher(tx)
+        .map_err(|e| ShellError::InvalidArgument(format!("cannot watch {}: {}", path, e)))?;
+    watcher
+        .watch(Path::new(path), RecursiveMode::Recursive)
+        .map_err(|e| ShellError::InvalidArgument(format!("cannot watch {}: {}", path, e)))?;
+
+    writeln!(io.output, "Watching {} (Ctrl-C to stop)", path)?;
+    INTERRUPTED.store(false, Ordering::SeqCst);
+    while !INTERRUPTED.load(Ordering::SeqCst) {
+        let event = match rx.recv_timeout(Duration::from_millis(200)) {
+            Ok(Ok(event)) => event,
+            Ok(Err(e)) => {
+                eprintln!("notify: {}", e);
+                continue;
+            }
+            Err(mpsc::RecvTimeoutError::Timeout) => continue,
+            Err(mpsc::RecvTimeoutError::Disconnected) => break,
+        };
+
+        let kind = match event.kind {
+            EventKind::Create(_) => "created",
+            EventKind::Modify(_) => "modified",
+            EventKind::Remove(_) => "deleted",
+            _ => continue,
+        };
+        for changed in &event.paths {
+            writeln!(io.output, "{}: {}", kind, changed.display())?;
+        }
+
+        if let Some(cmd) = exec {
+            let (command, cmd_args) = (cmd[0], &cmd[1..]);
+            if let Err(e) = shell.execute(command, cmd_args, io) {
+                handle_error(e, command, shell.state.error_format);
+            }
+        }
+    }
+    Ok(())
+}
+
+fn plugin_dir() -> Option<PathBuf> {
+    dirs::home_dir().map(|home| home.join(".ash").join("commands"))
+}
+
+fn scan_plugins() -> HashMap<String, PathBuf> {
+    let Some(entries) = plugin_dir().and_then(|dir| fs::read_dir(dir).ok()) else {
+        return HashMap::new();
+    };
+
+    entries
+        .filter_map(Result::ok)
+        .filter(|entry| fs::metadata(entry.path()).is_ok_and(|meta| meta.is_file() && is_executable(&meta)))
+        .map(|entry| (entry.file_name().to_string_lossy().into_owned(), entry.path()))
+        .collect()
+}
+
+#[cfg(unix)]
+fn is_executable(metadata: &fs::Metadata) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+    metadata.permissions().mode() & 0o111 != 0
+}
+
+#[cfg(not(unix))]
+fn is_executable(_metadata: &fs::Metadata) -> bool {
+    true
+}
+
+// The first executable file called `name` in a PATH directory; a name with
+// a `/` in it is checked as it is
+fn find_in_path(name: &str) -> Option<PathBuf> {
+    let runnable = |path: &Path| fs::metadata(path).is_ok_and(|metadata| metadata.is_file() && is_executable(&metadata));
+    if name.contains('/') {
+        let path = PathBuf::from(name);
+        return runnable(&path).then_some(path);
+    }
+    env::split_paths(&env::var_os("PATH")?).map(|dir| dir.join(name)).find(|path| runnable(path))
+}
+
+// `complete [-W words] [-f | -d | -G pattern] command...` sets how the
+// arguments of each command complete on Tab; `-r` removes the setting and
+// no arguments lists them all. Words are separated by commas or spaces.
+fn complete(args: &[&str], shell: &mut Shell, out: &mut dyn Write) -> ShellResult<()> {
+    const FLAGS: &[Flag] = &[
+        Flag::value("W", "words"),
+        Flag::switch("f", "files"),
+        Flag::switch("d", "directories"),
+        Flag::value("G", "glob"),
+        Flag::switch("r", "remove"),
+    ];
+    let parsed = parse_args(args, FLAGS)?;
+    let Some(helper) = shell.rl.as_mut().and_then(|rl| rl.helper_mut()) else {
+        return Ok(());
+    };
+    if parsed.flags.is_empty() && parsed.operands.is_empty() {
+        for (command, spec) in &helper.specs {
+            writeln!(out, "{}", spec.describe(command))?;
+        }
+        return Ok(());
+    }
+    if parsed.operands.is_empty() {
+        return Err(ShellError::MissingArguments("command name"));
+    }
+    if parsed.has("remove") {
+        for command in &parsed.operands {
+            helper.specs.remove(*command);
+        }
+        return Ok(());
+    }
+
+    // The last of -f, -d and -G wins
+    let paths = parsed.flags.iter().rev().find_map(|(flag, value)| match (*flag, value) {
+        ("files", _) => Some(PathKind::Any),
+        ("directories", _) => Some(PathKind::Dirs),
+        ("glob", Some(pattern)) => Some(PathKind::Matching(pattern.to_string())),
+        _ => None,
+    });
+    let words: Vec<String> = parsed
+        .values("words")
+        .flat_map(|list| list.split([',', ' ']))
+        .filter(|word| !word.is_empty())
+        .map(String::from)
+        .collect();
+    for command in &parsed.operands {
+        let spec = CompletionSpec { words: words.clone(), paths: paths.clone() };
+        helper.specs.insert(command.to_string(), spec);
+    }
+    Ok(())
+}
+
+// Says what each name would run, checked in the order the shell tries
+// them: alias, builtin, plugin, then PATH
+fn which(args: &[&str], shell: &mut Shell, out: &mut dyn Write) -> ShellResult<()> {
+    if args.is_empty() {
+        return Err(ShellError::MissingArguments("command name"));
+    }
+    let mut missing = Vec::new();
+    for name in args {
+        if let Some(value) = shell.state.aliases.get(*name) {
+            writeln!(out, "{}: aliased to {}", name, value)?;
+        } else if shell.commands.contains_key(*name) {
+            writeln!(out, "{}: shell builtin", name)?;
+        } else if let Some(path) = plugin_path(name, &mut shell.state).or_else(|| find_in_path(name)) {
+            writeln!(out, "{}", path.display())?;
+        } else {
+            missing.push(name.to_string());
+        }
+    }
+    // Every name is looked up; all but the last one missing are reported here
+    let last = missing.pop();
+    for name in missing {
+        eprintln!("{}", ShellError::CommandNotFound(name));
+    }
+    match last {
+        Some(name) => Err(ShellError::CommandNotFound(name)),
+        None => Ok(()),
+    }
+}
+
+fn plugin_path(name: &str, state: &mut ShellState) -> Option<PathBuf> {
+    state.plugins.get_or_insert_with(scan_plugins).get(name).cloned()
+}
+
+fn rehash(args: &[&str], state: &mut ShellState) -> ShellResult<()> {
+    if !args.is_empty() {
+        return Err(ShellError::InvalidArgument("rehash takes no arguments".into()));
+    }
+    state.plugins = Some(scan_plugins());
+    Ok(())
+}
+
+// Plugins and programs on PATH are only consulted once no builtin matched
+// the command name, and a plugin shadows a program of the same name.
+fn run_program(command: &str, args: &[&str], state: &mut ShellState, io: &mut Streams) -> ShellResult<()> {
+    let path = plugin_path(command, state).unwrap_or_else(|| PathBuf::from(command));
+    let mut cmd = process::Command::new(path);
+    cmd.args(args);
+    if io.input.is_some() {
+        cmd.stdin(process::Stdio::piped());
+    }
+    match &io.output {
+        Sink::Terminal => {}
+        Sink::Capture(_) => {
+            cmd.stdout(process::Stdio::piped());
+        }
+        Sink::File(file) => {
+            cmd.stdout(file.try_clone()?);
+        }
+    }
+    let mut child = cmd.spawn().map_err(|e| match e.kind() {
+        io::ErrorKind::NotFound => ShellError::CommandNotFound(command.to_string()),
+        io::ErrorKind::PermissionDenied => ShellError::PermissionDenied(command.to_string()),
+        _ => ShellError::Io(e),
+    })?;
+
+    // Feed piped input from another thread so a program that writes output
+    // before it has read all of its input can't deadlock against us
+    let feeder = match (io.input.take(), child.stdin.take()) {
+        (Some(input), Some(mut stdin)) => Some(thread::spawn(move || stdin.write_all(&input))),
+        _ => None,
+    };
+    if let (Sink::Capture(captured), Some(mut stdout)) = (&mut io.output, child.stdout.take()) {
+        stdout.read_to_end(captured)?;
+    }
+    state.last_status = exit_code(child.wait()?);
+    if let Some(feeder) = feeder {
+        // The program may exit without reading everything; that's not an error
+        let _ = feeder.join();
+    }
+    Ok(())
+}
+
+// Spawns a program without waiting for it and records it as a job. Its
+// stdin isn't the terminal, so it can't steal input from the prompt.
+fn start_job(
+    command: &str,
+    args: &[&str],
+    state: &mut ShellState,
+    io: &mut Streams,
+    errors: &mut dyn Write,
+) -> ShellResult<()> {
+    let path = plugin_path(command, state).unwrap_or_else(|| PathBuf::from(command));
+    let mut cmd = process::Command::new(path);
+    cmd.args(args);
+    cmd.stdin(if io.input.is_some() { process::Stdio::piped() } else { process::Stdio::null() });
+    match &io.output {
+        Sink::Terminal => {}
+        // Nobody is left to read captured output once we return
+        Sink::Capture(_) => {
+            cmd.stdout(process::Stdio::null());
+        }
+        Sink::File(file) => {
+            cmd.stdout(file.try_clone()?);
+        }
+    }
+    let mut child = cmd.spawn().map_err(|e| match e.kind() {
+        io::ErrorKind::NotFound => ShellError::CommandNotFound(command.to_string()),
+        io::ErrorKind::PermissionDenied => ShellError::PermissionDenied(command.to_string()),
+        _ => ShellError::Io(e),
+    })?;
+    if let (Some(input), Some(mut stdin)) = (io.input.take(), child.stdin.take()) {
+        thread::spawn(move || stdin.write_all(&input));
+    }
+
+    let id = state.next_job_id;
+    state.next_job_id += 1;
+    writeln!(errors, "[{}] {}", id, child.id())?;
+    let command = std::iter::once(command).chain(args.iter().copied()).collect::<Vec<_>>().join(" ");
+    state.jobs.push(Job { id, command, child });
+    Ok(())
+}
+
+// Drops finished jobs, describing each one as bash does: `Done`, or `Exit N`
+// for a nonzero status
+fn reap_jobs(state: &mut ShellState) -> Vec<(Job, String)> {
+    let mut finished = Vec::new();
+    let mut running = Vec::new();
+    for mut job in state.jobs.drain(..) {
+        match job.child.try_wait() {
+            Ok(None) => running.push(job),
+            Ok(Some(status)) => finished.push((job, job_status(status))),
+            Err(e) => finished.push((job, format!("Unknown ({})", e))),
+        }
+    }
+    state.jobs = running;
+    finished
+}
+
+fn job_status(status: process::ExitStatus) -> String {
+    match exit_code(status) {
+        0 => "Done".to_string(),
+        code => format!("Exit {}", code),
+    }
+}
+
+// One line per job as bash prints it; `long` adds the process ID
+fn report_job(job: &Job, status: &str, long: bool, out: &mut dyn Write) -> io::Result<()> {
+    if long {
+        writeln!(out, "[{}]+ {} {:<10} {}", job.id, job.child.id(), status, job.command)
+    } else {
+        writeln!(out, "[{}]+ {:<10} {}", job.id, status, job.command)
+    }
+}
+
+// Called before each prompt, so a job that finished in the meantime is
+// announced once, the next time the shell is about to wait for input
+fn report_finished_jobs(state: &mut ShellState, out: &mut dyn Write) {
+    for (job, status) in reap_jobs(state) {
+        let _ = report_job(&job, &status, false, out);
+    }
+}
+
+// Jobs that finished since the last prompt are listed (and forgotten)
+// first, then the ones still running
+fn jobs(args: &[&str], state: &mut ShellState, out: &mut dyn Write) -> ShellResult<()> {
+    let long = match args {
+        [] => false,
+        ["-l"] => true,
+        _ => return Err(ShellError::InvalidArgument(args.join(" "))),
+    };
+    for (job, status) in reap_jobs(state) {
+        report_job(&job, &status, long, out)?;
+    }
+    for job in &state.jobs {
+        report_job(job, "Running", long, out)?;
+    }
+    Ok(())
+}
+
+// Blocks until the given jobs (all of them with no arguments) have finished
+// and reports how each one ended
+fn wait(args: &[&str], state: &mut ShellState, out: &mut dyn Write) -> ShellResult<()> {
+    let ids = args
+        .iter()
+        .map(|arg| match arg.parse::<usize>() {
+            Ok(id) if state.jobs.iter().any(|job| job.id == id) => Ok(id),
+            _ => Err(ShellError::InvalidArgument(format!("no such job: {}", arg))),
+        })
+        .collect::<ShellResult<Vec<_>>>()?;
+
+    let (mut waiting, rest) = state.jobs.drain(..).partition(|job| ids.is_empty() || ids.contains(&job.id));
+    state.jobs = rest;
+    waiting.sort_by_key(|job: &Job| job.id);
+    let mut status = 0;
+    for mut job in waiting {
+        let result = job.child.wait()?;
+        status = exit_code(result);
+        report_job(&job, &job_status(result), false, out)?;
+    }
+    state.last_status = status;
+    Ok(())
+}
+
+// Killed processes report 128 + the signal number, as in other shells
+fn exit_code(status: process::ExitStatus) -> i32 {
+    #[cfg(unix)]
+    {
+        use std::os::unix::process::ExitStatusExt;
+        if let Some(signal) = status.signal() {
+            return 128 + signal;
+        }
+    }
+    status.code().unwrap_or(1)
+}
+
+fn set_option(args: &[&str], shell: &mut Shell, out: &mut dyn Write) -> ShellResult<()> {
+    match args {
+        [] | ["-o"] | ["+o"] => {
+            let on_off = |on: bool| if on { "on" } else { "off" };
+            writeln!(out, "emacs\t{}", on_off(shell.state.edit_mode == EditMode::Emacs))?;
+            writeln!(out, "pipefail\t{}", on_off(shell.state.pipefail))?;
+            writeln!(out, "posix\t{}", on_off(shell.state.posix))?;
+            writeln!(out, "safe\t{}", on_off(shell.state.safe_mode))?;
+            writeln!(out, "vi\t{}", on_off(shell.state.edit_mode == EditMode::Vi))?;
+            writeln!(out, "xtrace\t{}", on_off(shell.state.xtrace))?;
+            Ok(())
+        }
+        [flag @ ("-x" | "+x")] => {
+            shell.state.xtrace = *flag == "-x";
+            Ok(())
+        }
+        [flag @ ("-o" | "+o"), option] => {
+            let enable = *flag == "-o";
+            match *option {
+                // The two editing modes are mutually exclusive, so turning one off selects the other
+                "vi" | "emacs" => {
+                    let vi = (*option == "vi") == enable;
+                    shell.state.edit_mode = if vi { EditMode::Vi } else { EditMode::Emacs };
+                    if let Some(rl) = &mut shell.rl {
+                        rl.set_edit_mode(shell.state.edit_mode);
+                    }
+                    Ok(())
+                }
+                "safe" => {
+                    shell.state.safe_mode = enable;
+                    if enable {
+                        writeln!(out, "Safe mode: blocking {}", SAFE_MODE_BLOCKS)?;
+                    }
+                    Ok(())
+                }
+                "xtrace" => {
+                    shell.state.xtrace = enable;
+                    Ok(())
+                }
+                "pipefail" => {
+                    shell.state.pipefail = enable;
+                    Ok(())
+                }
+                "posix" => {
+                    shell.state.posix = enable;
+                    Ok(())
+                }
+                _ => Err(ShellError::InvalidArgument(format!("unknown option: {}", option))),
+            }
+        }
+        _ => Err(ShellError::InvalidArgument(args.join(" "))),
+    }
+}
+
+// Lets a script stop early, with a clear message, when it needs features from
+// a newer ASH than `current`. `0.2` means 0.2.0 or later, as does `0.2.0`.
+fn require_version(args: &[&str], current: &str) -> ShellResult<()> {
+    let [required] = args else {
+        return Err(ShellError::MissingArguments("version"));
+    };
+    let requirement = semver::VersionReq::parse(&format!(">={}", required))
+        .map_err(|_| ShellError::InvalidArgument(format!("invalid version: {} (expected e.g. 0.2.0)", required)))?;
+    let current = semver::Version::parse(current).map_err(|e| ShellError::InvalidArgument(e.to_string()))?;
+    if !requirement.matches(&current) {
+        return Err(ShellError::Unavailable(format!("ASH {} or later is required; this is ASH {}", required, current)));
+    }
+    Ok(())
+}
+
+fn set_prompt_command(args: &[&str], state: &mut ShellState, out: &mut dyn Write) -> ShellResult<()> {
+    match args {
+        [] => {
+            if let Some(hook) = &state.prompt_command {
+                writeln!(out, "{}", hook)?;
+            }
+        }
+        ["-c"] => state.prompt_command = None,
+        _ => state.prompt_command = Some(args.join(" ")),
+    }
+    Ok(())
+}
+
+fn chpwd(args: &[&str], state: &mut ShellState, out: &mut dyn Write) -> ShellResult<()> {
+    match args {
+        [] => {
+            for hook in &state.chpwd_hooks {
+                writeln!(out, "{}", hook)?;
+            }
+        }
+        ["-c"] => state.chpwd_hooks.clear(),
+        _ => state.chpwd_hooks.push(args.join(" ")),
+    }
+    Ok(())
+}
+
+fn bind(args: &[&str], shell: &mut Shell, out: &mut dyn Write) -> ShellResult<()> {
+    match args {
+        [] | ["-p"] => {
+            for (keyseq, binding) in &shell.state.bindings {
+                match binding {
+                    Binding::Action(action) => writeln!(out, "\"{}\": {}", keyseq, action)?,
+                    Binding::Macro(text) => writeln!(out, "\"{}\": \"{}\"", keyseq, text)?,
+                }
+            }
+            Ok(())
+        }
+        ["-l"] => {
+            for action in BIND_ACTIONS {
+                writeln!(out, "{}", action)?;
+            }
+            Ok(())
+        }
+        ["-r"] => Err(ShellError::MissingArguments("key sequence")),
+        ["-r", keyseq] => {
+            let keyseq = keyseq.trim_matches(|c| c == '"' || c == '\'');
+            let keys = parse_keyseq(keyseq)?;
+            if let Some(rl) = &mut shell.rl {
+                rl.unbind_sequence(keys);
+            }
+            shell.state.bindings.retain(|(bound, _)| bound != keyseq);
+            Ok(())
+        }
+        _ => {
+            let (keyseq, binding) = parse_binding(&args.join(" "))?;
+            let cmd = match &binding {
+                Binding::Action(action) => bind_action_cmd(action),
+                Binding::Macro(text) => Some(Cmd::Insert(1, text.clone())),
+            };
+            if let Some(cmd) = cmd {
+                let keys = parse_keyseq(&keyseq)?;
+                if let Some(rl) = &mut shell.rl {
+                    rl.bind_sequence(keys, EventHandler::Simple(cmd));
+                }
+            }
+            shell.state.bindings.retain(|(bound, _)| *bound != keyseq);
+            shell.state.bindings.push((keyseq, binding));
+            Ok(())
+        }
+    }
+}
+
+// Parses readline-style `"keyseq": action` or `"keyseq": "macro text"`,
+// optionally wrapped in single quotes as it would be written in bash.
+fn parse_binding(spec: &str) -> ShellResult<(String, Binding)> {
+    let spec = spec.trim().trim_matches('\'');
+    let invalid = || ShellError::InvalidArgument(format!("expected '\"keyseq\": action', got: {}", spec));
+
+    let rest = spec.strip_prefix('"').ok_or_else(invalid)?;
+    let (keyseq, target) = rest.split_once("\":").ok_or_else(invalid)?;
+    let target = target.trim();
+
+    let binding = if let Some(text) = target.strip_prefix('"').and_then(|t| t.strip_suffix('"')) {
+        Binding::Macro(text.to_string())
+    } else {
+        let action = BIND_ACTIONS
+            .iter()
+            .find(|name| **name == target)
+            .ok_or_else(|| ShellError::InvalidArgument(format!("unknown action: {} (see bind -l)", target)))?;
+        Binding::Action(action)
+    };
+    Ok((keyseq.to_string(), binding))
+}
+
+// Supports `\C-x` (control), `\M-x` or `\ex` (meta/alt) and single characters.
+fn parse_keyseq(keyseq: &str) -> ShellResult<KeyEvent> {
+    let single = |rest: &str| {
+        let mut chars = rest.chars();
+        match (chars.next(), chars.next()) {
+            (Some(c), None) => Some(c),
+            _ => None,
+        }
+    };
+
+    let key = if let Some(c) = keyseq.strip_prefix("\\C-").and_then(single) {
+        Some(KeyEvent::ctrl(c))
+    } else if let Some(c) = keyseq.strip_prefix("\\M-").or_else(|| keyseq.strip_prefix("\\e")).and_then(single) {
+        Some(KeyEvent::alt(c))
+    } else {
+        single(keyseq).map(KeyEvent::from)
+    };
+    key.ok_or_else(|| ShellError::InvalidArgument(format!("unsupported key sequence: {}", keyseq)))
+}
+
+// Replaces `!!` with the previous command, `!N` with history entry N (as
+// numbered by `history`), `!-N` with the Nth most recent entry and `!prefix`
+// with the latest entry starting with `prefix`. `None` means the line had
+// nothing to expand; an event that matches nothing is returned as the
+// error. A `!` before a space, `=` or `(`, at the end or inside single
+// quotes is left alone, and `\!` gives a literal `!`.
+fn expand_history(line: &str, history: &FileHistory) -> Option<Result<String, String>> {
+    if !line.contains('!') {
+        return None;
+    }
+    let entries: Vec<&String> = history.iter().collect();
+    let mut expanded = String::with_capacity(line.len());
+    let mut changed = false;
+    let mut rest = line;
+    while let Some(i) = rest.find('!') {
+        // Single quotes keep a `!` literal, as they do everything else
+        if (expanded.matches('\'').count() + rest[..i].matches('\'').count()) % 2 == 1 {
+            expanded.push_str(&rest[..=i]);
+            rest = &rest[i + 1..];
+            continue;
+        }
+        if rest[..i].ends_with('\\') {
+            expanded.push_str(&rest[..i - 1]);
+            expanded.push('!');
+            rest = &rest[i + 1..];
+            changed = true;
+            continue;
+        }
+        expanded.push_str(&rest[..i]);
+        let after = &rest[i + 1..];
+        let len = match after.chars().next() {
+            None | Some(' ' | '\t' | '=' | '(') => {
+                expanded.push('!');
+                rest = after;
+                continue;
+            }
+            Some('!') => 1,
+            Some(_) => after.find(|c: char| c.is_whitespace() || ";|&".contains(c)).unwrap_or(after.len()),
+        };
+        let event = &after[..len];
+        let found = match event {
+            "!" => entries.last(),
+            _ => match event.parse::<i64>() {
+                Ok(n) if n > 0 => entries.get(n as usize - 1),
+                Ok(n) if n < 0 => entries.len().checked_sub(n.unsigned_abs() as usize).and_then(|idx| entries.get(idx)),
+                Ok(_) => None,
+                Err(_) => entries.iter().rev().find(|entry| entry.starts_with(event)),
+            },
+        };
+        match found {
+            Some(entry) => expanded.push_str(entry),
+            None => return Some(Err(format!("!{}", event))),
+        }
+        rest = &after[len..];
+        changed = true;
+    }
+    expanded.push_str(rest);
+    changed.then_some(Ok(expanded))
+}
+
+fn show_history(
+    args: &[&str],
+    rl: Option<&LineEditor>,
+    state: &ShellState,
+    out: &mut dyn Write,
+) -> ShellResult<()> {
+    let parsed = parse_args(args, &[Flag::switch("a", "all")])?;
+    let last = match parsed.operands[..] {
+        [] => None,
+        [count] => Some(
+            count
+                .parse::<usize>()
+                .map_err(|_| ShellError::InvalidArgument(format!("invalid entry count: {}", count)))?,
+        ),
+        _ => return Err(ShellError::InvalidArgument(parsed.operands[1..].join(" "))),
+    };
+    // Only the most recent ASH_HISTSIZE entries are loaded; --all reads the
+    // whole file, plus whatever this session hasn't written to it yet
+    let full;
+    let entries: Vec<&String> = if parsed.has("all") {
+        full = dirs::home_dir()
+            .map(|home| read_history(&home.join(".ash_history"), usize::MAX))
+            .transpose()?
+            .unwrap_or_default();
+        full.iter().chain(&state.history_new).collect()
+    } else {
+        rl.map(|rl| rl.history().iter().collect()).unwrap_or_default()
+    };
+    if entries.is_empty() {
+        writeln!(out, "No command history available")?;
+        return Ok(());
+    }
+    // `history N` shows the last N entries, still numbered as in the full list
+    let skipped = last.map_or(0, |last| entries.len().saturating_sub(last));
+
+    // ASH_HISTTIMEFORMAT is a chrono/strftime format printed before each entry,
+    // e.g. "%F %T " (include a trailing space, as with bash's HISTTIMEFORMAT)
+    let time_format = env::var("ASH_HISTTIMEFORMAT").ok().filter(|fmt| !fmt.is_empty());
+    let stamps = match &time_format {
+        Some(_) => match_history_times(&entries, &state.history_times),
+        None => vec![None; entries.len()],
+    };
+
+    for (idx, (entry, stamp)) in entries.iter().zip(stamps).enumerate().skip(skipped) {
+        let time = match (&time_format, stamp.and_then(|secs| DateTime::from_timestamp(secs, 0))) {
+            (Some(fmt), Some(time)) => format_time(&time.with_timezone(&Local), fmt)?,
+            _ => String::new(),
+        };
+        writeln!(out, "{}: {}{}", idx + 1, time, entry)?;
+    }
+    Ok(())
+}
+
+const DEFAULT_HISTORY_SIZE: usize = 1000;
+
+// ASH_HISTSIZE caps how many of the most recent entries are loaded at startup,
+// kept in memory and left in the file on exit
+fn history_load_limit() -> usize {
+    env::var("ASH_HISTSIZE")
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .filter(|&n| n > 0)
+        .unwrap_or(DEFAULT_HISTORY_SIZE)
+}
+
+// rustyline's "#V2" format: one entry per line, with backslashes and newlines
+// inside an entry escaped.
+const HISTORY_HEADER: &[u8] = b"#V2\n";
+
+// Reads the last `limit` entries of a history file. The file is scanned
+// backwards a chunk at a time, so startup cost tracks `limit` rather than the
+// size of the file.
+fn read_history(path: &Path, limit: usize) -> io::Result<Vec<String>> {
+    const CHUNK: u64 = 64 * 1024;
+
+    let mut file = fs::File::open(path)?;
+    let len = file.metadata()?.len();
+    let mut header = [0; HISTORY_HEADER.len()];
+    let v2 = file.read_exact(&mut header).is_ok() && header == HISTORY_HEADER;
+
+    // Each entry ends in a newline, so `limit` entries need `limit + 1` of them
+    // unless the scan reaches the start of the file
+    let mut start = len;
+    let mut tail = Vec::new();
+    while start > 0 && bytecount_newlines(&tail) <= limit {
+        let read_from = start.saturating_sub(CHUNK);
+        let mut chunk = vec![0; (start - read_from) as usize];
+        file.seek(SeekFrom::Start(read_from))?;
+        file.read_exact(&mut chunk)?;
+        chunk.extend_from_slice(&tail);
+        tail = chunk;
+        start = read_from;
+    }
+
+    let mut body = tail.as_slice();
+    if start > 0 {
+        // Drop the partial entry the scan stopped in the middle of
+        let cut = body.iter().position(|&b| b == b'\n').map_or(body.len(), |i| i + 1);
+        body = &body[cut..];
+    } else if v2 {
+        body = &body[HISTORY_HEADER.len()..];
+    }
+
+    let (entries, damaged) = parse_history(body, v2);
+    if damaged > 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{} unreadable history entries", damaged),
+        ));
+    }
+    let skip = entries.len().saturating_sub(limit);
+    Ok(entries.into_iter().skip(skip).collect())
+}
+
+// Splits history file contents into entries, also counting the lines that
+// aren't valid UTF-8 or (in the V2 format) contain a bad escape.
+fn parse_history(body: &[u8], v2: bool) -> (Vec<String>, usize) {
+    let mut entries = Vec::new();
+    let mut damaged = 0;
+    for line in body.split(|&b| b == b'\n').filter(|line| !line.is_empty()) {
+        let entry = match std::str::from_utf8(line) {
+            Ok(line) if v2 => unescape_history_entry(line),
+            Ok(line) => Some(line.to_string()),
+            Err(_) => None,
+        };
+        match entry {
+            Some(entry) => entries.push(entry),
+            None => damaged += 1,
+        }
+    }
+    (entries, damaged)
+}
+
+// Rewrites a damaged history file with only its readable entries, keeping the
+// original as `<file>.bak`. Returns how many entries were kept and dropped.
+fn recover_history(path: &Path) -> io::Result<(usize, usize)> {
+    let content = fs::read(path)?;
+    let body = content.strip_prefix(HISTORY_HEADER);
+    let (entries, damaged) = parse_history(body.unwrap_or(&content), body.is_some());
+
+    let mut backup = path.as_os_str().to_owned();
+    backup.push(".bak");
+    fs::copy(path, &backup)?;
+
+    let mut cleaned = HISTORY_HEADER.to_vec();
+    for entry in &entries {
+        cleaned.extend_from_slice(escape_history_entry(entry).as_bytes());
+        cleaned.push(b'\n');
+    }
+    fs::write(path, cleaned)?;
+    Ok((entries.len(), damaged))
+}
+
+fn bytecount_newlines(bytes: &[u8]) -> usize {
+    bytes.iter().filter(|&&b| b == b'\n').count()
+}
+
+fn escape_history_entry(entry: &str) -> String {
+    entry.replace('\\', "\\\\").replace('\n', "\\n")
+}
+
+// Only `\n` and `\\` are valid escapes; anything else means the line is damaged
+fn unescape_history_entry(line: &str) -> Option<String> {
+    let mut entry = String::with_capacity(line.len());
+    let mut chars = line.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            entry.push(c);
+            continue;
+        }
+        match chars.next()? {
+            'n' => entry.push('\n'),
+            '\\' => entry.push('\\'),
+            _ => return None,
+        }
+    }
+    Some(entry)
+}
+
+// Appends this session's entries rather than rewriting the file, so ones
+// written meanwhile by other sessions are kept. If that takes the file past
+// `limit` entries, it is then cut down to the most recent `limit`.
+fn append_history(path: &Path, entries: &[String], limit: usize) -> io::Result<()> {
+    if entries.is_empty() {
+        return Ok(());
+    }
+
+    let mut options = fs::OpenOptions::new();
+    options.create(true).append(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let file = options.open(path)?;
+    let empty = file.metadata()?.len() == 0;
+    let mut out = io::BufWriter::new(file);
+    if empty {
+        out.write_all(HISTORY_HEADER)?;
+    }
+    for entry in entries {
+        writeln!(out, "{}", escape_history_entry(entry))?;
+    }
+    out.flush()?;
+    drop(out);
+
+    // A damaged file is left for the next startup to recover
+    let kept = match read_history(path, limit.saturating_add(1)) {
+        Ok(kept) => kept,
+        Err(e) if e.kind() == io::ErrorKind::InvalidData => return Ok(()),
+        Err(e) => return Err(e),
+    };
+    if kept.len() <= limit {
+        return Ok(());
+    }
+    let mut trimmed = HISTORY_HEADER.to_vec();
+    for entry in &kept[1..] {
+        trimmed.extend_from_slice(escape_history_entry(entry).as_bytes());
+        trimmed.push(b'\n');
+    }
+    replace_file(path, &trimmed)
+}
+
+// Keeps only the last `limit` records of the history times sidecar, matching
+// what is left of the history file
+fn trim_history_times(path: &Path, limit: usize) -> io::Result<()> {
+    let content = match fs::read(path) {
+        Ok(content) => content,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e),
+    };
+    let records = bytecount_newlines(&content);
+    if records <= limit {
+        return Ok(());
+    }
+    let mut cut = 0;
+    for _ in 0..records - limit {
+        cut += content[cut..].iter().position(|&b| b == b'\n').map_or(0, |i| i + 1);
+    }
+    replace_file(path, &content[cut..])
+}
+
+// Writes a sibling file and renames it over `path`, so a crash partway
+// through can't leave the original half-written
+fn replace_file(path: &Path, content: &[u8]) -> io::Result<()> {
+    let mut temp = path.as_os_str().to_owned();
+    temp.push(".tmp");
+    let mut options = fs::OpenOptions::new();
+    options.create(true).write(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    options.open(&temp)?.write_all(content)?;
+    fs::rename(&temp, path)
+}
+
+fn history_times_path(history_path: &Path) -> PathBuf {
+    history_path.with_file_name(".ash_history_times")
+}
+
+// The sidecar holds one `<unix-seconds>\t<command>` record per history entry,
+// with backslashes and newlines in the command escaped.
+fn load_history_times(path: &Path) -> Vec<(i64, String)> {
+    let Ok(content) = fs::read(path) else {
+        return Vec::new();
+    };
+    String::from_utf8_lossy(&content)
+        .lines()
+        .filter_map(|line| {
+            let (secs, command) = line.split_once('\t')?;
+            Some((secs.parse().ok()?, command.replace("\\n", "\n").replace("\\\\", "\\")))
+        })
+        .collect()
+}
+
+fn record_history_time(path: &Path, command: &str, now: DateTime<Local>, state: &mut ShellState) {
+    let now = now.timestamp();
+    let escaped = command.replace('\\', "\\\\").replace('\n', "\\n");
+    let written = fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .and_then(|mut file| writeln!(file, "{}\t{}", now, escaped));
+    if let Err(e) = written {
+        eprintln!("Failed to record history time: {}", e);
+    }
+    state.history_times.push((now, command.to_string()));
+}
+
+// Pairs history entries with sidecar records by walking both from the newest
+// end, so entries dropped from history (or recorded before timestamps were
+// kept) don't shift every other timestamp.
+fn match_history_times(entries: &[&String], times: &[(i64, String)]) -> Vec<Option<i64>> {
+    let mut stamps = vec![None; entries.len()];
+    let mut remaining = times.len();
+    for (i, entry) in entries.iter().enumerate().rev() {
+        if let Some(k) = times[..remaining].iter().rposition(|(_, command)| command == *entry) {
+            stamps[i] = Some(times[k].0);
+            remaining = k;
+        }
+    }
+    stamps
+}
+
+// Like `DateTime::format`, but rejects bad specifiers instead of panicking.
+fn format_time<Tz: TimeZone>(time: &DateTime<Tz>, fmt: &str) -> ShellResult<String>
+where
+    Tz::Offset: std::fmt::Display,
+{
+    let items: Vec<Item> = StrftimeItems::new(fmt).collect();
+    if items.contains(&Item::Error) {
+        return Err(ShellError::InvalidArgument(format!("invalid time format: {}", fmt)));
+    }
+    Ok(time.format_with_items(items.into_iter()).to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicUsize;
+
+    // A new, empty directory under the system temp dir, unique to one test
+    pub(crate) fn scratch_dir(name: &str) -> PathBuf {
+        static NEXT: AtomicUsize = AtomicUsize::new(0);
+        let id = NEXT.fetch_add(1, Ordering::SeqCst);
+        let dir = env::temp_dir().join(format!("ash-test-{}-{}-{}", process::id(), id, name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn path_str(path: &Path) -> &str {
+        path.to_str().unwrap()
+    }
+
+    // What a builtin wrote to its output, along with how it finished
+    fn captured(run: impl FnOnce(&mut dyn Write) -> ShellResult<()>) -> (String, ShellResult<()>) {
+        let mut out = Vec::new();
+        let result = run(&mut out);
+        (String::from_utf8(out).unwrap(), result)
+    }
+
+    fn alias_expansion(aliases: &[(&str, &str)], line: &str) -> Vec<String> {
+        let mut state = ShellState::new();
+        for (name, value) in aliases {
+            state.aliases.insert(name.to_string(), value.to_string());
+        }
+        expand_alias(parse_input(line, state.last_status).unwrap(), &state).unwrap().into_iter().map(|word| word.text).collect()
+    }
+
+    #[test]
+    fn alias_expands_through_other_aliases() {
+        let aliases = [("ll", "ls -l"), ("ls", "ls --color")];
+        assert_eq!(alias_expansion(&aliases, "ll /tmp"), ["ls", "--color", "-l", "/tmp"]);
+    }
+
+    #[test]
+    fn alias_that_names_itself_is_applied_once() {
+        assert_eq!(alias_expansion(&[("ls", "ls -a")], "ls dir"), ["ls", "-a", "dir"]);
+    }
+
+    #[test]
+    fn aliases_that_name_each_other_stop_after_one_round() {
+        let aliases = [("a", "b one"), ("b", "a two")];
+        assert_eq!(alias_expansion(&aliases, "a three"), ["a", "two", "one", "three"]);
+    }
+
+    #[test]
+    fn only_the_command_word_is_expanded() {
+        assert_eq!(alias_expansion(&[("x", "y")], "echo x"), ["echo", "x"]);
+    }
+
+    #[test]
+    fn alias_takes_a_quoted_definition() {
+        let mut shell = Shell::new();
+        shell.run_command("alias ll='ls -l'; alias la=ls -a");
+        assert_eq!(shell.state.aliases["ll"], "ls -l");
+        assert_eq!(shell.state.aliases["la"], "ls -a");
+        assert!(matches!(alias(&["=ls"], &mut shell.state, &mut Vec::new()), Err(ShellError::InvalidArgument(_))));
+    }
+
+    #[test]
+    fn alias_lists_and_unalias_removes() {
+        let mut shell = Shell::new();
+        shell.run_command("alias ll='ls -l'; alias g=grep");
+        assert_eq!(shell.run_command("alias").stdout, b"alias g='grep'\nalias ll='ls -l'\n");
+        assert_eq!(shell.run_command("alias ll").stdout, b"alias ll='ls -l'\n");
+        assert_ne!(shell.run_command("alias nope").status, 0);
+        shell.run_command("unalias ll");
+        assert_eq!(shell.run_command("alias").stdout, b"alias g='grep'\n");
+        assert_ne!(shell.run_command("unalias ll").status, 0);
+        shell.run_command("unalias -a");
+        assert!(shell.run_command("alias").stdout.is_empty());
+    }
+
+    #[test]
+    fn aliases_apply_in_every_pipeline_stage() {
+        let mut shell = Shell::new();
+        shell.run_command("alias say=echo; alias pick='grep b'");
+        assert_eq!(shell.run_command("say abc | pick").stdout, b"abc\n");
+    }
+
+    // A directory holding a subdirectory with a file in it, for `rm -r`
+    fn filled_dir(parent: &Path, name: &str) -> PathBuf {
+        let dir = parent.join(name);
+        fs::create_dir_all(dir.join("sub")).unwrap();
+        fs::write(dir.join("sub/file"), "x").unwrap();
+        dir
+    }
+
+    #[test]
+    fn rm_takes_the_recursive_flag_before_or_after_the_path() {
+        let scratch = scratch_dir("rm-r");
+        let state = ShellState::new();
+        let before = filled_dir(&scratch, "before");
+        let after = filled_dir(&scratch, "after");
+        rm(&["-r", path_str(&before)], &state).unwrap();
+        rm(&[path_str(&after), "-r"], &state).unwrap();
+        assert!(!before.exists() && !after.exists());
+    }
+
+    #[test]
+    fn rm_accepts_recursive_in_any_form_and_place() {
+        let scratch = scratch_dir("rm-forms");
+        let state = ShellState::new();
+        let dirs = ["long", "trailing", "bundled", "middle"].map(|name| filled_dir(&scratch, name));
+        let [long, trailing, bundled, middle] = dirs.each_ref().map(|dir| path_str(dir));
+        rm(&["--recursive", long], &state).unwrap();
+        rm(&[trailing, "--recursive"], &state).unwrap();
+        rm(&["-fr", bundled], &state).unwrap();
+        rm(&[middle, "-r", path_str(&scratch.join("missing")), "-f"], &state).unwrap();
+        assert!(dirs.iter().all(|dir| !dir.exists()));
+    }
+
+    #[test]
+    fn rm_with_only_flags_is_missing_its_operand() {
+        let state = ShellState::new();
+        assert!(matches!(rm(&["-r"], &state), Err(ShellError::MissingArguments(_))));
+        assert!(matches!(rm(&["-rf"], &state), Err(ShellError::MissingArguments(_))));
+    }
+
+    #[test]
+    fn rm_treats_everything_after_double_dash_as_a_path() {
+        let state = ShellState::new();
+        // Relative, so it can only have been read as a path
+        assert!(matches!(rm(&["--", "-r"], &state), Err(ShellError::FileNotFound(path)) if path == "-r"));
+        assert!(matches!(rm(&["--"], &state), Err(ShellError::MissingArguments(_))));
+        let file = scratch_dir("rm-dashes").join("-r");
+        fs::write(&file, "").unwrap();
+        rm(&["--", path_str(&file)], &state).unwrap();
+        assert!(!file.exists());
+    }
+
+    #[test]
+    fn grep_searches_past_a_missing_file_when_given_several() {
+        let dir = scratch_dir("grep-files");
+        let one = dir.join("one");
+        let two = dir.join("two");
+        fs::write(&one, "needle\n").unwrap();
+        fs::write(&two, "needle\n").unwrap();
+        let missing = dir.join("missing");
+        let (output, result) = captured(|out| grep(&["needle", path_str(&one), path_str(&missing), path_str(&two)], None, out));
+        result.unwrap();
+        assert_eq!(output, format!("{}: needle\n{}: needle\n", one.display(), two.display()));
+        let missing_only = grep(&["needle", path_str(&missing)], None, &mut Vec::new());
+        assert!(matches!(missing_only, Err(ShellError::FileNotFound(_))));
+        let missing_twice = grep(&["needle", path_str(&missing), path_str(&missing)], None, &mut Vec::new());
+        assert!(missing_twice.is_err());
+    }
+
+    #[test]
+    fn cp_and_mv_put_several_sources_into_a_directory() {
+        let dir = scratch_dir("cp-many");
+        let [a, b, c, d] = ["a", "b", "c", "d"].map(|name| {
+            let path = dir.join(name);
+            fs::write(&path, name).unwrap();
+            path
+        });
+        let dest = dir.join("dest");
+        fs::create_dir(&dest).unwrap();
+        cp(&[path_str(&a), path_str(&b), path_str(&dest)]).unwrap();
+        mv(&[path_str(&c), path_str(&d), path_str(&dest)], &ShellState::new()).unwrap();
+        for name in ["a", "b", "c", "d"] {
+            assert_eq!(fs::read_to_string(dest.join(name)).unwrap(), name);
+        }
+        assert!(a.exists() && b.exists() && !c.exists() && !d.exists());
+    }
+
+    #[test]
+    fn cp_and_mv_refuse_extra_operands_without_a_directory() {
+        let dir = scratch_dir("cp-extra");
+        let [a, b] = ["a", "b"].map(|name| {
+            let path = dir.join(name);
+            fs::write(&path, name).unwrap();
+            path
+        });
+        let missing = dir.join("missing");
+        let args = [path_str(&a), path_str(&b), path_str(&missing)];
+        assert!(matches!(cp(&args), Err(ShellError::InvalidArgument(_))));
+        assert!(matches!(mv(&args, &ShellState::new()), Err(ShellError::InvalidArgument(_))));
+        assert!(a.exists() && b.exists() && !missing.exists());
+    }
+
+    #[test]
+    fn cp_and_mv_need_a_directory_for_a_trailing_slash() {
+        let dir = scratch_dir("cp-slash");
+        let file = dir.join("a.txt");
+        fs::write(&file, "a").unwrap();
+        let missing = format!("{}/", path_str(&dir.join("missing")));
+        assert!(matches!(cp(&[path_str(&file), &missing]), Err(ShellError::InvalidArgument(_))));
+        assert!(matches!(mv(&[path_str(&file), &missing], &ShellState::new()), Err(ShellError::InvalidArgument(_))));
+        assert!(file.exists() && !dir.join("missing").exists());
+    }
+
+    #[test]
+    fn cp_and_mv_with_one_source_rename_or_land_in_a_directory() {
+        let dir = scratch_dir("cp-one");
+        let file = dir.join("a.txt");
+        fs::write(&file, "a").unwrap();
+        let archive = dir.join("archive");
+        fs::create_dir(&archive).unwrap();
+        cp(&[path_str(&file), path_str(&dir.join("b.txt"))]).unwrap();
+        cp(&[path_str(&file), path_str(&archive)]).unwrap();
+        assert_eq!(fs::read_to_string(dir.join("b.txt")).unwrap(), "a");
+        assert_eq!(fs::read_to_string(archive.join("a.txt")).unwrap(), "a");
+
+        let state = ShellState::new();
+        mv(&[path_str(&dir.join("b.txt")), path_str(&dir.join("c.txt"))], &state).unwrap();
+        mv(&[path_str(&dir.join("c.txt")), &format!("{}/", path_str(&archive))], &state).unwrap();
+        assert_eq!(fs::read_to_string(archive.join("c.txt")).unwrap(), "a");
+        assert!(!dir.join("b.txt").exists() && !dir.join("c.txt").exists());
+    }
+
+    #[test]
+    fn cat_reports_a_bad_file_once_it_has_tried_them_all() {
+        let dir = scratch_dir("cat-bad");
+        let file = dir.join("a.txt");
+        fs::write(&file, "a\n").unwrap();
+        let missing = dir.join("missing");
+        let (output, result) = captured(|out| cat(&[path_str(&missing), path_str(&file), path_str(&dir)], None, out));
+        assert!(matches!(result, Err(ShellError::Reported("cat"))));
+        assert_eq!(output, "a\n");
+    }
+
+    #[test]
+    fn io_error_reason_drops_the_os_error_suffix() {
+        let missing = fs::read_to_string("/nonexistent/ash-test").unwrap_err();
+        assert_eq!(io_error_reason(&missing), "No such file or directory");
+    }
+
+    #[test]
+    fn pipeline_splits_on_bars_but_not_escaped_ones() {
+        assert_eq!(parse_pipeline("echo a | grep a").unwrap(), ["echo a ", " grep a"]);
+        assert_eq!(parse_pipeline("echo a \\| b").unwrap(), ["echo a | b"]);
+        assert!(matches!(parse_pipeline("| grep a"), Err(ShellError::MissingArguments(_))));
+        assert!(matches!(parse_pipeline("echo a |"), Err(ShellError::MissingArguments(_))));
+        assert!(matches!(parse_pipeline("echo a || grep a"), Err(ShellError::MissingArguments(_))));
+    }
+
+    #[test]
+    fn grep_without_a_file_searches_piped_input() {
+        let (output, result) = captured(|out| grep(&["b"], Some(b"abc\nxyz\nbob\n"), out));
+        result.unwrap();
+        assert_eq!(output, "abc\nbob\n");
+    }
+
+    #[test]
+    fn cd_into_a_file_is_not_a_directory() {
+        let dir = scratch_dir("cd-file");
+        let file = dir.join("file");
+        fs::write(&file, "").unwrap();
+        let mut shell = Shell::new();
+        let cwd = env::current_dir().unwrap();
+        let (_, result) = captured(|out| cd(&[path_str(&file)], &mut shell, out));
+        assert!(matches!(result, Err(ShellError::NotADirectory(path)) if path == path_str(&file)));
+        let (_, result) = captured(|out| cd(&[path_str(&dir.join("missing"))], &mut shell, out));
+        assert!(matches!(result, Err(ShellError::FileNotFound(_))));
+        assert_eq!(env::current_dir().unwrap(), cwd);
+    }
+
+    #[test]
+    fn multibyte_and_invalid_utf8_names_are_listed() {
+        use std::os::unix::ffi::OsStrExt;
+        let dir = scratch_dir("utf8-names");
+        fs::write(dir.join("café-日本.txt"), "needle\n").unwrap();
+        fs::write(dir.join(std::ffi::OsStr::from_bytes(b"bad\xff")), "").unwrap();
+        let (listed, result) = captured(|out| ls(&[path_str(&dir)], false, out));
+        result.unwrap();
+        let (found, result) = captured(|out| find(&[path_str(&dir)], out));
+        result.unwrap();
+        for output in [listed, found] {
+            assert!(output.contains("café-日本.txt"), "{}", output);
+            assert!(output.contains("bad\u{fffd}"), "{}", output);
+        }
+        let named = dir.join("café-日本.txt");
+        let (output, result) = captured(|out| grep(&["needle", path_str(&named)], None, out));
+        result.unwrap();
+        assert_eq!(output, "needle\n");
+    }
+
+    #[test]
+    fn invalid_utf8_content_passes_through_cat_and_grep() {
+        let file = scratch_dir("utf8-content").join("latin1");
+        fs::write(&file, b"caf\xe9 needle\nplain\n").unwrap();
+        let mut raw = Vec::new();
+        cat(&[path_str(&file)], None, &mut raw).unwrap();
+        assert_eq!(raw, b"caf\xe9 needle\nplain\n");
+        let (output, result) = captured(|out| grep(&["needle", path_str(&file)], None, out));
+        result.unwrap();
+        assert_eq!(output, "caf\u{fffd} needle\n");
+    }
+
+    #[test]
+    fn recursive_grep_treats_a_file_with_a_nul_byte_as_binary() {
+        let dir = scratch_dir("grep-binary");
+        fs::write(dir.join("blob"), b"needle\0\n").unwrap();
+        fs::write(dir.join("text"), "needle\n").unwrap();
+        let (blob, text) = (dir.join("blob"), dir.join("text"));
+        let run = |mode: &str| {
+            let (output, result) = captured(|out| grep(&["-r", mode, "needle", path_str(&dir)], None, out));
+            result.unwrap();
+            output
+        };
+        let text_match = format!("{}: needle\n", text.display());
+        let binary = format!("Binary file {} matches\n", blob.display());
+        assert_eq!(run("--binary-files=binary"), format!("{}{}", binary, text_match));
+        assert_eq!(run("--binary-files=text"), format!("{}: needle\0\n{}", blob.display(), text_match));
+        assert_eq!(run("--binary-files=without-match"), text_match);
+    }
+
+    // One of each ShellError variant but Reported, with its JSON kind
+    fn every_error() -> Vec<(ShellError, &'static str)> {
+        vec![
+            (io::Error::other("disk on fire").into(), "io"),
+            (ShellError::InvalidArgument("-x".into()), "invalid_argument"),
+            (ShellError::MissingArguments("file"), "missing_arguments"),
+            (ShellError::CommandNotFound("nope".into()), "command_not_found"),
+            (ShellError::FileNotFound("a".into()), "file_not_found"),
+            (ShellError::IsDirectory("d".into()), "is_directory"),
+            (ShellError::NotADirectory("f".into()), "not_a_directory"),
+            (ShellError::PermissionDenied("p".into()), "permission_denied"),
+            (ShellError::Unavailable("clipboard".into()), "unavailable"),
+            (ShellError::TimedOut("sleep 5".into(), Duration::from_secs(1)), "timed_out"),
+        ]
+    }
+
+    #[test]
+    fn json_errors_are_one_record_per_line() {
+        for (error, kind) in every_error() {
+            let (message, hint) = describe_error(&error, "rm");
+            let mut errors = Vec::new();
+            report_error(error, "rm", ErrorFormat::Json, &mut errors).unwrap();
+            let text = String::from_utf8(errors).unwrap();
+            assert_eq!(text.lines().count(), 1, "{}", text);
+            let record: serde_json::Value = serde_json::from_str(&text).unwrap();
+            assert_eq!(record["kind"], kind);
+            assert_eq!(record["message"], message.as_str());
+            assert_eq!(record["command"], "rm");
+            assert_eq!(record.get("hint").and_then(|hint| hint.as_str()), hint.as_deref());
+            let usage = matches!(kind, "invalid_argument" | "missing_arguments").then(|| get_command_usage("rm"));
+            assert_eq!(record.get("usage").and_then(|usage| usage.as_str()), usage);
+        }
+    }
+
+    #[test]
+    fn reported_errors_print_nothing_in_json() {
+        let mut errors = Vec::new();
+        report_error(ShellError::Reported("cat"), "cat", ErrorFormat::Json, &mut errors).unwrap();
+        assert!(errors.is_empty());
+    }
+
+    #[test]
+    fn exit_codes_follow_posix_shells() {
+        for (error, kind) in every_error() {
+            let expected = match kind {
+                "command_not_found" => 127,
+                "permission_denied" => 126,
+                "timed_out" => 124,
+                "invalid_argument" | "missing_arguments" => 2,
+                _ => 1,
+            };
+            assert_eq!(error.exit_code(), expected, "{}", kind);
+        }
+        assert_eq!(ShellError::Reported("cat").exit_code(), 1);
+    }
+
+    #[test]
+    fn last_status_takes_the_error_exit_code() {
+        let mut shell = Shell::new();
+        assert_eq!(shell.run_command("ash-test-no-such-command").status, 127);
+        assert_eq!(shell.run_command("mkdir").status, 2);
+        assert_eq!(shell.run_command("false || echo ok").stdout, b"ok\n");
+        assert_eq!(shell.run_command("pwd").status, 0);
+    }
+
+    #[test]
+    fn grep_flags_combine_anywhere_among_the_operands() {
+        let file = scratch_dir("grep-flags").join("words");
+        fs::write(&file, "Needle\nhay\nneedle\n").unwrap();
+        let path = path_str(&file);
+        let run = |args: &[&str]| {
+            let (output, result) = captured(|out| grep(args, None, out));
+            result.unwrap();
+            output
+        };
+        assert_eq!(run(&["-n", "needle", path]), "3: needle\n");
+        assert_eq!(run(&["needle", path, "-in"]), "1: Needle\n3: needle\n");
+        assert_eq!(run(&["-iv", "needle", path]), "hay\n");
+        assert_eq!(run(&["-c", "-i", "needle", path]), "2\n");
+    }
+
+    #[test]
+    fn crlf_and_unterminated_lines_in_grep_cat_and_tail() {
+        let dir = scratch_dir("crlf");
+        let crlf = dir.join("crlf");
+        let open = dir.join("open");
+        fs::write(&crlf, "one\r\ntwo\r\n").unwrap();
+        fs::write(&open, "one\ntwo").unwrap();
+        for file in [&crlf, &open] {
+            let (output, result) = captured(|out| grep(&["-E", "o$", path_str(file)], None, out));
+            result.unwrap();
+            assert_eq!(output, "two\n");
+        }
+        let (output, result) = captured(|out| cat(&["-n", path_str(&crlf)], None, out));
+        result.unwrap();
+        assert_eq!(output, "     1\tone\n     2\ttwo\n");
+        let (output, result) = captured(|out| cat(&["-n", path_str(&open), path_str(&crlf)], None, out));
+        result.unwrap();
+        assert_eq!(output, "     1\tone\n     2\ttwo     3\tone\n     4\ttwo\n");
+        let (output, result) = captured(|out| tail(&["-n", "1", path_str(&open)], None, out));
+        result.unwrap();
+        assert_eq!(output, "two");
+    }
+
+    #[test]
+    fn grep_and_tail_z_use_nul_separated_records() {
+        let file = scratch_dir("grep-z").join("records");
+        fs::write(&file, b"one\ntwo\0three\0four\0").unwrap();
+        let path = path_str(&file);
+        let mut out = Vec::new();
+        grep(&["-z", "o", path], None, &mut out).unwrap();
+        assert_eq!(out, b"one\ntwo\0four\0");
+        let mut out = Vec::new();
+        tail(&["-z", "-n", "2", path], None, &mut out).unwrap();
+        assert_eq!(out, b"three\0four\0");
+
+        let mut shell = Shell::new();
+        let output = shell.run_command(&format!("cat {} | grep -z r | tail -z -n 1 -", path));
+        assert_eq!(output.stdout, b"four\0");
+    }
+
+    #[test]
+    fn grep_follow_links_stops_at_symlink_loops() {
+        use std::os::unix::fs::symlink;
+        let dir = scratch_dir("grep-loop");
+        fs::write(dir.join("a"), "needle\n").unwrap();
+        fs::create_dir(dir.join("sub")).unwrap();
+        fs::write(dir.join("sub/b"), "needle\n").unwrap();
+        symlink(".", dir.join("self")).unwrap();
+        symlink("..", dir.join("sub/up")).unwrap();
+        let (output, result) = captured(|out| grep(&["-rL", "needle", path_str(&dir)], None, out));
+        let root = path_str(&dir);
+        assert_eq!(output, format!("{0}/a: needle\n{0}/sub/b: needle\n", root));
+        result.unwrap();
+    }
+
+    // top.txt, sub/mid.txt and sub/deep/low.txt, each containing "needle"
+    fn nested_tree(name: &str) -> PathBuf {
+        let root = scratch_dir(name);
+        fs::create_dir_all(root.join("sub/deep")).unwrap();
+        for file in ["top.txt", "sub/mid.txt", "sub/deep/low.txt"] {
+            fs::write(root.join(file), "needle\n").unwrap();
+        }
+        root
+    }
+
+    #[test]
+    fn max_depth_one_keeps_to_the_top_level() {
+        let root = nested_tree("max-depth");
+        let (output, result) = captured(|out| grep(&["-r", "--max-depth", "1", "needle", path_str(&root)], None, out));
+        result.unwrap();
+        assert_eq!(output, format!("{}: needle\n", path_str(&root.join("top.txt"))));
+
+        let copy = scratch_dir("max-depth-copy").join("copy");
+        cp(&["-r", "--max-depth", "1", path_str(&root), path_str(&copy)]).unwrap();
+        assert!(copy.join("top.txt").is_file() && copy.join("sub").is_dir());
+        assert_eq!(fs::read_dir(copy.join("sub")).unwrap().count(), 0);
+    }
+    // A directory holding subdirectories of the given sizes, one file each
+    fn sized_dirs(name: &str, sizes: &[(&str, usize)]) -> PathBuf {
+        let root = scratch_dir(name);
+        for (dir, size) in sizes {
+            fs::create_dir(root.join(dir)).unwrap();
+            fs::write(root.join(dir).join("data"), vec![b'x'; *size]).unwrap();
+        }
+        root
+    }
+
+    // du's output as (size, path below the scratch directory) pairs
+    fn du_rows(args: &[&str], base: &Path) -> Vec<(String, String)> {
+        let (output, result) = captured(|out| du(args, out));
+        result.unwrap();
+        output
+            .lines()
+            .map(|line| {
+                let (size, path) = line.split_once('\t').unwrap();
+                (size.to_string(), Path::new(path).strip_prefix(base).unwrap().display().to_string())
+            })
+            .collect()
+    }
+
+    #[test]
+    fn du_sort_orders_by_size_or_by_name() {
+        let root = sized_dirs("du-sort", &[("big", 1000), ("mid", 3000), ("small", 2000)]);
+        let base = root.parent().unwrap();
+        let name = root.file_name().unwrap().to_str().unwrap();
+        let rows = |args: &[&str]| du_rows(&[args, &[path_str(&root)]].concat(), base);
+        let sized = |rows: &[(&str, &str)]| -> Vec<(String, String)> {
+            rows.iter().map(|(size, dir)| (size.to_string(), format!("{}{}", name, dir))).collect()
+        };
+        let walked = sized(&[("1000", "/big"), ("3000", "/mid"), ("2000", "/small"), ("6000", "")]);
+        assert_eq!(rows(&[]), walked);
+        let by_size = sized(&[("6000", ""), ("3000", "/mid"), ("2000", "/small"), ("1000", "/big")]);
+        assert_eq!(rows(&["--sort"]), by_size);
+        assert_eq!(rows(&["--sort=size"]), by_size);
+        let by_name = sized(&[("6000", ""), ("1000", "/big"), ("3000", "/mid"), ("2000", "/small")]);
+        assert_eq!(rows(&["--sort=name"]), by_name);
+        assert!(matches!(captured(|out| du(&["--sort=age"], out)).1, Err(ShellError::InvalidArgument(_))));
+    }
+
+    #[test]
+    fn du_sort_combines_with_summaries_in_human_units() {
+        let root = sized_dirs("du-sort-sh", &[("a", 1536), ("b", 10240), ("c", 3000)]);
+        let dirs = ["a", "b", "c"].map(|dir| root.join(dir));
+        let args = [&["-h", "-s", "--sort"][..], &dirs.each_ref().map(|dir| path_str(dir))].concat();
+        let expected = [("10.0K", "b"), ("2.9K", "c"), ("1.5K", "a")].map(|(size, dir)| (size.to_string(), dir.to_string()));
+        assert_eq!(du_rows(&args, &root), expected);
+    }
+
+    #[test]
+    fn du_summarizes_and_reports_a_file_on_its_own() {
+        let root = sized_dirs("du-summary", &[("a", 100), ("b", 50)]);
+        fs::write(root.join("top"), b"xyz").unwrap();
+        let base = root.parent().unwrap();
+        let name = root.file_name().unwrap().to_str().unwrap().to_string();
+        assert_eq!(du_rows(&["-s", path_str(&root)], base), [("153".to_string(), name.clone())]);
+        assert_eq!(du_rows(&[path_str(&root.join("top"))], base), [("3".to_string(), format!("{}/top", name))]);
+        assert_eq!(human_size(1023), "1023B");
+        assert_eq!(human_size(3 * 1024 * 1024), "3.0M");
+        let missing = root.join("missing");
+        assert!(matches!(captured(|out| du(&[path_str(&missing)], out)).1, Err(ShellError::Reported("du"))));
+    }
+    #[test]
+    fn du_exclude_leaves_matching_subtrees_out_of_the_total() {
+        let root = sized_dirs("du-exclude", &[("keep", 1000), ("target", 5000), ("logs", 300)]);
+        fs::write(root.join("keep/run.log"), vec![b'x'; 200]).unwrap();
+        fs::create_dir(root.join("keep/target")).unwrap();
+        fs::write(root.join("keep/target/data"), vec![b'x'; 4000]).unwrap();
+        let base = root.parent().unwrap();
+        let name = root.file_name().unwrap().to_str().unwrap();
+        let args = ["-s", "--exclude", "target", "--exclude=*.log", "--exclude", "logs", path_str(&root)];
+        assert_eq!(du_rows(&args, base), [("1000".to_string(), name.to_string())]);
+        let rows = du_rows(&["--exclude=target", path_str(&root)], base);
+        assert!(rows.iter().all(|(_, path)| !path.contains("target")));
+        assert_eq!(rows.last().unwrap().0, "1500");
+    }
+
+    // Always 2024-03-05 14:07:09 UTC, a Tuesday
+    struct FixedClock;
+
+    impl Clock for FixedClock {
+        fn now(&self) -> DateTime<Local> {
+            Utc.with_ymd_and_hms(2024, 3, 5, 14, 7, 9).unwrap().with_timezone(&Local)
+        }
+    }
+
+    fn date_output(args: &[&str]) -> String {
+        let (output, result) = captured(|out| date(args, &FixedClock, out));
+        result.unwrap();
+        output
+    }
+
+    #[test]
+    fn date_modes_print_standard_timestamps() {
+        assert_eq!(date_output(&["-u", "--iso-8601"]), "2024-03-05T14:07:09+00:00\n");
+        assert_eq!(date_output(&["-u", "--rfc-3339"]), "2024-03-05 14:07:09+00:00\n");
+        assert_eq!(date_output(&["-u", "--rfc-2822"]), "Tue, 05 Mar 2024 14:07:09 +0000\n");
+        assert_eq!(date_output(&["-u"]), "2024-03-05 14:07:09\n");
+        // Epoch seconds don't depend on the time zone
+        assert_eq!(date_output(&["+%s"]), "1709647629\n");
+        assert_eq!(date_output(&["-u", "-d", "+1 day", "+%F"]), "2024-03-06\n");
+    }
+
+    #[test]
+    fn the_last_date_format_given_wins() {
+        assert_eq!(date_output(&["-u", "--rfc-2822", "--iso-8601"]), "2024-03-05T14:07:09+00:00\n");
+        assert_eq!(date_output(&["-u", "--iso-8601", "+%Y"]), "2024\n");
+        assert_eq!(date_output(&["-u", "-f", "%Y", "+%H"]), "14\n");
+        let local = date_output(&["--iso-8601"]);
+        let offset = Regex::new(r"^2024-03-0[456]T\d\d:\d\d:09[+-]\d\d:\d\d\n$").unwrap();
+        assert!(offset.is_match(&local), "{}", local);
+    }
+
+    #[test]
+    fn head_and_tail_z_count_nul_records_in_every_file() {
+        let dir = scratch_dir("head-tail-z");
+        let (first, second) = (dir.join("first"), dir.join("second"));
+        fs::write(&first, b"a\nb\0c\0d\0").unwrap();
+        fs::write(&second, b"e\0f").unwrap();
+        let (output, result) = captured(|out| head(&["-z", "-n", "2", path_str(&first)], None, out));
+        result.unwrap();
+        assert_eq!(output, "a\nb\0c\0");
+        let (output, result) = captured(|out| tail(&["-z", "-n", "1", path_str(&first), path_str(&second)], None, out));
+        result.unwrap();
+        let expected = format!("==> {} <==\nd\0\n==> {} <==\nf", path_str(&first), path_str(&second));
+        assert_eq!(output, expected);
+    }
+
+    #[test]
+    fn xargs_0_passes_each_nul_separated_path_as_one_argument() {
+        let dir = scratch_dir("xargs-null");
+        let list = dir.join("list");
+        fs::write(&list, "has space.txt\0new\nline.txt\0plain.txt\0").unwrap();
+        let mut shell = Shell::new();
+        let output = shell.run_command(&format!("cat {} | xargs -0 printf [%s]", path_str(&list)));
+        assert_eq!(String::from_utf8(output.stdout).unwrap(), "[has space.txt][new\nline.txt][plain.txt]");
+        // Without -0 the same input splits on the whitespace inside names
+        fs::write(&list, "has space.txt\nnew\nline.txt\n").unwrap();
+        let output = shell.run_command(&format!("cat {} | xargs printf [%s]", path_str(&list)));
+        assert_eq!(String::from_utf8(output.stdout).unwrap(), "[has][space.txt][new][line.txt]");
+    }
+
+    #[test]
+    fn wc_counts_crlf_unterminated_and_nul_records() {
+        assert_eq!(wc_counts(&b"one two\r\nthree\r\n"[..], b'\n').unwrap(), [2, 3, 16]);
+        assert_eq!(wc_counts(&b"one\ntwo"[..], b'\n').unwrap(), [1, 2, 7]);
+        assert_eq!(wc_counts(&b""[..], b'\n').unwrap(), [0, 0, 0]);
+        assert_eq!(wc_counts(&b"a b\0c\nd\0e"[..], b'\0').unwrap(), [2, 3, 9]);
+        let long = format!("{} end\n", "x".repeat(100_000));
+        assert_eq!(wc_counts(long.as_bytes(), b'\n').unwrap(), [1, 2, 100_005]);
+    }
+
+    #[test]
+    fn wc_totals_several_files_and_counts_piped_input() {
+        let dir = scratch_dir("wc");
+        let (crlf, open) = (dir.join("crlf"), dir.join("open"));
+        fs::write(&crlf, "one\r\ntwo\r\n").unwrap();
+        fs::write(&open, "one\ntwo").unwrap();
+        let (output, result) = captured(|out| wc(&[path_str(&crlf), path_str(&open)], None, out));
+        result.unwrap();
+        let expected = format!(" 2  2 10 {}\n 1  2  7 {}\n 3  4 17 total\n", path_str(&crlf), path_str(&open));
+        assert_eq!(output, expected);
+        let (output, result) = captured(|out| wc(&["-lz"], Some(b"x\0y\0"), out));
+        result.unwrap();
+        assert_eq!(output, "2\n");
+        let missing = dir.join("missing");
+        let (output, result) = captured(|out| wc(&["-l", path_str(&open), path_str(&missing)], None, out));
+        assert!(matches!(result, Err(ShellError::Reported("wc"))));
+        assert_eq!(output, format!("1 {}\n", path_str(&open)));
+    }
+
+    #[test]
+    fn find_follow_links_stops_at_symlink_loops() {
+        use std::os::unix::fs::symlink;
+        let dir = scratch_dir("find-loop");
+        fs::write(dir.join("a"), "").unwrap();
+        fs::create_dir(dir.join("sub")).unwrap();
+        symlink(".", dir.join("self")).unwrap();
+        symlink("..", dir.join("sub/up")).unwrap();
+        let (output, result) = captured(|out| find(&["-L", path_str(&dir)], out));
+        let root = path_str(&dir);
+        let expected = [root.to_string(), format!("{}/a", root), format!("{}/self", root), format!("{}/sub", root), format!("{}/sub/up", root)];
+        assert_eq!(output.lines().collect::<Vec<_>>(), expected);
+        assert!(matches!(result, Err(ShellError::Reported("find"))));
+    }
+
+    #[test]
+    fn quotes_and_backslashes_keep_words_together() {
+        let words = |line: &str| -> Vec<String> { parse_input(line, 0).unwrap().into_iter().map(|word| word.text).collect() };
+        assert_eq!(words("echo 'a  b' \"c d\" e\\ f"), ["echo", "a  b", "c d", "e f"]);
+        assert_eq!(words("echo 'it''s' \"$?\" '$?'"), ["echo", "its", "0", "$?"]);
+        assert_eq!(words("echo \\'x \\\\"), ["echo", "'x", "\\"]);
+        assert!(matches!(parse_input("echo 'open", 0), Err(ShellError::InvalidArgument(_))));
+        let mut shell = Shell::new();
+        assert_eq!(shell.run_command("echo 'a | b; c'").stdout, b"a | b; c\n");
+    }
+
+    #[test]
+    fn pipefail_reports_the_rightmost_failing_stage() {
+        let missing = scratch_dir("pipefail").join("missing");
+        let missing = path_str(&missing);
+        let mut shell = Shell::new();
+        assert_eq!(shell.run_command(&format!("cat {} | wc -l", missing)).status, 0);
+        shell.run_command("set -o pipefail");
+        let output = shell.run_command(&format!("cat {} | wc -l", missing));
+        assert_eq!((output.stdout.as_slice(), output.status), (&b"0\n"[..], 1));
+        assert_eq!(shell.run_command("ash-no-such-command | wc -l").status, 127);
+        assert_eq!(shell.run_command(&format!("ash-no-such-command | cat {} | wc -l", missing)).status, 1);
+        assert_eq!(shell.run_command("echo fine | wc -l").status, 0);
+        shell.run_command("set +o pipefail");
+        assert_eq!(shell.run_command(&format!("cat {} | wc -l", missing)).status, 0);
+    }
+
+    fn words(line: &str) -> Vec<String> {
+        parse_input(line, 0).unwrap().into_iter().map(|word| word.text).collect()
+    }
+
+    #[test]
+    fn a_custom_ifs_splits_a_path_like_value() {
+        assert_eq!(split_fields("/usr/bin:/bin:/usr/local/bin", ":"), ["/usr/bin", "/bin", "/usr/local/bin"]);
+        assert_eq!(split_fields("a::b", ":"), ["a", "", "b"]);
+        assert_eq!(split_fields("a : b", ": "), ["a", "b"]);
+        assert_eq!(split_fields("a b:c", ":"), ["a b", "c"]);
+        assert_eq!(split_fields("a:b", ""), ["a:b"]);
+    }
+
+    #[test]
+    fn default_ifs_splits_on_runs_of_whitespace() {
+        assert_eq!(split_fields("  one \t two\nthree ", DEFAULT_IFS), ["", "one", "two", "three", ""]);
+        assert_eq!(split_fields("plain", DEFAULT_IFS), ["plain"]);
+    }
+
+    #[test]
+    fn only_unquoted_expansions_are_split() {
+        env::set_var("ASH_TEST_IFS_WORDS", "one two  three");
+        assert_eq!(words("echo $ASH_TEST_IFS_WORDS"), ["echo", "one", "two", "three"]);
+        assert_eq!(words("echo \"$ASH_TEST_IFS_WORDS\""), ["echo", "one two  three"]);
+        assert_eq!(words("echo x${ASH_TEST_IFS_WORDS}y"), ["echo", "xone", "two", "threey"]);
+    }
+
+    #[test]
+    fn default_and_assign_modifiers_tell_unset_from_empty() {
+        env::remove_var("ASH_TEST_PARAM_UNSET");
+        env::set_var("ASH_TEST_PARAM_EMPTY", "");
+        env::set_var("ASH_TEST_PARAM_SET", "value");
+        assert_eq!(expand_parameter("ASH_TEST_PARAM_UNSET:-d", 0).unwrap(), "d");
+        assert_eq!(expand_parameter("ASH_TEST_PARAM_EMPTY:-d", 0).unwrap(), "d");
+        assert_eq!(expand_parameter("ASH_TEST_PARAM_EMPTY-d", 0).unwrap(), "");
+        assert_eq!(expand_parameter("ASH_TEST_PARAM_SET:-d", 0).unwrap(), "value");
+
+        env::remove_var("ASH_TEST_PARAM_ASSIGN");
+        assert_eq!(expand_parameter("ASH_TEST_PARAM_ASSIGN:=d", 0).unwrap(), "d");
+        assert_eq!(env::var("ASH_TEST_PARAM_ASSIGN").unwrap(), "d");
+        assert_eq!(expand_parameter("ASH_TEST_PARAM_ASSIGN:=other", 0).unwrap(), "d");
+        assert_eq!(expand_parameter("ASH_TEST_PARAM_EMPTY=d", 0).unwrap(), "");
+        assert_eq!(env::var("ASH_TEST_PARAM_EMPTY").unwrap(), "");
+    }
+
+    #[test]
+    fn error_modifier_fails_only_when_missing() {
+        env::remove_var("ASH_TEST_PARAM_REQUIRED");
+        assert!(matches!(
+            expand_parameter("ASH_TEST_PARAM_REQUIRED:?need it", 0),
+            Err(ShellError::InvalidArgument(message)) if message == "ASH_TEST_PARAM_REQUIRED: need it"
+        ));
+        assert!(matches!(
+            expand_parameter("ASH_TEST_PARAM_REQUIRED:?", 0),
+            Err(ShellError::InvalidArgument(message)) if message.ends_with("parameter null or not set")
+        ));
+        env::set_var("ASH_TEST_PARAM_REQUIRED", "");
+        assert!(expand_parameter("ASH_TEST_PARAM_REQUIRED:?msg", 0).is_err());
+        assert_eq!(expand_parameter("ASH_TEST_PARAM_REQUIRED?msg", 0).unwrap(), "");
+    }
+
+    #[test]
+    fn length_counts_characters() {
+        env::set_var("ASH_TEST_PARAM_LENGTH", "héllo");
+        assert_eq!(expand_parameter("#ASH_TEST_PARAM_LENGTH", 0).unwrap(), "5");
+        env::remove_var("ASH_TEST_PARAM_NO_LENGTH");
+        assert_eq!(expand_parameter("#ASH_TEST_PARAM_NO_LENGTH", 0).unwrap(), "0");
+    }
+
+    #[test]
+    fn prefix_and_suffix_modifiers_strip_shortest_or_longest() {
+        env::set_var("ASH_TEST_PARAM_PATH", "/usr/local/lib.tar.gz");
+        assert_eq!(expand_parameter("ASH_TEST_PARAM_PATH#*/", 0).unwrap(), "usr/local/lib.tar.gz");
+        assert_eq!(expand_parameter("ASH_TEST_PARAM_PATH##*/", 0).unwrap(), "lib.tar.gz");
+        assert_eq!(expand_parameter("ASH_TEST_PARAM_PATH%.*", 0).unwrap(), "/usr/local/lib.tar");
+        assert_eq!(expand_parameter("ASH_TEST_PARAM_PATH%%.*", 0).unwrap(), "/usr/local/lib");
+        assert_eq!(expand_parameter("ASH_TEST_PARAM_PATH#nomatch", 0).unwrap(), "/usr/local/lib.tar.gz");
+        env::remove_var("ASH_TEST_PARAM_NO_PATH");
+        assert_eq!(expand_parameter("ASH_TEST_PARAM_NO_PATH%.*", 0).unwrap(), "");
+    }
+
+    #[test]
+    fn substitution_replaces_first_or_every_match() {
+        env::set_var("ASH_TEST_PARAM_SUB", "a-b-c");
+        assert_eq!(expand_parameter("ASH_TEST_PARAM_SUB/-/+", 0).unwrap(), "a+b-c");
+        assert_eq!(expand_parameter("ASH_TEST_PARAM_SUB//-/+", 0).unwrap(), "a+b+c");
+        assert_eq!(expand_parameter("ASH_TEST_PARAM_SUB/-", 0).unwrap(), "ab-c");
+        assert_eq!(expand_parameter("ASH_TEST_PARAM_SUB/x/y", 0).unwrap(), "a-b-c");
+        assert!(matches!(expand_parameter("ASH_TEST_PARAM_SUB:/x/y", 0), Err(ShellError::InvalidArgument(_))));
+    }
+
+    #[test]
+    fn require_version_passes_at_or_below_the_current_version() {
+        for required in ["0.2.0", "0.2", "0.1.9", "0.2.3"] {
+            require_version(&[required], "0.2.3").unwrap();
+        }
+    }
+
+    #[test]
+    fn require_version_fails_above_the_current_version() {
+        assert!(matches!(
+            require_version(&["0.3.0"], "0.2.3"),
+            Err(ShellError::Unavailable(message)) if message == "ASH 0.3.0 or later is required; this is ASH 0.2.3"
+        ));
+        assert!(matches!(require_version(&["0.2.4"], "0.2.3"), Err(ShellError::Unavailable(_))));
+        assert_eq!(require_version(&["1.0"], "0.2.3").unwrap_err().exit_code(), 1);
+        assert!(matches!(require_version(&["soon"], "0.2.3"), Err(ShellError::InvalidArgument(_))));
+        assert!(matches!(require_version(&[], "0.2.3"), Err(ShellError::MissingArguments(_))));
+    }
+
+    #[test]
+    fn ln_creates_links_that_read_back() {
+        let dir = scratch_dir("ln");
+        let target = dir.join("target");
+        fs::write(&target, "shared").unwrap();
+
+        let hard = dir.join("hard");
+        ln(&[path_str(&target), path_str(&hard)]).unwrap();
+        assert_eq!(fs::read_to_string(&hard).unwrap(), "shared");
+        fs::write(&target, "changed").unwrap();
+        assert_eq!(fs::read_to_string(&hard).unwrap(), "changed");
+
+        let soft = dir.join("soft");
+        ln(&["-s", "target", path_str(&soft)]).unwrap();
+        assert_eq!(fs::read_link(&soft).unwrap(), Path::new("target"));
+        assert_eq!(fs::read_to_string(&soft).unwrap(), "changed");
+
+        let dangling = dir.join("dangling");
+        ln(&["-s", "nowhere", path_str(&dangling)]).unwrap();
+        assert_eq!(fs::read_link(&dangling).unwrap(), Path::new("nowhere"));
+    }
+
+    #[test]
+    fn ln_refuses_an_existing_name_and_a_missing_hard_link_target() {
+        let dir = scratch_dir("ln-errors");
+        let target = dir.join("target");
+        fs::write(&target, "keep").unwrap();
+        let taken = dir.join("taken");
+        fs::write(&taken, "mine").unwrap();
+        assert!(matches!(
+            ln(&[path_str(&target), path_str(&taken)]),
+            Err(ShellError::InvalidArgument(message)) if message.ends_with("already exists")
+        ));
+        assert!(matches!(ln(&["-s", path_str(&target), path_str(&taken)]), Err(ShellError::InvalidArgument(_))));
+        assert_eq!(fs::read_to_string(&taken).unwrap(), "mine");
+
+        let missing = dir.join("missing");
+        let link = dir.join("link");
+        assert!(matches!(ln(&[path_str(&missing), path_str(&link)]), Err(ShellError::FileNotFound(_))));
+        assert!(fs::symlink_metadata(&link).is_err());
+    }
+
+    #[test]
+    fn nul_separated_names_survive_grep_sort_and_wc() {
+        let dir = scratch_dir("nul-pipeline");
+        for name in ["b\nc.txt", "a.txt", "skip.log"] {
+            fs::write(dir.join(name), "").unwrap();
+        }
+        let root = path_str(&dir);
+        let mut shell = Shell::new();
+        let output = shell.run_command(&format!("find {} -type f -print0 | grep -z txt | sort -z", root));
+        assert_eq!(output.stdout, format!("{0}/a.txt\0{0}/b\nc.txt\0", root).into_bytes());
+        let output = shell.run_command(&format!("find {} -type f -print0 | grep -z txt | wc -lz", root));
+        assert_eq!(output.stdout, b"2\n");
+    }
+
+    #[test]
+    fn sort_uniq_and_nl_filter_lines_or_nul_records() {
+        fn filter<F>(run: F, args: &[&str], input: &[u8]) -> Vec<u8>
+        where
+            F: Fn(&[&str], Option<&[u8]>, &mut dyn Write) -> ShellResult<()>,
+        {
+            let mut out = Vec::new();
+            run(args, Some(input), &mut out).unwrap();
+            out
+        }
+        assert_eq!(filter(sort, &["-n"], b"10 x\n9 y\nz\n-1 w\n"), b"z\n-1 w\n9 y\n10 x\n");
+        assert_eq!(filter(sort, &["-rz"], b"a\nb\0c\0"), b"c\0a\nb\0");
+        assert_eq!(filter(uniq, &["-c"], b"a\na\nb\na\n"), b"      2 a\n      1 b\n      1 a\n");
+        assert_eq!(filter(uniq, &["-z"], b"x\0x\0y"), b"x\0y\0");
+        assert_eq!(filter(nl, &[], b"one\n\ntwo\n"), b"     1\tone\n       \n     2\ttwo\n");
+        assert_eq!(filter(nl, &["-z"], b"a\nb\0c\0"), b"     1\ta\nb\0     2\tc\0");
+    }
+
+    #[test]
+    fn du_max_depth_limits_rows_not_totals_and_l_follows_links() {
+        use std::os::unix::fs::symlink;
+        let root = sized_dirs("du-depth", &[("a", 100)]);
+        fs::create_dir(root.join("a/deep")).unwrap();
+        fs::write(root.join("a/deep/data"), vec![b'x'; 50]).unwrap();
+        let base = root.parent().unwrap();
+        let name = root.file_name().unwrap().to_str().unwrap();
+        let rows = du_rows(&["--max-depth", "1", path_str(&root)], base);
+        assert_eq!(rows, [("150".to_string(), format!("{}/a", name)), ("150".to_string(), name.to_string())]);
+        symlink(root.join("a/data"), root.join("link")).unwrap();
+        assert_eq!(du_rows(&["-s", path_str(&root)], base)[0].0, "150");
+        assert_eq!(du_rows(&["-sL", path_str(&root)], base)[0].0, "250");
+        assert_eq!(du_rows(&["-L", "-P", "-s", path_str(&root)], base)[0].0, "150");
+    }
+
+    #[test]
+    fn cat_reads_piped_input_for_a_dash_and_numbers_across_it() {
+        let file = scratch_dir("cat-dash").join("file");
+        fs::write(&file, "a\nb\n").unwrap();
+        let (output, result) = captured(|out| cat(&["-n", path_str(&file), "-"], Some(b"c\n"), out));
+        result.unwrap();
+        assert_eq!(output, "     1\ta\n     2\tb\n     3\tc\n");
+        let (output, result) = captured(|out| cat(&[], Some(b"piped"), out));
+        result.unwrap();
+        assert_eq!(output, "piped");
+    }
+
+    #[test]
+    fn edit_distance_counts_single_character_edits() {
+        assert_eq!(edit_distance("grep", "grep"), 0);
+        assert_eq!(edit_distance("grp", "grep"), 1);
+        assert_eq!(edit_distance("grpe", "grep"), 2);
+        assert_eq!(edit_distance("", "ls"), 2);
+        assert_eq!(edit_distance("kitten", "sitting"), 3);
+    }
+
+    #[test]
+    fn typos_suggest_the_closest_builtin() {
+        assert_eq!(closest_builtin("grpe"), Some("grep"));
+        assert_eq!(closest_builtin("ecoh"), Some("echo"));
+        assert_eq!(closest_builtin("mkdri"), Some("mkdir"));
+        assert_eq!(closest_builtin("pwdd"), Some("pwd"));
+    }
+
+    #[test]
+    fn far_off_or_tiny_names_get_no_suggestion() {
+        assert_eq!(closest_builtin("qz"), None);
+        assert_eq!(closest_builtin("x"), None);
+        assert_eq!(closest_builtin("kubectl"), None);
+        assert_eq!(closest_builtin("somethingelse"), None);
+    }
+    #[test]
+    fn find_skips_gitignored_paths_inside_a_repo() {
+        let root = scratch_dir("find-gitignore");
+        fs::create_dir_all(root.join(".git")).unwrap();
+        fs::create_dir_all(root.join("src")).unwrap();
+        fs::create_dir_all(root.join("target")).unwrap();
+        fs::write(root.join(".gitignore"), "target/\n").unwrap();
+        fs::write(root.join("src/.gitignore"), "*.log\n").unwrap();
+        for file in ["src/main.rs", "src/debug.log", "target/out"] {
+            fs::write(root.join(file), "").unwrap();
+        }
+        let dir = path_str(&root);
+
+        let (listed, result) = captured(|out| find(&[dir, "-type", "f"], out));
+        result.unwrap();
+        let names: Vec<&str> = listed.lines().map(|line| line.strip_prefix(dir).unwrap()).collect();
+        assert_eq!(names, ["/.gitignore", "/src/.gitignore", "/src/main.rs"]);
+
+        let (listed, result) = captured(|out| find(&[dir, "-type", "f", "--no-ignore"], out));
+        result.unwrap();
+        assert_eq!(listed.lines().count(), 5);
+    }
+
+    #[test]
+    fn sourced_lines_run_as_full_command_lines() {
+        let dir = scratch_dir("source");
+        let [a, b, c] = ["a", "b", "c"].map(|name| dir.join(name));
+        let rc = dir.join("rc");
+        let line = format!("touch {}; false || touch {} && touch {}\n", path_str(&a), path_str(&b), path_str(&c));
+        fs::write(&rc, line).unwrap();
+        let mut shell = Shell::new();
+        shell.state.last_status = 3;
+        source_file(&rc, &mut shell);
+        assert!(a.exists() && b.exists() && c.exists());
+        assert_eq!(shell.state.last_status, 3);
+    }
+
+    struct Shout;
+
+    impl Command for Shout {
+        fn name(&self) -> &str {
+            "shout"
+        }
+
+        fn usage(&self) -> &str {
+            "shout [word...]"
+        }
+
+        fn description(&self) -> &str {
+            "Print the arguments, or piped input, in capitals"
+        }
+
+        fn run(&self, _: &mut Shell, args: &[&str], io: &mut Streams) -> ShellResult<()> {
+            let text = match io.input() {
+                Some(input) => String::from_utf8_lossy(input).into_owned(),
+                None => format!("{}\n", args.join(" ")),
+            };
+            io.output().write_all(text.to_uppercase().as_bytes())?;
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn registered_commands_run_like_builtins() {
+        let mut shell = Shell::new();
+        shell.register(Box::new(Shout));
+        let output = shell.run_command("shout hello world");
+        assert_eq!((output.stdout.as_slice(), output.status), (&b"HELLO WORLD\n"[..], 0));
+        let output = shell.run_command("echo piped | shout && shout again");
+        assert_eq!(output.stdout, b"PIPED\nAGAIN\n");
+    }
+
+    #[test]
+    fn wc_reads_piped_input_for_a_dash() {
+        let file = scratch_dir("wc-dash").join("file");
+        fs::write(&file, "a\nb\n").unwrap();
+        let (output, result) = captured(|out| wc(&["-l", "-", path_str(&file)], Some(b"x\ny\nz\n"), out));
+        result.unwrap();
+        assert_eq!(output, format!("3 -\n2 {}\n5 total\n", path_str(&file)));
+        let (output, result) = captured(|out| wc(&["-l"], Some(b"x\ny\n"), out));
+        result.unwrap();
+        assert_eq!(output, "2\n");
+    }
+
+    #[test]
+    fn cp_r_copies_symlinks_as_links_unless_told_to_follow() {
+        let dir = scratch_dir("cp-links");
+        let real = dir.join("real");
+        fs::create_dir(&real).unwrap();
+        fs::write(real.join("file"), "x").unwrap();
+        let tree = dir.join("tree");
+        fs::create_dir(&tree).unwrap();
+        symlink("../real", tree.join("link")).unwrap();
+
+        let copy = dir.join("copy");
+        cp(&["-r", path_str(&tree), path_str(&copy)]).unwrap();
+        assert_eq!(fs::read_link(copy.join("link")).unwrap(), Path::new("../real"));
+
+        let followed = dir.join("followed");
+        cp(&["-rL", path_str(&tree), path_str(&followed)]).unwrap();
+        assert!(!fs::symlink_metadata(followed.join("link")).unwrap().file_type().is_symlink());
+        assert_eq!(fs::read_to_string(followed.join("link/file")).unwrap(), "x");
+
+        // A link named on the command line is copied as a link too
+        let top = dir.join("top");
+        cp(&["-r", path_str(&tree.join("link")), path_str(&top)]).unwrap();
+        assert_eq!(fs::read_link(&top).unwrap(), Path::new("../real"));
+        assert!(matches!(cp(&[path_str(&tree.join("link")), path_str(&dir.join("plain"))]), Err(ShellError::IsDirectory(_))));
+    }
+
+    #[test]
+    fn a_trailing_ampersand_is_split_off_its_word() {
+        assert_eq!(words("sleep 10&"), ["sleep", "10", "&"]);
+        assert_eq!(words("sleep 10 &"), ["sleep", "10", "&"]);
+        assert_eq!(words("sleep&  "), ["sleep", "&"]);
+        assert_eq!(words("echo a&b"), ["echo", "a&b"]);
+        assert_eq!(words("echo 'a&'"), ["echo", "a&"]);
+    }
+
+    #[test]
+    fn an_escaped_trailing_ampersand_stays_in_its_word() {
+        let mut shell = Shell::new();
+        assert_eq!(shell.run_command("echo a\\&").stdout, b"a&\n");
+        assert_eq!(shell.run_command("echo a\\& b").stdout, b"a& b\n");
+    }
+
+    #[test]
+    fn an_injected_clock_drives_date_and_cal() {
+        let mut shell = Shell::new();
+        shell.set_clock(FixedClock);
+        assert_eq!(shell.run_command("date -u +%FT%T").stdout, b"2024-03-05T14:07:09\n");
+        let output = String::from_utf8(shell.run_command("cal").stdout).unwrap();
+        assert_eq!(output.lines().next().unwrap().trim(), "March 2024");
+        let output = String::from_utf8(shell.run_command("cal -y").stdout).unwrap();
+        assert_eq!(output.lines().next().unwrap().trim(), "2024");
+
+        let due = FixedClock.now() + TimeDelta::hours(1);
+        let output = String::from_utf8(shell.run_command("at +1h echo later").stdout).unwrap();
+        assert_eq!(output, format!("job 1 at {}\n", due.format("%Y-%m-%d %H:%M:%S")));
+        shell.run_command("at -r 1");
+
+        let log = scratch_dir("clock-audit").join("audit.jsonl");
+        shell.state.audit_log = Some(io::BufWriter::new(fs::File::create(&log).unwrap()));
+        shell.run_command("echo logged");
+        let record: serde_json::Value = serde_json::from_str(fs::read_to_string(&log).unwrap().trim()).unwrap();
+        assert_eq!(record["timestamp"], FixedClock.now().to_rfc3339());
+    }
+
+    #[test]
+    fn grep_fails_without_a_match_in_every_mode() {
+        let file = scratch_dir("grep-status").join("file");
+        fs::write(&file, "alpha\nbeta\n").unwrap();
+        let file = path_str(&file);
+        let modes: [&[&str]; 6] = [&[], &["-q"], &["-c"], &["-n"], &["-o"], &["--total"]];
+        for mode in modes {
+            let (_, found) = captured(|out| grep(&[mode, &["beta", file]].concat(), None, out));
+            assert!(found.is_ok(), "{:?}", mode);
+            let (_, missing) = captured(|out| grep(&[mode, &["gamma", file]].concat(), None, out));
+            assert!(matches!(missing, Err(ShellError::Reported("grep"))), "{:?}", mode);
+        }
+        // -c still prints its zero
+        assert_eq!(captured(|out| grep(&["-c", "gamma", file], None, out)).0, "0\n");
+        let mut shell = Shell::new();
+        let output = shell.run_command(&format!("grep gamma {} || echo none", file));
+        assert_eq!((output.stdout.as_slice(), output.status, output.stderr.is_empty()), (&b"none\n"[..], 0, true));
+        assert_eq!(shell.run_command(&format!("grep gamma {}", file)).status, 1);
+        assert_eq!(shell.run_command(&format!("grep -v gamma {}", file)).status, 0);
+    }
+
+    fn job(state: &mut ShellState, command: &str, args: &[&str], finished: bool) {
+        let mut child = process::Command::new(command).args(args).spawn().unwrap();
+        if finished {
+            child.wait().unwrap();
+        }
+        let id = state.next_job_id;
+        state.next_job_id += 1;
+        let command = std::iter::once(command).chain(args.iter().copied()).collect::<Vec<_>>().join(" ");
+        state.jobs.push(Job { id, command, child });
+    }
+
+    #[test]
+    fn finished_jobs_are_reported_once() {
+        let mut state = ShellState::new();
+        job(&mut state, "true", &[], true);
+        job(&mut state, "sleep", &["30"], false);
+        job(&mut state, "sh", &["-c", "exit 3"], true);
+        let mut out = Vec::new();
+        report_finished_jobs(&mut state, &mut out);
+        assert_eq!(String::from_utf8(out).unwrap(), "[1]+ Done       true\n[3]+ Exit 3     sh -c exit 3\n");
+        let mut out = Vec::new();
+        report_finished_jobs(&mut state, &mut out);
+        assert!(out.is_empty());
+        let (output, result) = captured(|out| jobs(&[], &mut state, out));
+        result.unwrap();
+        assert_eq!(output, "[2]+ Running    sleep 30\n");
+        state.jobs[0].child.kill().unwrap();
+    }
+
+    #[test]
+    fn every_builtin_usage_starts_with_its_name() {
+        for builtin in BUILTINS {
+            assert!(builtin.usage.split_whitespace().next() == Some(builtin.name), "{}: {:?}", builtin.name, builtin.usage);
+        }
+    }
+
+    #[test]
+    fn touch_preserves_content_and_updates_the_time() {
+        let dir = scratch_dir("touch");
+        let file = dir.join("notes.txt");
+        fs::write(&file, "keep me\n").unwrap();
+        let old = std::time::SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(1_000_000);
+        fs::File::options().write(true).open(&file).unwrap().set_modified(old).unwrap();
+
+        touch(&[path_str(&file)]).unwrap();
+        assert_eq!(fs::read_to_string(&file).unwrap(), "keep me\n");
+        assert!(fs::metadata(&file).unwrap().modified().unwrap() > old);
+
+        let missing = dir.join("missing");
+        touch(&["-c", path_str(&missing)]).unwrap();
+        assert!(!missing.exists());
+        touch(&[path_str(&missing)]).unwrap();
+        assert_eq!(fs::read(&missing).unwrap(), b"");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn touch_updates_a_read_only_file() {
+        use std::os::unix::fs::PermissionsExt;
+        let dir = scratch_dir("touch-read-only");
+        let file = dir.join("locked");
+        fs::write(&file, "locked content").unwrap();
+        let old = std::time::SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(1_000_000);
+        fs::File::options().write(true).open(&file).unwrap().set_modified(old).unwrap();
+        fs::set_permissions(&file, fs::Permissions::from_mode(0o444)).unwrap();
+
+        touch(&[path_str(&file)]).unwrap();
+        assert_eq!(fs::read_to_string(&file).unwrap(), "locked content");
+        assert!(fs::metadata(&file).unwrap().modified().unwrap() > old);
+    }
+
+    #[test]
+    fn cat_of_a_directory_is_an_error() {
+        let dir = scratch_dir("cat-dir");
+        let (output, result) = captured(|out| cat(&[path_str(&dir)], None, out));
+        assert_eq!(output, "");
+        assert!(matches!(result, Err(ShellError::IsDirectory(path)) if path == path_str(&dir)));
+        assert!(matches!(cat(&[path_str(&dir.join("missing"))], None, &mut io::sink()), Err(ShellError::FileNotFound(_))));
+
+        let file = dir.join("file");
+        fs::write(&file, "text\n").unwrap();
+        let (output, result) = captured(|out| cat(&[path_str(&dir), path_str(&file)], None, out));
+        assert_eq!(output, "text\n");
+        assert!(matches!(result, Err(ShellError::Reported("cat"))));
+    }
+
+    #[test]
+    fn timeout_keeps_the_status_and_pipes_of_an_external_command() {
+        let mut shell = Shell::new();
+        shell.run_command("timeout 5 /bin/false");
+        assert_eq!(shell.state.last_status, 1);
+
+        let result = shell.run_command("timeout 5 /bin/echo hi | wc -c");
+        assert_eq!(String::from_utf8_lossy(&result.stdout).trim(), "3");
+        assert_eq!(shell.state.last_status, 0);
+
+        let result = shell.run_command("echo piped | timeout 5 /bin/cat");
+        assert_eq!(String::from_utf8_lossy(&result.stdout), "piped\n");
+
+        shell.run_command("timeout 0.1 /bin/sleep 5");
+        assert_eq!(shell.state.last_status, 124);
+    }
+
+    #[test]
+    fn print_notification_writes_to_the_given_output() {
+        let (output, result) = captured(|out| print_notification("build", "done in 3s", out));
+        assert!(result.is_ok());
+        assert_eq!(output, "build: done in 3s\n");
+        let (output, _) = captured(|out| print_notification("ping", "", out));
+        assert_eq!(output, "ping\n");
+    }
+
+    #[test]
+    fn redirections_may_come_before_the_command() {
+        let dir = scratch_dir("leading-redirect");
+        let out = dir.join("out");
+        let file = path_str(&out);
+        let mut shell = Shell::new();
+        shell.run_command(&format!("> {} echo first", file));
+        assert_eq!(fs::read_to_string(&out).unwrap(), "first\n");
+        shell.run_command(&format!(">>{} echo second", file));
+        let result = shell.run_command(&format!("< {} cat", file));
+        assert_eq!(String::from_utf8_lossy(&result.stdout), "first\nsecond\n");
+    }
+
+    #[test]
+    fn a_shell_without_an_editor_still_runs_editor_commands() {
+        let mut shell = Shell::without_editor();
+        assert_eq!(shell.run_command("echo detached").stdout, b"detached\n");
+        assert_eq!(shell.run_command("history").stdout, b"No command history available\n");
+        for line in ["set -o vi", "bind '\"\\C-x\": clear-screen'", "bind -r '\\C-x'", "complete -W 'a b' tool"] {
+            assert_eq!(shell.run_command(line).status, 0, "{}", line);
+        }
+    }
+}
@@ -0,0 +1,193 @@
+// The Ctrl-T file picker: a full-screen list of the paths under the current
+// directory, narrowed by fuzzy matching as you type, whose choice is
+// inserted at the cursor.
+
+use std::io::{self, Write};
+use std::path::Path;
+
+use rustyline::{Cmd, ConditionalEventHandler, Event, EventContext, RepeatCount};
+
+use crate::layout;
+use crate::load_parent_gitignores;
+use crate::walk::Walk;
+
+// Walking a huge tree on every Ctrl-T would stall the prompt
+const MAX_CANDIDATES: usize = 50_000;
+
+pub(crate) struct FilePicker;
+
+impl ConditionalEventHandler for FilePicker {
+    fn handle(&self, _: &Event, _: RepeatCount, _: bool, _: &EventContext) -> Option<Cmd> {
+        let candidates = candidates();
+        // The picker drew over the whole screen, so the prompt needs
+        // redrawing whether or not anything was picked
+        Some(match pick(&candidates) {
+            Some(path) => Cmd::Insert(1, format!("{} ", path)),
+            None => Cmd::Repaint,
+        })
+    }
+}
+
+// Paths below the current directory, skipping what .gitignore excludes
+fn candidates() -> Vec<String> {
+    let root = Path::new(".");
+    Walk::new(root)
+        .ignores(Some(load_parent_gitignores(root)))
+        .flatten()
+        .filter(|entry| entry.depth > 0)
+        .take(MAX_CANDIDATES)
+        .map(|entry| {
+            let path = entry.path.strip_prefix(root).unwrap_or(&entry.path).display().to_string();
+            if entry.is_dir { format!("{}/", path) } else { path }
+        })
+        .collect()
+}
+
+// How well `query` matches `candidate`, if its characters appear there in
+// order (ignoring case). Lower is better: matches packed close together
+// beat scattered ones, then shorter paths beat longer ones.
+fn score(query: &str, candidate: &str) -> Option<(usize, usize)> {
+    let mut chars = candidate.char_indices();
+    let mut first = None;
+    let mut last = 0;
+    for wanted in query.chars().flat_map(char::to_lowercase) {
+        let (i, _) = chars.find(|(_, c)| c.to_lowercase().eq(std::iter::once(wanted)))?;
+        first.get_or_insert(i);
+        last = i;
+    }
+    Some((last - first.unwrap_or(0), candidate.len()))
+}
+
+fn matches<'a>(query: &str, candidates: &'a [String]) -> Vec<&'a str> {
+    let mut scored: Vec<_> = candidates
+        .iter()
+        .filter_map(|candidate| score(query, candidate).map(|score| (score, candidate.as_str())))
+        .collect();
+    scored.sort();
+    scored.into_iter().map(|(_, candidate)| candidate).collect()
+}
+
+enum Key {
+    Char(char),
+    Backspace,
+    Up,
+    Down,
+    Enter,
+    Cancel,
+    Other,
+}
+
+// Runs the picker on the alternate screen until Enter picks the selected
+// path or Esc/Ctrl-C/Ctrl-G gives up. The line editor already has the
+// terminal in raw mode, so keys arrive one at a time.
+fn pick(candidates: &[String]) -> Option<String> {
+    let mut screen = io::stderr();
+    let _ = write!(screen, "\x1b[?1049h");
+    let mut query = String::new();
+    let mut selected = 0;
+    let picked = loop {
+        let found = matches(&query, candidates);
+        selected = selected.min(found.len().saturating_sub(1));
+        if draw(&mut screen, &query, &found, candidates.len(), selected).is_err() {
+            break None;
+        }
+        match read_key() {
+            Key::Char(c) => {
+                query.push(c);
+                selected = 0;
+            }
+            Key::Backspace => {
+                query.pop();
+                selected = 0;
+            }
+            Key::Up => selected = selected.saturating_sub(1),
+            Key::Down => selected += 1,
+            Key::Enter => break found.get(selected).map(|path| path.to_string()),
+            Key::Cancel => break None,
+            Key::Other => {}
+        }
+    };
+    let _ = write!(screen, "\x1b[?1049l");
+    let _ = screen.flush();
+    picked
+}
+
+fn draw(screen: &mut dyn Write, query: &str, found: &[&str], total: usize, selected: usize) -> io::Result<()> {
+    let width = layout::terminal_width();
+    let rows = layout::terminal_height().saturating_sub(2).max(1);
+    // Keep the selection on screen once it moves past the first page
+    let top = selected.saturating_sub(rows - 1);
+    write!(screen, "\x1b[H\x1b[2J")?;
+    for (i, path) in found.iter().enumerate().skip(top).take(rows) {
+        let path: String = path.chars().take(width.saturating_sub(2)).collect();
+        if i == selected {
+            write!(screen, "\x1b[7m> {}\x1b[0m\r\n", path)?;
+        } else {
+            write!(screen, "  {}\r\n", path)?;
+        }
+    }
+    write!(screen, "\x1b[{};1H  {}/{}\r\n> {}", rows + 1, found.len(), total, query)?;
+    screen.flush()
+}
+
+#[cfg(unix)]
+fn read_key() -> Key {
+    match read_byte(None) {
+        Some(b'\r' | b'\n') => Key::Enter,
+        Some(0x7f | 0x08) => Key::Backspace,
+        Some(0x03 | 0x07) | None => Key::Cancel,
+        Some(0x0e) => Key::Down,
+        Some(0x10) => Key::Up,
+        // A lone Esc cancels; Esc [ A and Esc [ B are the arrow keys
+        Some(0x1b) => match read_byte(Some(50)) {
+            None => Key::Cancel,
+            Some(b'[' | b'O') => match read_byte(Some(50)) {
+                Some(b'A') => Key::Up,
+                Some(b'B') => Key::Down,
+                _ => Key::Other,
+            },
+            Some(_) => Key::Other,
+        },
+        Some(first) => read_char(first).map_or(Key::Other, Key::Char),
+    }
+}
+
+// Decodes a UTF-8 character from its first byte and the ones after it;
+// control characters aren't part of a query
+#[cfg(unix)]
+fn read_char(first: u8) -> Option<char> {
+    let len = match first {
+        0x20..=0x7e => 1,
+        0xc0..=0xdf => 2,
+        0xe0..=0xef => 3,
+        0xf0..=0xf7 => 4,
+        _ => return None,
+    };
+    let mut bytes = vec![first];
+    for _ in 1..len {
+        bytes.push(read_byte(Some(50))?);
+    }
+    std::str::from_utf8(&bytes).ok()?.chars().next()
+}
+
+// Reads straight from the terminal rather than through std's buffered
+// stdin, which could hold on to keys meant for the line editor. With a
+// timeout (in milliseconds), gives up if nothing arrives in time.
+#[cfg(unix)]
+fn read_byte(timeout: Option<i32>) -> Option<u8> {
+    if let Some(timeout) = timeout {
+        let mut poll = libc::pollfd { fd: libc::STDIN_FILENO, events: libc::POLLIN, revents: 0 };
+        if unsafe { libc::poll(&mut poll, 1, timeout) } <= 0 {
+            return None;
+        }
+    }
+    let mut byte = 0u8;
+    let read = unsafe { libc::read(libc::STDIN_FILENO, &mut byte as *mut u8 as *mut libc::c_void, 1) };
+    (read == 1).then_some(byte)
+}
+
+// Without raw terminal reads there is no way to drive the picker
+#[cfg(not(unix))]
+fn read_key() -> Key {
+    Key::Cancel
+}
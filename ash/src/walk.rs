@@ -0,0 +1,274 @@
+// Depth-first directory traversal shared by the commands that recurse into
+// trees, so symlink policy, loop detection, depth limits and .gitignore
+// handling behave the same everywhere.
+
+use std::collections::HashSet;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::{gitignore_stack_matches, Gitignore, ShellError, ShellResult};
+
+pub(crate) struct WalkEntry {
+    pub(crate) path: PathBuf,
+    // The file name, decoded lossily
+    pub(crate) name: String,
+    // 0 for the starting point, 1 for what it directly contains, and so on
+    pub(crate) depth: usize,
+    // Whether this is a directory the walk descends into; a symlink counts
+    // only when links are followed
+    pub(crate) is_dir: bool,
+    pub(crate) is_symlink: bool,
+}
+
+// Yields the starting point, then everything under it in name order, each
+// directory before its contents. Errors reading one directory are yielded in
+// its place and the walk carries on with the rest.
+pub(crate) struct Walk<'a> {
+    root: Option<PathBuf>,
+    stack: Vec<Frame>,
+    follow_links: bool,
+    max_depth: Option<usize>,
+    // .gitignore files in effect for the directory being read; `None` when
+    // ignore files are not being honored
+    ignores: Option<Vec<Gitignore>>,
+    // Canonical paths of the directories already read, so a symlink loop
+    // can't recurse forever
+    visited: HashSet<PathBuf>,
+    filter: Option<EntryFilter<'a>>,
+    pending: Option<ShellError>,
+}
+
+type EntryFilter<'a> = Box<dyn FnMut(&WalkEntry) -> bool + 'a>;
+
+struct Frame {
+    // Remaining entries in reverse name order, so `pop` gives the next one
+    entries: Vec<fs::DirEntry>,
+    depth: usize,
+    pushed_ignore: bool,
+}
+
+impl<'a> Walk<'a> {
+    pub(crate) fn new(root: &Path) -> Walk<'a> {
+        Walk {
+            root: Some(root.to_path_buf()),
+            stack: Vec::new(),
+            follow_links: false,
+            max_depth: None,
+            ignores: None,
+            visited: HashSet::new(),
+            filter: None,
+            pending: None,
+        }
+    }
+
+    pub(crate) fn follow_links(mut self, follow: bool) -> Self {
+        self.follow_links = follow;
+        self
+    }
+
+    // Entries deeper than `depth` are neither yielded nor read
+    pub(crate) fn max_depth(mut self, depth: Option<usize>) -> Self {
+        self.max_depth = depth;
+        self
+    }
+
+    // Honors .gitignore files, starting from `parents` (those that apply from
+    // above the starting point) and adding each directory's own on the way in
+    pub(crate) fn ignores(mut self, parents: Option<Vec<Gitignore>>) -> Self {
+        self.ignores = parents;
+        self
+    }
+
+    // Entries below the starting point that `filter` rejects are skipped,
+    // along with everything under them
+    pub(crate) fn filter_entry(mut self, filter: impl FnMut(&WalkEntry) -> bool + 'a) -> Self {
+        self.filter = Some(Box::new(filter));
+        self
+    }
+
+    fn enter(&mut self, dir: &Path, depth: usize) {
+        if self.follow_links {
+            let first_visit = match dir.canonicalize() {
+                Ok(canonical) => self.visited.insert(canonical),
+                Err(e) => {
+                    self.pending = Some(walk_error(dir, e));
+                    return;
+                }
+            };
+            if !first_visit {
+                self.pending = Some(walk_error(dir, io::Error::other("directory already searched (symlink loop?)")));
+                return;
+            }
+        }
+
+        let mut entries = match fs::read_dir(dir).and_then(|entries| entries.collect::<io::Result<Vec<_>>>()) {
+            Ok(entries) => entries,
+            Err(e) => {
+                self.pending = Some(walk_error(dir, e));
+                return;
+            }
+        };
+        entries.sort_by_key(|entry| std::cmp::Reverse(entry.file_name()));
+        let pushed_ignore = match (&mut self.ignores, Gitignore::load(dir)) {
+            (Some(stack), Some(gitignore)) => {
+                stack.push(gitignore);
+                true
+            }
+            _ => false,
+        };
+        self.stack.push(Frame { entries, depth, pushed_ignore });
+    }
+
+    fn ignored(&self, entry: &WalkEntry) -> bool {
+        match &self.ignores {
+            Some(stack) => (entry.is_dir && entry.name == ".git") || gitignore_stack_matches(stack, &entry.path, entry.is_dir),
+            None => false,
+        }
+    }
+
+    fn descends(&self, depth: usize) -> bool {
+        self.max_depth.is_none_or(|max| depth < max)
+    }
+}
+
+impl Iterator for Walk<'_> {
+    type Item = ShellResult<WalkEntry>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(root) = self.root.take() {
+            let entry = WalkEntry {
+                name: root.file_name().unwrap_or(root.as_os_str()).to_string_lossy().into_owned(),
+                depth: 0,
+                is_dir: root.is_dir(),
+                is_symlink: false,
+                path: root,
+            };
+            if entry.is_dir && self.descends(0) {
+                self.enter(&entry.path, 0);
+            }
+            return Some(Ok(entry));
+        }
+
+        loop {
+            if let Some(e) = self.pending.take() {
+                return Some(Err(e));
+            }
+            let frame = self.stack.last_mut()?;
+            let depth = frame.depth + 1;
+            let Some(dir_entry) = frame.entries.pop() else {
+                if self.stack.pop().is_some_and(|frame| frame.pushed_ignore) {
+                    if let Some(stack) = &mut self.ignores {
+                        stack.pop();
+                    }
+                }
+                continue;
+            };
+
+            let path = dir_entry.path();
+            let file_type = match dir_entry.file_type() {
+                Ok(file_type) => file_type,
+                Err(e) => return Some(Err(walk_error(&path, e))),
+            };
+            let is_symlink = file_type.is_symlink();
+            let is_dir = if is_symlink {
+                self.follow_links && fs::metadata(&path).is_ok_and(|target| target.is_dir())
+            } else {
+                file_type.is_dir()
+            };
+            let entry = WalkEntry {
+                name: dir_entry.file_name().to_string_lossy().into_owned(),
+                path,
+                depth,
+                is_dir,
+                is_symlink,
+            };
+
+            if self.ignored(&entry) || self.filter.as_mut().is_some_and(|filter| !filter(&entry)) {
+                continue;
+            }
+            if entry.is_dir && self.descends(depth) {
+                self.enter(&entry.path, depth);
+            }
+            return Some(Ok(entry));
+        }
+    }
+}
+
+fn walk_error(path: &Path, e: io::Error) -> ShellError {
+    io::Error::new(e.kind(), format!("{}: {}", path.display(), e)).into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::symlink;
+    use crate::tests::scratch_dir;
+
+    // root/a, root/b/c and root/b/d/e
+    fn tree(name: &str) -> PathBuf {
+        let root = scratch_dir(name);
+        fs::create_dir_all(root.join("b/d")).unwrap();
+        for file in ["a", "b/c", "b/d/e"] {
+            fs::write(root.join(file), "").unwrap();
+        }
+        root
+    }
+
+    // Each entry's path below `root` and its depth
+    fn listed(root: &Path, walk: Walk) -> Vec<(String, usize)> {
+        walk.map(|entry| {
+            let entry = entry.unwrap();
+            let rel = entry.path.strip_prefix(root).unwrap().to_string_lossy().into_owned();
+            (rel, entry.depth)
+        })
+        .collect()
+    }
+
+    fn pairs(expected: &[(&str, usize)]) -> Vec<(String, usize)> {
+        expected.iter().map(|&(path, depth)| (path.to_string(), depth)).collect()
+    }
+
+    #[test]
+    fn walks_in_name_order_with_each_directory_before_its_contents() {
+        let root = tree("walk-order");
+        let expected = [("", 0), ("a", 1), ("b", 1), ("b/c", 2), ("b/d", 2), ("b/d/e", 3)];
+        assert_eq!(listed(&root, Walk::new(&root)), pairs(&expected));
+    }
+
+    #[test]
+    fn max_depth_limits_what_is_read() {
+        let root = tree("walk-depth");
+        assert_eq!(listed(&root, Walk::new(&root).max_depth(Some(0))), pairs(&[("", 0)]));
+        assert_eq!(listed(&root, Walk::new(&root).max_depth(Some(1))), pairs(&[("", 0), ("a", 1), ("b", 1)]));
+    }
+
+    #[test]
+    fn rejected_entries_are_skipped_with_everything_under_them() {
+        let root = tree("walk-filter");
+        let walk = Walk::new(&root).filter_entry(|entry| entry.name != "d");
+        assert_eq!(listed(&root, walk), pairs(&[("", 0), ("a", 1), ("b", 1), ("b/c", 2)]));
+    }
+
+    #[test]
+    fn symlinks_are_entered_only_when_followed() {
+        let root = tree("walk-links");
+        let outside = scratch_dir("walk-links-target");
+        fs::write(outside.join("e"), "").unwrap();
+        symlink(&outside, root.join("link")).unwrap();
+        let link = Walk::new(&root).map(Result::unwrap).find(|entry| entry.name == "link").unwrap();
+        assert!(link.is_symlink && !link.is_dir);
+        let followed = listed(&root, Walk::new(&root).follow_links(true));
+        assert!(followed.contains(&("link/e".to_string(), 2)));
+        assert!(!listed(&root, Walk::new(&root)).iter().any(|(path, _)| path == "link/e"));
+    }
+
+    #[test]
+    fn a_symlink_loop_is_reported_once_and_the_walk_ends() {
+        let root = tree("walk-loop");
+        symlink("..", root.join("b/up")).unwrap();
+        let results: Vec<_> = Walk::new(&root).follow_links(true).collect();
+        assert_eq!(results.iter().filter(|result| result.is_err()).count(), 1);
+        assert_eq!(results.len(), 8);
+    }
+}